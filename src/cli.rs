@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use autodeploy::{objects::structs::DirtyPolicy, utils::docker::parse_size};
+
+/// Configurable custom wrapper over git for quick and hassle free deployments
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path of `global.toml`, looked up in the current directory,
+    /// `$XDG_CONFIG_HOME/autodeploy` and `/etc/autodeploy` otherwise
+    #[arg(long, global = true, env = "AUTODEPLOY_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Print the planned git and docker actions without executing them
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    /// Never prompt, the operation, application and branch have to be given
+    #[arg(long, global = true)]
+    pub non_interactive: bool,
+    #[command(subcommand)]
+    pub command: Option<Operation>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Operation {
+    /// Deploy a branch of an application
+    Deploy {
+        /// Names of the applications as configured, applications sharing a clone
+        /// fetch it once
+        application: Vec<String>,
+        /// Remote branch to deploy, e.g. `main` or `origin/main`
+        #[arg(long)]
+        branch: Option<String>,
+        /// What to do with local changes in the repository, defaults to abort in
+        /// non-interactive mode
+        #[arg(long, value_enum)]
+        on_dirty: Option<DirtyPolicy>,
+        /// Build without the layer cache, e.g. after a base image CVE
+        #[arg(long)]
+        no_cache: bool,
+        /// Always pull the base images before building
+        #[arg(long)]
+        pull: bool,
+        /// Pull this tag from the registry of the application instead of
+        /// building, e.g. a commit SHA pushed by an earlier deployment
+        #[arg(long, value_name = "TAG", conflicts_with_all = ["branch", "no_cache", "pull"])]
+        from_registry: Option<String>,
+    },
+    /// Restart an application
+    Restart {
+        /// Name of the application as configured
+        application: Option<String>,
+    },
+    /// Stop an application
+    Stop {
+        /// Name of the application as configured
+        application: Option<String>,
+    },
+    /// Stop an application for good and delete what it left behind
+    Remove {
+        /// Name of the application as configured
+        application: Option<String>,
+        /// Delete the named volumes of the containers
+        #[arg(long)]
+        volumes: bool,
+        /// Delete the images built for the application
+        #[arg(long)]
+        images: bool,
+        /// Delete the generated compose file
+        #[arg(long)]
+        compose_file: bool,
+        /// Delete the clone of the repository
+        #[arg(long)]
+        clone: bool,
+        /// Delete the deployment history and the blue/green proxy config
+        #[arg(long)]
+        state: bool,
+        /// Delete all of the above
+        #[arg(long)]
+        all: bool,
+    },
+    /// Remove the images of old deployments beyond the `keep_images` of every
+    /// application
+    Prune {
+        /// Name of the application as configured, all applications otherwise
+        application: Option<String>,
+        /// Also prune the build cache down to this size, e.g. `10GB` or `0`,
+        /// podman and nerdctl only accept `0`
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        builder_cache: Option<u64>,
+    },
+    /// Show who ran which operation from the audit log
+    History {
+        /// Name of the application as configured, all operations otherwise
+        application: Option<String>,
+        /// Number of the latest entries shown
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+        /// Print the entries as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+impl Operation {
+    /// the menu entry matching the operation
+    pub fn choice(&self) -> &'static str {
+        match self {
+            Operation::Deploy { .. } => "Deploy Application",
+            Operation::Restart { .. } => "Restart Application",
+            Operation::Stop { .. } => "Stop Application",
+            Operation::Remove { .. } => "Remove Application",
+            Operation::Prune { .. } => "Prune Images",
+            Operation::History { .. } => "Show History",
+        }
+    }
+
+    pub fn application(&self) -> Option<&str> {
+        match self {
+            Operation::Deploy { application, .. } => application.first().map(String::as_str),
+            Operation::Restart { application }
+            | Operation::Stop { application }
+            | Operation::Remove { application, .. }
+            | Operation::Prune { application, .. }
+            | Operation::History { application, .. } => application.as_deref(),
+        }
+    }
+}
@@ -0,0 +1,9 @@
+//! Configurable custom wrapper over git for quick and hassle free deployments
+//!
+//! The [`pipeline::Deployer`] drives a deployment step by step, fetch,
+//! checkout, render, build, start and verify, and reports its progress through
+//! event callbacks so it can be embedded in other tooling.
+
+pub mod objects;
+pub mod pipeline;
+pub mod utils;
@@ -1,21 +1,34 @@
-use std::{fs, path::Path, process::exit, slice::Iter};
+use std::{path::Path, process::exit, rc::Rc, slice::Iter, time::Duration};
 
-use dotenvy::dotenv;
-use inquire::{InquireError, Select};
-use objects::structs::{Application, ComposeConfiguation, Container};
-use text_to_ascii_art::to_art;
-use utils::{
-    docker::{build_compose, generate_compose, restart_compose, start_compose, stop_compose},
-    file::check_file,
-    git::{
-        branch_checkout, check_repository, prompt_branch_selection, prompt_clone_repository,
-        pull_repository,
+use autodeploy::{
+    objects::structs::{Application, Backend, DirtyPolicy, GlobalConfiguration},
+    pipeline::{Confirmation, DeployError, Deployer, Event, Removal},
+    utils::{
+        audit::AuditLog,
+        config::{
+            load_global_configuration, load_project_configuration, locate_global_configuration,
+        },
+        docker::{BuildFlags, Runtime, parse_size, resolve_engine},
+        engine::EngineClient,
+        file::check_file,
+        git::{check_repository, print_changelog},
+        notify::Notifier,
+        runner::SystemRunner,
     },
 };
+use chrono::DateTime;
+use clap::Parser;
+use cli::{Cli, Operation};
+use dotenvy::dotenv;
+use indicatif::{HumanBytes, HumanDuration};
+use inquire::{Confirm, InquireError, MultiSelect, Select};
+use prompt::{
+    confirm_changelog, confirm_clone, confirm_reset, prompt_branch_selection, prompt_dirty_policy,
+};
+use text_to_ascii_art::to_art;
 
-mod objects;
-mod utils;
-use objects::structs::{GlobalConfiguration, ProjectConfiguation};
+mod cli;
+mod prompt;
 // USER FLOW
 // prompt for operation, deploy restart or stop
 // prompt for the appropriate project
@@ -23,29 +36,16 @@ use objects::structs::{GlobalConfiguration, ProjectConfiguation};
 // else use the docker bindings or the os to operate using the files generated
 //
 
-fn init() -> GlobalConfiguration {
-    let global_configuration_file = "global.toml";
-    let global_configuration = match fs::read_to_string(global_configuration_file) {
-        Ok(c) => {
-            // println!("Successfully read global configuration");
-            c
-        }
-        Err(_) => {
-            eprintln!("Could not read file `{}`", global_configuration_file);
+fn init(path: Option<&Path>) -> GlobalConfiguration {
+    let global_configuration =
+        locate_global_configuration(path).and_then(|path| load_global_configuration(&path));
+    match global_configuration {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
             exit(1);
         }
-    };
-    let config: GlobalConfiguration = match toml::from_str(&global_configuration) {
-        Ok(d) => {
-            // println!("Successfully loaded global configuration");
-            d
-        }
-        Err(_) => {
-            eprintln!("Unable to load data from `{}`", global_configuration_file);
-            exit(1);
-        }
-    };
-    config
+    }
 }
 fn banner(config: GlobalConfiguration) {
     let print_banner: bool = config.print_banner;
@@ -61,28 +61,304 @@ fn banner(config: GlobalConfiguration) {
         println!("Auto Deploy - Internal Tooling")
     }
     print!("\nAuthor: Kalyan Mudumby (@theinhumaneme / theinhumaneme@gmail.com)");
-    if config.organization.is_some() {
-        print!("\nOrganization: {}", config.organization.unwrap());
+    if let Some(organization) = config.organization {
+        print!("\nOrganization: {}", organization);
     }
-    if config.client.is_some() {
-        print!("\nClient: {}", config.client.unwrap());
+    if let Some(client) = config.client {
+        print!("\nClient: {}", client);
     }
     println!("\n");
 }
+/// report a failed step, a cancelled deployment is not an error
+fn fail(error: DeployError) -> ! {
+    match error {
+        DeployError::Cancelled(reason) => {
+            println!("{}", reason);
+            exit(0);
+        }
+        error => {
+            eprintln!("{}", error);
+            exit(1);
+        }
+    }
+}
+/// deploy a branch of the application, an unchanged application is skipped
+fn deploy(
+    deployer: &Deployer,
+    service: &Application,
+    branch_argument: Option<&str>,
+    on_dirty: Option<DirtyPolicy>,
+    build_flags: BuildFlags,
+    cli: &Cli,
+) {
+    let (dry_run, non_interactive) = (cli.dry_run, cli.non_interactive);
+    let repository_path = deployer.repository_path(service);
+    let mut pipeline = deployer
+        .pipeline(service)
+        .dirty_policy(on_dirty)
+        .build_flags(build_flags)
+        .on_event(move |event| match event {
+            Event::StepStarted(step) if non_interactive => {
+                println!("==> {}", step)
+            }
+            Event::Changelog(changelog) => print_changelog(changelog),
+            Event::Signature(verification) => println!(
+                "Verified {} signature of {} by {}",
+                verification.format.as_deref().unwrap_or_default(),
+                verification.object,
+                verification.signer.as_deref().unwrap_or_default()
+            ),
+            Event::LocalChanges(changes) => {
+                println!("Local changes in {}:", repository_path);
+                for change in changes {
+                    println!("  {}", change);
+                }
+            }
+            _ => {}
+        })
+        .confirm(move |confirmation| match confirmation {
+            Confirmation::Changelog(_) => non_interactive || confirm_changelog(),
+            Confirmation::Reset { branch, ahead } => confirm_reset(branch, ahead),
+        })
+        .on_dirty(move |_| {
+            if non_interactive {
+                Some(DirtyPolicy::Abort)
+            } else {
+                prompt_dirty_policy()
+            }
+        });
+    if let Some(Operation::Deploy {
+        from_registry: Some(tag),
+        ..
+    }) = &cli.command
+    {
+        if let Err(e) = pipeline.deploy_from_registry(tag) {
+            fail(e);
+        }
+        return;
+    }
+    // First Check if the repo exists?
+    if !dry_run
+        && !non_interactive
+        && !check_repository(Path::new(pipeline.repository_path()))
+        && !confirm_clone(pipeline.repository_path())
+    {
+        fail(pipeline.abort(DeployError::Cancelled(
+            "The repository was not cloned".to_string(),
+        )));
+    }
+    if let Err(e) = pipeline.fetch() {
+        fail(e);
+    }
+    let branch = match branch_argument {
+        Some(name) => name.to_string(),
+        None if non_interactive => fail(pipeline.abort(DeployError::MissingBranch)),
+        None => match prompt_branch_selection(pipeline.repository_path(), service) {
+            Some(value) => value,
+            // the error is handled by interim, we just kick the user outta the flow
+            None => {
+                fail(pipeline.abort(DeployError::Cancelled("No branch was selected".to_string())))
+            }
+        },
+    };
+    match pipeline.deploy(&branch) {
+        Ok(_) => {}
+        Err(e @ DeployError::Unchanged(_)) => println!("{}", e),
+        Err(e) => fail(e),
+    }
+}
+/// remove the application after confirming what is deleted, without any
+/// flags the interactive mode asks what to delete besides the containers
+fn remove(
+    deployer: &Deployer,
+    service: &Application,
+    mut removal: Removal,
+    shared_with: &[&str],
+    dry_run: bool,
+    non_interactive: bool,
+) {
+    if removal == Removal::default() && !non_interactive {
+        let options = vec![
+            "Named volumes",
+            "Images",
+            "Compose file",
+            "Clone",
+            "Deployment state",
+        ];
+        let selection =
+            match MultiSelect::new("What should be deleted besides the containers?", options)
+                .prompt()
+            {
+                Ok(selection) => selection,
+                Err(_) => exit(1),
+            };
+        removal = Removal {
+            volumes: selection.contains(&"Named volumes"),
+            images: selection.contains(&"Images"),
+            compose_file: selection.contains(&"Compose file"),
+            clone: selection.contains(&"Clone"),
+            state: selection.contains(&"Deployment state"),
+        };
+    }
+    if removal.clone && !shared_with.is_empty() {
+        println!(
+            "The clone {} is shared with {}, it is kept",
+            deployer.repository_path(service),
+            shared_with.join(", ")
+        );
+        removal.clone = false;
+    }
+    if !dry_run && !non_interactive {
+        let deleted: Vec<&str> = [
+            (removal.volumes, "named volumes"),
+            (removal.images, "images"),
+            (removal.compose_file, "compose file"),
+            (removal.clone, "clone"),
+            (removal.state, "deployment state"),
+        ]
+        .into_iter()
+        .filter_map(|(selected, name)| selected.then_some(name))
+        .collect();
+        let message = if deleted.is_empty() {
+            format!("Stop and remove the containers of {}?", service.name)
+        } else {
+            format!(
+                "Stop and remove the containers of {} and delete its {}?",
+                service.name,
+                deleted.join(", ")
+            )
+        };
+        if !Confirm::new(&message)
+            .with_default(false)
+            .prompt()
+            .unwrap_or(false)
+        {
+            println!("Removal cancelled");
+            exit(0);
+        }
+    }
+    let summary = match deployer.remove(service, removal) {
+        Ok(summary) => summary,
+        Err(e) => fail(e),
+    };
+    for (item, size) in &summary.removed {
+        match size {
+            Some(size) => println!("  {} ({})", item, HumanBytes(*size)),
+            None => println!("  {}", item),
+        }
+    }
+    if dry_run {
+        println!(
+            "[dry-run] would reclaim {}",
+            HumanBytes(summary.reclaimed())
+        );
+    } else {
+        println!("Reclaimed {}", HumanBytes(summary.reclaimed()));
+    }
+}
+/// remove the old images of the applications and prune the build cache
+fn prune(
+    deployer: &Deployer,
+    services: &[&Application],
+    builder_cache: Option<u64>,
+    dry_run: bool,
+) {
+    let mut reclaimed = 0;
+    for service in services {
+        if service.keep_images.is_none() {
+            println!("{} has no `keep_images`, skipping", service.name);
+            continue;
+        }
+        let summary = deployer.prune(service);
+        for (item, size) in &summary.removed {
+            println!("  {} ({})", item, HumanBytes(size.unwrap_or_default()));
+        }
+        reclaimed += summary.reclaimed();
+    }
+    if dry_run {
+        println!("[dry-run] would reclaim {}", HumanBytes(reclaimed));
+    } else {
+        println!("Reclaimed {}", HumanBytes(reclaimed));
+    }
+    if let Some(keep_storage) = builder_cache {
+        if let Err(e) = deployer.prune_build_cache(keep_storage) {
+            fail(e);
+        }
+    }
+}
+/// print the latest entries of the audit log, oldest first
+fn history(audit_log: &AuditLog, application: Option<&str>, limit: usize, json: bool) {
+    let entries = audit_log.entries(application);
+    let shown = &entries[entries.len().saturating_sub(limit)..];
+    if shown.is_empty() && !json {
+        println!("No operations have been logged");
+    }
+    for entry in shown {
+        if json {
+            println!("{}", serde_json::to_string(entry).unwrap());
+            continue;
+        }
+        let timestamp = DateTime::parse_from_rfc3339(&entry.timestamp)
+            .map(|timestamp| timestamp.format("%Y-%m-%d %H:%M:%S %Z").to_string())
+            .unwrap_or(entry.timestamp.clone());
+        let user = match &entry.operator.sudo_user {
+            Some(sudo_user) => format!("{} (sudo by {})", entry.operator.user, sudo_user),
+            None => entry.operator.user.clone(),
+        };
+        println!(
+            "{}  {:<17} {:<16} {:<9} {}@{} in {}",
+            timestamp,
+            entry.operation,
+            entry.application.as_deref().unwrap_or("-"),
+            entry.outcome,
+            user,
+            entry.operator.hostname,
+            HumanDuration(Duration::from_millis(entry.duration_ms))
+        );
+        if let (Some(branch), Some(commit)) = (&entry.branch, &entry.commit) {
+            println!("    {} at {}", branch, commit);
+        }
+        if let Some(error) = &entry.error {
+            println!("    {}", error);
+        }
+    }
+}
 fn main() {
+    let cli = Cli::parse();
     dotenv().ok();
+    let global_configuration = init(cli.config.as_deref());
+    let audit_log_size = match parse_size(&global_configuration.audit_log_size) {
+        Ok(size) => size,
+        Err(e) => {
+            eprintln!("Invalid `audit_log_size`: {}", e);
+            exit(1);
+        }
+    };
+    let audit_log = AuditLog::new(&global_configuration.audit_log)
+        .rotate(audit_log_size, global_configuration.audit_log_files);
+    if let Some(Operation::History {
+        application,
+        limit,
+        json,
+    }) = &cli.command
+    {
+        history(&audit_log, application.as_deref(), *limit, *json);
+        return;
+    }
     let git_username = std::env::var("GIT_USERNAME").expect("GIT_USERNAME must be set.");
     let git_password: String = std::env::var("GIT_PASSWORD").expect("GIT_PASSWORD must be set.");
-    if !check_file(&init().configuration_file) {
+    if !check_file(&global_configuration.configuration_file) {
         eprintln!("Could not read project configuration file");
         exit(1);
     }
-    banner(init());
-    let configuration_file = fs::read_to_string(init().configuration_file).unwrap();
-    let config: ProjectConfiguation = match toml::from_str(&configuration_file) {
-        Ok(d) => d,
-        Err(_) => {
-            eprintln!("Invalid Project Configuration Detected");
+    banner(global_configuration.clone());
+    if cli.dry_run {
+        println!("Dry run, the planned actions are printed and nothing is executed\n");
+    }
+    let config = match load_project_configuration(&global_configuration.configuration_file) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
             exit(1);
         }
     };
@@ -90,9 +366,57 @@ fn main() {
         "Deploy Application",
         "Restart Application",
         "Stop Application",
+        "Remove Application",
     ];
-    let operation_choice: Result<&str, InquireError> =
-        Select::new("What would you like to do?", operations).prompt();
+    let operation_choice: Result<&str, InquireError> = match &cli.command {
+        Some(operation) => Ok(operation.choice()),
+        None if cli.non_interactive => {
+            eprintln!("An operation is required in non-interactive mode");
+            exit(1);
+        }
+        None => Select::new("What would you like to do?", operations).prompt(),
+    };
+    let runtime = match global_configuration.backend {
+        Backend::Cli => Runtime::compose(Rc::new(SystemRunner))
+            .engine(resolve_engine(global_configuration.engine)),
+        Backend::Api => Runtime::api(
+            Rc::new(SystemRunner),
+            EngineClient::new(&global_configuration.docker_socket),
+        ),
+    };
+    // notifications are sent with their own runner, they are not audited
+    let notifier = Notifier::new(
+        global_configuration.notifications.clone(),
+        Rc::new(SystemRunner),
+    )
+    .secrets_directory(&global_configuration.secrets_directory);
+    let deployer = Deployer::new(&config.repository_path, &git_username, &git_password)
+        .runtime(runtime)
+        .compose_directory(&global_configuration.compose_directory)
+        .state_directory(&global_configuration.state_directory)
+        .secrets_directory(&global_configuration.secrets_directory)
+        .audit_log(audit_log)
+        .notifier(notifier)
+        .dry_run(cli.dry_run)
+        .non_interactive(cli.non_interactive);
+    if let Some(Operation::Prune {
+        application,
+        builder_cache,
+    }) = &cli.command
+    {
+        let services: Vec<&Application> = match application {
+            Some(name) => match config.application.iter().find(|s| &s.name == name) {
+                Some(service) => vec![service],
+                None => {
+                    eprintln!("Unknown application `{}`", name);
+                    exit(1);
+                }
+            },
+            None => config.application.iter().collect(),
+        };
+        prune(&deployer, &services, *builder_cache, cli.dry_run);
+        return;
+    }
     let mut project_iterator: Iter<Application> = config.application.iter();
     let projects = config
         .application
@@ -101,63 +425,117 @@ fn main() {
         .collect();
     match operation_choice {
         Ok(choice) => {
-            let projects_choice = Select::new("Choose Project", projects).prompt();
+            let projects_choice = match cli.command.as_ref().and_then(Operation::application) {
+                Some(name) => match config.application.iter().find(|s| s.name == name) {
+                    Some(service) => Ok(&service.name),
+                    None => {
+                        eprintln!("Unknown application `{}`", name);
+                        exit(1);
+                    }
+                },
+                None if cli.non_interactive => {
+                    eprintln!("An application is required in non-interactive mode");
+                    exit(1);
+                }
+                None => Select::new("Choose Project", projects).prompt(),
+            };
             match projects_choice {
                 Ok(project) => match choice {
                     "Deploy Application" => {
-                        // First Check if the repo exists?
-                        let service: Option<&Application> =
-                            project_iterator.find(|&s| s.name == *project);
-                        // dbg!(service.unwrap());
-                        let repository_path =
-                            config.repository_path.to_owned() + "/" + &service.unwrap().slug;
-                        let repo_url = &service.unwrap().repository_url;
-                        let repo_exists = check_repository(Path::new(&repository_path));
-                        if !repo_exists {
-                            prompt_clone_repository(
-                                &git_username,
-                                &git_password,
-                                repo_url,
-                                &repository_path,
-                            )
-                        } else if pull_repository(&git_username, &git_password, &repository_path) {
-                            println!("All branches have been fetched and updated successfully.");
-                        } else {
-                            println!("Pulling the repository failed");
-                            panic!();
-                        }
-                        let branch = prompt_branch_selection(&repository_path);
-                        if let Some(value) = branch {
-                            println!("Selected branch is {:?}", value);
-                            branch_checkout(&repository_path, value);
-                        } else {
-                            // the error is handled by interim, we just kick the user outta the flow
-                            exit(1)
+                        let (names, branch_argument, on_dirty, build_flags) = match &cli.command {
+                            Some(Operation::Deploy {
+                                application,
+                                branch,
+                                on_dirty,
+                                no_cache,
+                                pull,
+                                ..
+                            }) => (
+                                if application.is_empty() {
+                                    vec![project.clone()]
+                                } else {
+                                    application.clone()
+                                },
+                                branch.as_deref(),
+                                *on_dirty,
+                                BuildFlags {
+                                    no_cache: *no_cache,
+                                    pull: *pull,
+                                },
+                            ),
+                            _ => (vec![project.clone()], None, None, BuildFlags::default()),
                         };
-                        let compose_path = generate_compose(
-                            &config.repository_path,
-                            "./compose_files",
-                            &service.unwrap().slug,
-                            &service.unwrap().container,
-                        );
-                        build_compose(&compose_path);
-                        start_compose(&compose_path, &service.unwrap().slug);
+                        let mut services = Vec::new();
+                        for name in &names {
+                            match config.application.iter().find(|s| &s.name == name) {
+                                Some(service) => services.push(service),
+                                None => {
+                                    eprintln!("Unknown application `{}`", name);
+                                    exit(1);
+                                }
+                            }
+                        }
+                        for service in services {
+                            deploy(
+                                &deployer,
+                                service,
+                                branch_argument,
+                                on_dirty,
+                                build_flags,
+                                &cli,
+                            );
+                        }
                     }
                     "Restart Application" => {
-                        let service: Option<&Application> =
-                            project_iterator.find(|&s| s.name == *project);
-                        restart_compose(
-                            format!("./compose_files/{}.yaml", &service.unwrap().slug).as_str(),
-                            &service.unwrap().slug,
-                        )
+                        let service = project_iterator.find(|&s| s.name == *project).unwrap();
+                        if let Err(e) = deployer.restart(service) {
+                            fail(e);
+                        }
                     }
                     "Stop Application" => {
-                        let service: Option<&Application> =
-                            project_iterator.find(|&s| s.name == *project);
-                        stop_compose(
-                            format!("./compose_files/{}.yaml", &service.unwrap().slug).as_str(),
-                            &service.unwrap().slug,
-                        )
+                        let service = project_iterator.find(|&s| s.name == *project).unwrap();
+                        if let Err(e) = deployer.stop(service) {
+                            fail(e);
+                        }
+                    }
+                    "Remove Application" => {
+                        let service = project_iterator.find(|&s| s.name == *project).unwrap();
+                        let removal = match &cli.command {
+                            Some(Operation::Remove {
+                                volumes,
+                                images,
+                                compose_file,
+                                clone,
+                                state,
+                                all,
+                                ..
+                            }) => Removal {
+                                volumes: *volumes || *all,
+                                images: *images || *all,
+                                compose_file: *compose_file || *all,
+                                clone: *clone || *all,
+                                state: *state || *all,
+                            },
+                            _ => Removal::default(),
+                        };
+                        let shared_with: Vec<&str> = config
+                            .application
+                            .iter()
+                            .filter(|other| {
+                                other.name != service.name
+                                    && other.repository_directory()
+                                        == service.repository_directory()
+                            })
+                            .map(|other| other.name.as_str())
+                            .collect();
+                        remove(
+                            &deployer,
+                            service,
+                            removal,
+                            &shared_with,
+                            cli.dry_run,
+                            cli.non_interactive,
+                        );
                     }
                     &_ => {
                         println!("Invalid Flow, please restart the process");
@@ -1,5 +1,9 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
 
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -9,6 +13,192 @@ pub struct GlobalConfiguration {
     pub client: Option<String>,
     pub organization: Option<String>,
     pub configuration_file: String,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default = "default_docker_socket")]
+    pub docker_socket: String,
+    #[serde(default)]
+    pub engine: Engine,
+    #[serde(default = "default_compose_directory")]
+    pub compose_directory: String,
+    #[serde(default = "default_state_directory")]
+    pub state_directory: String,
+    /// one file per secret, named after it
+    #[serde(default = "default_secrets_directory")]
+    pub secrets_directory: String,
+    /// every operation is appended to it as a JSON line
+    #[serde(default = "default_audit_log")]
+    pub audit_log: String,
+    /// size the audit log is rotated at, e.g. `10MB`
+    #[serde(default = "default_audit_log_size")]
+    pub audit_log_size: String,
+    /// rotated audit logs kept besides the current one
+    #[serde(default = "default_audit_log_files")]
+    pub audit_log_files: usize,
+    #[serde(default)]
+    pub notifications: Notifications,
+}
+
+/// where the progress of deployments is announced
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notifications {
+    #[serde(default, rename = "channel")]
+    pub channels: Vec<Channel>,
+    #[serde(default)]
+    pub templates: Templates,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Channel {
+    pub kind: ChannelKind,
+    /// the incoming webhook or, for email, the SMTP server such as
+    /// `smtps://smtp.example.com`; `secret:NAME` reads it from a secret
+    pub url: String,
+    /// the events sent to the channel, all of them by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub events: Option<Vec<NotificationEvent>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default)]
+    pub to: Vec<String>,
+    /// prefix of the `<credentials>_USERNAME` and `<credentials>_PASSWORD`
+    /// environment variables of the SMTP login
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    Slack,
+    Discord,
+    Teams,
+    /// the notification as a JSON object
+    #[default]
+    Webhook,
+    Email,
+}
+
+impl fmt::Display for ChannelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelKind::Slack => write!(f, "slack"),
+            ChannelKind::Discord => write!(f, "discord"),
+            ChannelKind::Teams => write!(f, "teams"),
+            ChannelKind::Webhook => write!(f, "webhook"),
+            ChannelKind::Email => write!(f, "email"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    Started,
+    Succeeded,
+    Failed,
+    /// a blue/green deployment failed and the previous color keeps serving
+    RolledBack,
+}
+
+impl fmt::Display for NotificationEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotificationEvent::Started => write!(f, "started"),
+            NotificationEvent::Succeeded => write!(f, "succeeded"),
+            NotificationEvent::Failed => write!(f, "failed"),
+            NotificationEvent::RolledBack => write!(f, "rolled back"),
+        }
+    }
+}
+
+/// the message of every event, `{application}`, `{branch}`, `{commit}`,
+/// `{short_commit}`, `{operator}`, `{hostname}`, `{duration}` and `{error}`
+/// are replaced
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Templates {
+    pub started: String,
+    pub succeeded: String,
+    pub failed: String,
+    pub rolled_back: String,
+}
+
+impl Default for Templates {
+    fn default() -> Templates {
+        Templates {
+            started: "{operator} started deploying {application} {branch} ({short_commit}) on {hostname}".to_string(),
+            succeeded: "{application} {branch} ({short_commit}) was deployed by {operator} in {duration}".to_string(),
+            failed: "Deploying {application} {branch} ({short_commit}) failed after {duration}: {error}".to_string(),
+            rolled_back: "Deploying {application} {branch} ({short_commit}) failed after {duration}, the previous deployment keeps serving: {error}".to_string(),
+        }
+    }
+}
+
+impl Templates {
+    pub fn template(&self, event: NotificationEvent) -> &str {
+        match event {
+            NotificationEvent::Started => &self.started,
+            NotificationEvent::Succeeded => &self.succeeded,
+            NotificationEvent::Failed => &self.failed,
+            NotificationEvent::RolledBack => &self.rolled_back,
+        }
+    }
+}
+
+fn default_compose_directory() -> String {
+    "./compose_files".to_string()
+}
+
+fn default_state_directory() -> String {
+    "./state".to_string()
+}
+
+fn default_secrets_directory() -> String {
+    "./secrets".to_string()
+}
+
+fn default_audit_log() -> String {
+    "./audit.log".to_string()
+}
+
+fn default_audit_log_size() -> String {
+    "10MB".to_string()
+}
+
+fn default_audit_log_files() -> usize {
+    5
+}
+
+/// the container engine whose compose CLI runs the generated compose files
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Engine {
+    #[default]
+    Docker,
+    /// `podman compose`
+    Podman,
+    /// the standalone `podman-compose`
+    PodmanCompose,
+    /// containerd's `nerdctl compose`
+    Nerdctl,
+    /// the first engine found on `PATH`
+    Auto,
+}
+
+/// how the containers are built and run
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// the `docker compose` CLI
+    #[default]
+    Cli,
+    /// the Docker Engine API on `docker_socket`
+    Api,
+}
+
+fn default_docker_socket() -> String {
+    "/var/run/docker.sock".to_string()
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -23,9 +213,318 @@ pub struct Application {
     pub name: String,
     #[serde(rename(deserialize = "url"))]
     pub repository_url: String,
+    #[serde(default = "default_remote")]
+    pub remote: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forks: Vec<Remote>,
     #[serde(rename(deserialize = "directory_name"))]
     pub slug: String,
+    /// directory of a clone shared with other applications of the same
+    /// repository, defaults to `directory_name`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clone_directory: Option<String>,
+    /// subdirectory of the repository used as the build context
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    /// skip the deployment when nothing under `context` changed since the
+    /// deployed commit
+    #[serde(default)]
+    pub skip_unchanged: bool,
     pub container: Container,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registry: Option<Registry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blue_green: Option<BlueGreen>,
+    #[serde(default)]
+    pub divergence_policy: DivergencePolicy,
+    #[serde(default)]
+    pub lfs: bool,
+    /// images of the latest deployments kept by `prune`, older ones are removed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_images: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clone_depth: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fetch_branches: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch_allowlist: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch_denylist: Option<Vec<String>>,
+    #[serde(default)]
+    pub require_signed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_signers: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gpg_keyring: Option<String>,
+}
+
+/// what happens when the local branch being deployed has commits that are not
+/// on the remote, e.g. after a force-push upstream
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DivergencePolicy {
+    ResetToRemote,
+    Abort,
+    #[default]
+    Prompt,
+}
+
+fn default_remote() -> String {
+    "origin".to_string()
+}
+
+impl Application {
+    /// the directory of the clone below the repository root
+    pub fn repository_directory(&self) -> &str {
+        self.clone_directory.as_deref().unwrap_or(&self.slug)
+    }
+
+    /// the directory the container is built from, the clone or the `context`
+    /// subdirectory of it
+    pub fn build_directory(&self, repository_root: &str) -> String {
+        let repository_path = format!("{}/{}", repository_root, self.repository_directory());
+        match &self.context {
+            Some(context) => format!("{}/{}", repository_path, context.trim_end_matches('/')),
+            None => repository_path,
+        }
+    }
+
+    /// the primary remote followed by the forks
+    pub fn remotes(&self) -> Vec<Remote> {
+        let mut remotes = vec![Remote {
+            name: self.remote.clone(),
+            url: self.repository_url.clone(),
+        }];
+        remotes.extend(self.forks.iter().cloned());
+        remotes
+    }
+}
+
+/// additional remote, e.g. a fork whose branches can be deployed as well
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+}
+
+/// registry the built images are pushed to and `--from-registry` pulls from
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Registry {
+    /// host of the registry, e.g. `registry.example.com:5000`
+    pub url: String,
+    pub repository: String,
+    /// prefix of the `<credentials>_USERNAME` and `<credentials>_PASSWORD`
+    /// environment variables, the existing login of the engine is used without
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<String>,
+}
+
+impl Registry {
+    /// the registry host without a scheme
+    pub fn host(&self) -> &str {
+        let url = self.url.trim_end_matches('/');
+        url.split_once("://").map_or(url, |(_, host)| host)
+    }
+
+    /// the image name in the registry, without a tag
+    pub fn image(&self) -> String {
+        format!("{}/{}", self.host(), self.repository.trim_matches('/'))
+    }
+}
+
+/// zero-downtime deployments, the new stack is brought up next to the old one
+/// under an alternate project name and port before the proxy is switched over
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlueGreen {
+    pub container_port: u16,
+    pub blue_port: u16,
+    pub green_port: u16,
+    #[serde(default = "default_upstream_host")]
+    pub host: String,
+    #[serde(default = "default_health_path")]
+    pub health_path: String,
+    #[serde(default = "default_health_timeout")]
+    pub health_timeout: u64,
+    pub proxy: Proxy,
+    pub proxy_config: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_reload: Option<String>,
+}
+
+fn default_upstream_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_health_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_timeout() -> u64 {
+    60
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Proxy {
+    #[default]
+    Nginx,
+    Caddy,
+    Traefik,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+    Blue,
+    Green,
+}
+
+impl Color {
+    pub fn other(self) -> Color {
+        match self {
+            Color::Blue => Color::Green,
+            Color::Green => Color::Blue,
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Color::Blue => write!(f, "blue"),
+            Color::Green => write!(f, "green"),
+        }
+    }
+}
+
+/// state persisted between runs for every application
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApplicationState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_color: Option<Color>,
+    #[serde(default)]
+    pub deployments: Vec<DeploymentRecord>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeploymentRecord {
+    pub timestamp: String,
+    pub branch: String,
+    /// empty when deployed from a registry tag that is not a commit
+    pub commit: String,
+    /// id of the image built for the deployment, cleared once it is pruned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// the registry image `<repository>:<tag>` deployed with `--from-registry`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SignatureVerification>,
+    #[serde(default)]
+    pub changelog: Vec<ChangelogEntry>,
+}
+
+/// an operation as written to the audit log, one JSON object per line
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub operator: Operator,
+    pub operation: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub application: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// the commands run and the Engine API requests sent
+    #[serde(default)]
+    pub commands: Vec<String>,
+    /// the git operations carried out in-process, e.g. the fetched refspecs
+    /// and the checked out commit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub git: Vec<String>,
+    pub duration_ms: u64,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// who ran an operation, `sudo_user` is the user who invoked sudo
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Operator {
+    pub user: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sudo_user: Option<String>,
+    pub hostname: String,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    #[default]
+    Success,
+    Failure,
+    Cancelled,
+    Unchanged,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Success => write!(f, "success"),
+            Outcome::Failure => write!(f, "failure"),
+            Outcome::Cancelled => write!(f, "cancelled"),
+            Outcome::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+/// outcome of verifying the signature of a deployed revision
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignatureVerification {
+    pub verified: bool,
+    /// the signed object, `commit <sha>` or `tag <name>`
+    pub object: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signer: Option<String>,
+    pub message: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangelogEntry {
+    pub commit: String,
+    pub subject: String,
+    pub author: String,
+    pub date: String,
+}
+
+/// how local changes in a repository are handled before checking out a branch
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DirtyPolicy {
+    Abort,
+    Stash,
+    Discard,
+}
+
+impl fmt::Display for DirtyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DirtyPolicy::Abort => write!(f, "Abort the deployment"),
+            DirtyPolicy::Stash => write!(f, "Stash the changes"),
+            DirtyPolicy::Discard => write!(f, "Discard the changes"),
+        }
+    }
+}
+
+/// commits between the deployed revision and the selected branch tip
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Changelog {
+    pub target: String,
+    pub entries: Vec<ChangelogEntry>,
+    pub stats: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -33,6 +532,7 @@ pub struct Container {
     #[serde(rename(serialize = "container_name", deserialize = "name"))]
     pub name: String,
     pub image: String,
+    #[serde(skip_serializing_if = "Build::is_empty")]
     pub build: Build,
     #[serde(rename(deserialize = "restart_policy"))]
     pub restart: String,
@@ -54,8 +554,13 @@ pub struct Container {
     pub ports: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub volumes: Option<Vec<String>>,
+    /// `KEY=secret:NAME` takes the value from the secret `NAME` when the
+    /// container is started, only `KEY` is written to the compose file
     #[serde(skip_serializing_if = "Option::is_none")]
     pub environment: Option<Vec<String>>,
+    /// secrets mounted as files at `/run/secrets/<name>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secrets: Option<Vec<String>>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -68,9 +573,85 @@ pub struct Build {
     )]
     pub args: Option<Vec<String>>,
     pub dockerfile: String,
+    /// stage of a multi-stage Dockerfile to build
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// platform to build for, e.g. `linux/arm64`
+    #[serde(
+        rename(serialize = "platforms"),
+        serialize_with = "serialize_platform",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub platform: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_cache: bool,
+    /// always pull the base images
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pull: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_from: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_to: Option<Vec<String>>,
+    /// BuildKit secrets by id, only the ids are written to the build section
+    #[serde(
+        serialize_with = "serialize_secret_ids",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub secrets: Option<BTreeMap<String, BuildSecret>>,
+}
+
+impl Build {
+    /// a build without a Dockerfile is left out of the compose file, the image
+    /// is pulled instead
+    pub fn is_empty(&self) -> bool {
+        self.dockerfile.is_empty()
+    }
+}
+
+/// where the value of a BuildKit secret comes from, a file or an environment
+/// variable of autodeploy
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildSecret {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+}
+
+/// compose only knows a list of platforms
+fn serialize_platform<S: serde::Serializer>(
+    platform: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    platform
+        .iter()
+        .collect::<Vec<&String>>()
+        .serialize(serializer)
+}
+
+/// the build section references the secrets defined at the top level
+fn serialize_secret_ids<S: serde::Serializer>(
+    secrets: &Option<BTreeMap<String, BuildSecret>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    secrets
+        .iter()
+        .flat_map(|secrets| secrets.keys())
+        .collect::<Vec<&String>>()
+        .serialize(serializer)
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ComposeConfiguation {
     pub services: HashMap<String, Container>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secrets: Option<BTreeMap<String, BuildSecret>>,
+    /// the environment variables taken from secrets at `up`, by the secret
+    /// file relative to the compose file; compose ignores `x-` keys
+    #[serde(
+        rename = "x-secret-environment",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub secret_environment: BTreeMap<String, String>,
 }
@@ -0,0 +1,1356 @@
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt,
+    fs::{remove_dir_all, remove_file},
+    path::Path,
+    rc::Rc,
+    time::Instant,
+};
+
+use chrono::Utc;
+
+use crate::{
+    objects::structs::{
+        Application, AuditEntry, Build, Changelog, Color, DeploymentRecord, DirtyPolicy,
+        NotificationEvent, Outcome, SignatureVerification,
+    },
+    utils::{
+        audit::{AuditLog, current_operator},
+        bluegreen::{
+            active_target, color_project, promote_color, render_color, switch_color, target_color,
+        },
+        docker::{BuildFlags, Runtime, generate_compose},
+        file::disk_usage,
+        git::{
+            branch_changelog, branch_checkout, check_divergence, check_repository,
+            clone_repository, ensure_commit, fetch_refspecs, handle_local_changes, path_changed,
+            pull_lfs, pull_repository, resolve_branch, update_submodules, working_tree_changes,
+        },
+        notify::{Notification, Notifier},
+        registry::{image_tags, is_commit_tag, registry_credentials},
+        runner::SystemRunner,
+        signature::verify_revision,
+        state::{load_state, record_deployment, save_state},
+    },
+};
+
+/// the steps of a deployment, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    Fetch,
+    Checkout,
+    Render,
+    Build,
+    Push,
+    Pull,
+    Start,
+    Verify,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Fetch => write!(f, "fetch"),
+            Step::Checkout => write!(f, "checkout"),
+            Step::Render => write!(f, "render"),
+            Step::Build => write!(f, "build"),
+            Step::Push => write!(f, "push"),
+            Step::Pull => write!(f, "pull"),
+            Step::Start => write!(f, "start"),
+            Step::Verify => write!(f, "verify"),
+        }
+    }
+}
+
+/// progress reported to the event callback of a pipeline
+#[derive(Debug)]
+pub enum Event<'e> {
+    StepStarted(Step),
+    StepFinished(Step),
+    /// commits that are about to be deployed
+    Changelog(&'e Changelog),
+    Signature(&'e SignatureVerification),
+    /// uncommitted changes found in the repository before the checkout
+    LocalChanges(&'e [String]),
+}
+
+/// a question the confirm callback of a pipeline answers
+#[derive(Debug)]
+pub enum Confirmation<'e> {
+    /// deploy the commits of the changelog
+    Changelog(&'e Changelog),
+    /// reset the diverged local branch to the remote tip, dropping `ahead`
+    /// local commits, asked under the `prompt` divergence policy
+    Reset { branch: &'e str, ahead: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeployError {
+    /// the deployment was called off, e.g. the changelog was not confirmed
+    Cancelled(String),
+    /// nothing the application is built from changed since the deployed commit
+    Unchanged(String),
+    Clone,
+    Fetch,
+    UnknownBranch(String),
+    /// no branch was given and none can be asked for
+    MissingBranch,
+    Unverified(String),
+    LocalChanges,
+    Diverged,
+    BuildContext,
+    Build(String),
+    /// the application has no registry or its credentials are missing
+    Registry(String),
+    Push(String),
+    Pull(String),
+    Start(String),
+    Verify(String),
+    Restart(String),
+    Stop(String),
+    Remove(String),
+    PruneBuildCache,
+    /// reading or updating the clone failed
+    Git(String),
+    /// a step was run before the step it depends on
+    MissingStep(Step),
+}
+
+impl fmt::Display for DeployError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeployError::Cancelled(reason) => write!(f, "{}", reason),
+            DeployError::Unchanged(name) => {
+                write!(
+                    f,
+                    "{} is unchanged since the deployed commit, skipping",
+                    name
+                )
+            }
+            DeployError::Clone => write!(f, "Cloning the repository failed"),
+            DeployError::Fetch => write!(f, "Pulling the repository failed"),
+            DeployError::UnknownBranch(branch) => {
+                write!(f, "Unknown or not allowed branch `{}`", branch)
+            }
+            DeployError::MissingBranch => {
+                write!(f, "A branch is required in non-interactive mode")
+            }
+            DeployError::Unverified(message) => {
+                write!(f, "Refusing to deploy an unverified revision: {}", message)
+            }
+            DeployError::LocalChanges => {
+                write!(f, "Deployment aborted, the local changes were kept")
+            }
+            DeployError::Diverged => write!(f, "Deployment aborted, the diverged branch was kept"),
+            DeployError::BuildContext => write!(f, "Preparing the build context failed"),
+            DeployError::Build(project) => write!(f, "Building {} failed", project),
+            DeployError::Registry(message) => write!(f, "{}", message),
+            DeployError::Push(image) => write!(f, "Pushing {} failed", image),
+            DeployError::Pull(image) => write!(f, "Pulling {} failed", image),
+            DeployError::Start(project) => write!(f, "Starting {} failed", project),
+            DeployError::Verify(project) => write!(f, "Verifying {} failed", project),
+            DeployError::Restart(name) => write!(f, "Restarting {} failed", name),
+            DeployError::Stop(name) => write!(f, "Stopping {} failed", name),
+            DeployError::Remove(name) => write!(f, "Removing {} failed", name),
+            DeployError::PruneBuildCache => write!(f, "Pruning the build cache failed"),
+            DeployError::Git(message) => write!(f, "Git operation failed: {}", message),
+            DeployError::MissingStep(step) => write!(f, "The {} step has to run first", step),
+        }
+    }
+}
+
+impl std::error::Error for DeployError {}
+
+/// result of a successful deployment
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeployOutcome {
+    pub application: String,
+    pub branch: String,
+    pub commit: String,
+    pub compose_path: String,
+    pub project: String,
+    /// the color now serving traffic for blue/green applications
+    pub color: Option<Color>,
+    /// id of the built image
+    pub image: Option<String>,
+    pub signature: Option<SignatureVerification>,
+    pub changelog: Changelog,
+}
+
+/// what is deleted along with the containers when an application is removed
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Removal {
+    pub volumes: bool,
+    pub images: bool,
+    pub compose_file: bool,
+    pub clone: bool,
+    /// the deployment history in the state directory and the proxy config of
+    /// a blue/green application
+    pub state: bool,
+}
+
+/// the items deleted by a removal, with their size in bytes when it is known
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct RemovalSummary {
+    pub removed: Vec<(String, Option<u64>)>,
+}
+
+impl RemovalSummary {
+    /// the disk space freed by the items of known size
+    pub fn reclaimed(&self) -> u64 {
+        self.removed.iter().filter_map(|(_, size)| *size).sum()
+    }
+}
+
+/// where the repositories, compose files and state live and how the git and
+/// docker actions are carried out
+pub struct Deployer {
+    pub repository_root: String,
+    pub compose_directory: String,
+    pub state_directory: String,
+    pub secrets_directory: String,
+    git_username: String,
+    git_password: String,
+    pub dry_run: bool,
+    pub non_interactive: bool,
+    runtime: Runtime,
+    audit_log: Option<AuditLog>,
+    /// the git operations since the last audit entry, they are not run
+    /// through the runtime
+    git_operations: RefCell<Vec<String>>,
+    notifier: Option<Notifier>,
+    /// clones fetched by a pipeline of this deployer, shared clones are only
+    /// fetched once
+    fetched: RefCell<HashSet<String>>,
+}
+
+impl Deployer {
+    pub fn new(repository_root: &str, git_username: &str, git_password: &str) -> Deployer {
+        Deployer {
+            repository_root: repository_root.to_string(),
+            compose_directory: "./compose_files".to_string(),
+            state_directory: "./state".to_string(),
+            secrets_directory: "./secrets".to_string(),
+            git_username: git_username.to_string(),
+            git_password: git_password.to_string(),
+            dry_run: false,
+            non_interactive: false,
+            runtime: Runtime::compose(Rc::new(SystemRunner)),
+            audit_log: None,
+            git_operations: RefCell::new(vec![]),
+            notifier: None,
+            fetched: RefCell::new(HashSet::new()),
+        }
+    }
+
+    pub fn compose_directory(mut self, compose_directory: &str) -> Deployer {
+        self.compose_directory = compose_directory.to_string();
+        self
+    }
+
+    pub fn state_directory(mut self, state_directory: &str) -> Deployer {
+        self.state_directory = state_directory.to_string();
+        self
+    }
+
+    /// where the secrets referenced as `secret:NAME` are read from
+    pub fn secrets_directory(mut self, secrets_directory: &str) -> Deployer {
+        self.secrets_directory = secrets_directory.to_string();
+        self
+    }
+
+    /// how the containers are run, the docker compose CLI through a
+    /// [`SystemRunner`] by default
+    pub fn runtime(mut self, runtime: Runtime) -> Deployer {
+        self.runtime = runtime;
+        self
+    }
+
+    /// append every operation to the audit log, dry runs are not logged
+    pub fn audit_log(mut self, audit_log: AuditLog) -> Deployer {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// announce the deployments, their start, success and failure
+    pub fn notifier(mut self, notifier: Notifier) -> Deployer {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// print the planned git and docker actions instead of executing them
+    pub fn dry_run(mut self, dry_run: bool) -> Deployer {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// never prompt from within the git steps, e.g. for a diverged branch
+    pub fn non_interactive(mut self, non_interactive: bool) -> Deployer {
+        self.non_interactive = non_interactive;
+        self
+    }
+
+    pub fn repository_path(&self, application: &Application) -> String {
+        format!(
+            "{}/{}",
+            self.repository_root,
+            application.repository_directory()
+        )
+    }
+
+    /// the application as last deployed, running the registry image when it
+    /// was deployed from the registry
+    fn deployed_application(&self, application: &Application) -> Application {
+        let state = load_state(&self.state_directory, &application.slug);
+        match state
+            .deployments
+            .last()
+            .and_then(|record| record.reference.as_deref())
+        {
+            Some(reference) => registry_application(application, reference),
+            None => application.clone(),
+        }
+    }
+
+    fn compose_path(&self, application: &Application) -> String {
+        format!("{}/{}.yaml", self.compose_directory, application.slug)
+    }
+
+    /// append an operation to the audit log along with the commands run since
+    /// the previous entry, a failing audit log does not fail the operation
+    fn audit(
+        &self,
+        operation: &str,
+        application: Option<&Application>,
+        revision: Option<(String, String)>,
+        since: Instant,
+        error: Option<&DeployError>,
+    ) {
+        let commands = self.runtime.take_commands();
+        let git = self.git_operations.take();
+        let audit_log = match &self.audit_log {
+            Some(audit_log) if !self.dry_run => audit_log,
+            _ => return,
+        };
+        let outcome = match error {
+            None => Outcome::Success,
+            Some(DeployError::Cancelled(_)) => Outcome::Cancelled,
+            Some(DeployError::Unchanged(_)) => Outcome::Unchanged,
+            Some(_) => Outcome::Failure,
+        };
+        let (branch, commit) = revision.unzip();
+        let entry = AuditEntry {
+            timestamp: Utc::now().to_rfc3339(),
+            operator: current_operator(),
+            operation: operation.to_string(),
+            application: application.map(|application| application.name.clone()),
+            branch,
+            commit,
+            commands,
+            git,
+            duration_ms: since.elapsed().as_millis() as u64,
+            outcome,
+            error: error.map(ToString::to_string),
+        };
+        if let Err(e) = audit_log.append(&entry) {
+            eprintln!("Could not write the audit log: {}", e);
+        }
+    }
+
+    /// note a git operation for the audit log
+    fn record_git(&self, operation: String) {
+        self.git_operations.borrow_mut().push(operation);
+    }
+
+    /// a new deployment of the application
+    pub fn pipeline<'a>(&'a self, application: &'a Application) -> Pipeline<'a> {
+        Pipeline {
+            deployer: self,
+            application,
+            repository_path: self.repository_path(application),
+            on_event: Box::new(|_| {}),
+            confirm: Box::new(|confirmation| matches!(confirmation, Confirmation::Changelog(_))),
+            on_dirty: Box::new(|_| None),
+            dirty_policy: None,
+            build_flags: BuildFlags::default(),
+            branch: None,
+            changelog: None,
+            signature: None,
+            image: None,
+            prebuilt: None,
+            target: None,
+            since: Instant::now(),
+        }
+    }
+
+    /// restart the application, blue/green applications bring up the other
+    /// color from the existing image
+    pub fn restart(&self, application: &Application) -> Result<(), DeployError> {
+        let since = Instant::now();
+        let restarted = match &application.blue_green {
+            Some(_) => switch_color(
+                &self.runtime,
+                &self.repository_root,
+                &self.compose_directory,
+                &self.secrets_directory,
+                &self.state_directory,
+                &self.deployed_application(application),
+                self.dry_run,
+            ),
+            None => self.runtime.restart(
+                &self.compose_path(application),
+                &application.slug,
+                self.dry_run,
+            ),
+        };
+        let result = if restarted {
+            Ok(())
+        } else {
+            Err(DeployError::Restart(application.name.clone()))
+        };
+        self.audit(
+            "restart",
+            Some(application),
+            None,
+            since,
+            result.as_ref().err(),
+        );
+        result
+    }
+
+    pub fn stop(&self, application: &Application) -> Result<(), DeployError> {
+        let since = Instant::now();
+        let stopped = if application.blue_green.is_some() {
+            match active_target(
+                &self.compose_directory,
+                &self.state_directory,
+                &application.slug,
+            ) {
+                Some((compose_path, project)) => {
+                    self.runtime.stop(&compose_path, &project, self.dry_run)
+                }
+                None => {
+                    println!("There is no active deployment to stop");
+                    true
+                }
+            }
+        } else {
+            self.runtime.stop(
+                &self.compose_path(application),
+                &application.slug,
+                self.dry_run,
+            )
+        };
+        let result = if stopped {
+            Ok(())
+        } else {
+            Err(DeployError::Stop(application.name.clone()))
+        };
+        self.audit(
+            "stop",
+            Some(application),
+            None,
+            since,
+            result.as_ref().err(),
+        );
+        result
+    }
+
+    /// stop the application for good and delete what the removal asks for, in
+    /// dry-run mode nothing is deleted and the summary lists what would be
+    pub fn remove(
+        &self,
+        application: &Application,
+        removal: Removal,
+    ) -> Result<RemovalSummary, DeployError> {
+        let since = Instant::now();
+        let result = self.remove_files(application, removal);
+        self.audit(
+            "remove",
+            Some(application),
+            None,
+            since,
+            result.as_ref().err(),
+        );
+        result
+    }
+
+    fn remove_files(
+        &self,
+        application: &Application,
+        removal: Removal,
+    ) -> Result<RemovalSummary, DeployError> {
+        let mut summary = RemovalSummary::default();
+        let mut projects = vec![application.slug.clone()];
+        if application.blue_green.is_some() {
+            projects.extend(
+                [Color::Blue, Color::Green].map(|color| color_project(&application.slug, color)),
+            );
+        }
+        let compose_paths: Vec<(String, String)> = projects
+            .into_iter()
+            .map(|project| {
+                (
+                    format!("{}/{}.yaml", self.compose_directory, project),
+                    project,
+                )
+            })
+            .filter(|(compose_path, _)| Path::new(compose_path).exists())
+            .collect();
+        if compose_paths.is_empty() {
+            println!("There is no compose file of {}", application.name);
+        }
+        let image_size = (removal.images && !compose_paths.is_empty()).then(|| {
+            self.runtime
+                .inspect_image(&application.container.image)
+                .map(|image| image.size)
+        });
+        for (compose_path, project) in &compose_paths {
+            if !self.runtime.remove(
+                compose_path,
+                project,
+                removal.volumes,
+                removal.images,
+                self.dry_run,
+            ) {
+                return Err(DeployError::Remove(application.name.clone()));
+            }
+            if removal.volumes {
+                summary
+                    .removed
+                    .push((format!("named volumes of {}", project), None));
+            }
+        }
+        if let Some(size) = image_size {
+            summary
+                .removed
+                .push((format!("image {}", application.container.image), size));
+        }
+        if removal.images {
+            for tag in self.registry_tags(application) {
+                if self.runtime.inspect_image(&tag).is_none() {
+                    continue;
+                }
+                if !self.runtime.remove_image(&tag, self.dry_run) {
+                    return Err(DeployError::Remove(application.name.clone()));
+                }
+                summary.removed.push((format!("image {}", tag), None));
+            }
+        }
+        let mut paths = vec![];
+        if removal.compose_file {
+            paths.extend(compose_paths.into_iter().map(|(path, _)| path));
+        }
+        if removal.clone {
+            let repository_path = self.repository_path(application);
+            if Path::new(&repository_path).exists() {
+                paths.push(repository_path);
+            }
+        }
+        if removal.state {
+            let mut state_paths = vec![format!(
+                "{}/{}.toml",
+                self.state_directory, application.slug
+            )];
+            if let Some(blue_green) = &application.blue_green {
+                state_paths.push(blue_green.proxy_config.clone());
+            }
+            paths.extend(
+                state_paths
+                    .into_iter()
+                    .filter(|path| Path::new(path).exists()),
+            );
+        }
+        for path in paths {
+            let size = disk_usage(Path::new(&path));
+            if self.dry_run {
+                println!("[dry-run] would delete {}", path);
+            } else {
+                let deleted = if Path::new(&path).is_dir() {
+                    remove_dir_all(&path)
+                } else {
+                    remove_file(&path)
+                };
+                if let Err(e) = deleted {
+                    eprintln!("Deleting {} failed: {}", path, e);
+                    return Err(DeployError::Remove(application.name.clone()));
+                }
+                println!("Deleted {}", path);
+            }
+            summary.removed.push((path, Some(size)));
+        }
+        Ok(summary)
+    }
+    /// the registry images of the deployments in the state, the pushed commit
+    /// and branch tags and the pulled images
+    fn registry_tags(&self, application: &Application) -> Vec<String> {
+        let repository = match &application.registry {
+            Some(registry) => registry.image(),
+            None => return vec![],
+        };
+        let state = load_state(&self.state_directory, &application.slug);
+        let mut tags: Vec<String> = state
+            .deployments
+            .iter()
+            .flat_map(|record| match &record.reference {
+                Some(reference) => vec![reference.clone()],
+                None if record.commit.is_empty() => vec![],
+                None => pushed_tags(application, &record.branch, &record.commit)
+                    .into_iter()
+                    .map(|tag| format!("{}:{}", repository, tag))
+                    .collect(),
+            })
+            .collect();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+
+    /// remove the images of earlier deployments beyond the `keep_images` of the
+    /// application, images still used by a container are kept
+    pub fn prune(&self, application: &Application) -> RemovalSummary {
+        let mut summary = RemovalSummary::default();
+        let keep = match application.keep_images {
+            Some(keep) => keep.max(1),
+            None => return summary,
+        };
+        let since = Instant::now();
+        let mut state = load_state(&self.state_directory, &application.slug);
+        let mut images: Vec<&String> = vec![];
+        for record in state.deployments.iter().rev() {
+            if let Some(image) = record
+                .image
+                .as_ref()
+                .filter(|image| !images.contains(image))
+            {
+                images.push(image);
+            }
+        }
+        let mut pruned = vec![];
+        for id in images.into_iter().skip(keep) {
+            match self.runtime.inspect_image(id) {
+                Some(image) => {
+                    if !self.runtime.remove_image(&image.id, self.dry_run) {
+                        continue;
+                    }
+                    summary.removed.push((
+                        format!("image {} of {}", image.id, application.name),
+                        Some(image.size),
+                    ));
+                }
+                None => println!("The image {} is already gone", id),
+            }
+            pruned.push(id.clone());
+        }
+        if !self.dry_run && !pruned.is_empty() {
+            for record in state.deployments.iter_mut() {
+                if record.image.as_ref().is_some_and(|id| pruned.contains(id)) {
+                    record.image = None;
+                }
+            }
+            save_state(&self.state_directory, &application.slug, &state);
+        }
+        self.audit("prune", Some(application), None, since, None);
+        summary
+    }
+
+    /// prune the build cache of the engine down to `keep_storage` bytes
+    pub fn prune_build_cache(&self, keep_storage: u64) -> Result<(), DeployError> {
+        let since = Instant::now();
+        let result = if self.runtime.prune_build_cache(keep_storage, self.dry_run) {
+            Ok(())
+        } else {
+            Err(DeployError::PruneBuildCache)
+        };
+        self.audit(
+            "prune-build-cache",
+            None,
+            None,
+            since,
+            result.as_ref().err(),
+        );
+        result
+    }
+}
+
+/// compose file and project a deployment is brought up as
+struct Target {
+    compose_path: String,
+    project: String,
+    color: Option<Color>,
+}
+
+type DirtyCallback<'a> = Box<dyn FnMut(&[String]) -> Option<DirtyPolicy> + 'a>;
+
+fn git_failed(error: git2::Error) -> DeployError {
+    DeployError::Git(error.message().to_string())
+}
+
+/// the tags an image built from `commit` of `branch` is pushed as, branches of
+/// the primary remote are tagged without the remote name
+fn pushed_tags(application: &Application, branch: &str, commit: &str) -> Vec<String> {
+    let branch = branch
+        .strip_prefix(&format!("{}/", application.remote))
+        .unwrap_or(branch);
+    image_tags(commit, branch)
+}
+
+/// the application running a pulled image instead of building one
+fn registry_application(application: &Application, reference: &str) -> Application {
+    let mut prebuilt = application.clone();
+    prebuilt.container.image = reference.to_string();
+    prebuilt.container.build = Build::default();
+    prebuilt
+}
+
+/// a single deployment of an application, the steps can be run one by one or
+/// all at once with [`Pipeline::run`]
+pub struct Pipeline<'a> {
+    deployer: &'a Deployer,
+    application: &'a Application,
+    repository_path: String,
+    on_event: Box<dyn FnMut(Event) + 'a>,
+    confirm: Box<dyn FnMut(Confirmation) -> bool + 'a>,
+    on_dirty: DirtyCallback<'a>,
+    dirty_policy: Option<DirtyPolicy>,
+    build_flags: BuildFlags,
+    branch: Option<String>,
+    changelog: Option<Changelog>,
+    signature: Option<SignatureVerification>,
+    image: Option<String>,
+    /// the application running the pulled image when deploying from the registry
+    prebuilt: Option<Application>,
+    target: Option<Target>,
+    /// when the deployment started, for the audit log
+    since: Instant,
+}
+
+impl<'a> Pipeline<'a> {
+    /// called with the progress of the deployment
+    pub fn on_event(mut self, callback: impl FnMut(Event) + 'a) -> Pipeline<'a> {
+        self.on_event = Box::new(callback);
+        self
+    }
+
+    /// called with the changelog before checking out and before resetting a
+    /// diverged branch, returning false cancels the deployment or keeps the
+    /// branch; not called in dry-run mode, by default the changelog is accepted
+    /// and the branch is kept
+    pub fn confirm(mut self, callback: impl FnMut(Confirmation) -> bool + 'a) -> Pipeline<'a> {
+        self.confirm = Box::new(callback);
+        self
+    }
+
+    /// how local changes are handled, without a policy the deployment aborts
+    pub fn dirty_policy(mut self, policy: Option<DirtyPolicy>) -> Pipeline<'a> {
+        self.dirty_policy = policy;
+        self
+    }
+
+    /// called with the local changes when no dirty policy is set
+    pub fn on_dirty(
+        mut self,
+        callback: impl FnMut(&[String]) -> Option<DirtyPolicy> + 'a,
+    ) -> Pipeline<'a> {
+        self.on_dirty = Box::new(callback);
+        self
+    }
+
+    /// build without the layer cache and pull the base images, regardless of
+    /// the build section of the application
+    pub fn build_flags(mut self, flags: BuildFlags) -> Pipeline<'a> {
+        self.build_flags = flags;
+        self
+    }
+
+    pub fn repository_path(&self) -> &str {
+        &self.repository_path
+    }
+
+    fn started(&mut self, step: Step) {
+        (self.on_event)(Event::StepStarted(step));
+    }
+
+    fn finished(&mut self, step: Step) {
+        (self.on_event)(Event::StepFinished(step));
+    }
+
+    /// write the audit entry of the deployment with the revision checked out
+    /// or pulled so far
+    fn audit(&self, error: Option<&DeployError>) {
+        let revision = match (&self.branch, &self.changelog) {
+            (Some(branch), Some(changelog)) => Some((branch.clone(), changelog.target.clone())),
+            _ => None,
+        };
+        self.deployer.audit(
+            "deploy",
+            Some(self.application),
+            revision,
+            self.since,
+            error,
+        );
+    }
+
+    /// announce the deployment with the revision checked out or pulled so far
+    fn notify(&self, event: NotificationEvent, error: Option<&DeployError>) {
+        let notifier = match &self.deployer.notifier {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        let notification = Notification {
+            event,
+            application: self.application.name.clone(),
+            branch: self.branch.clone(),
+            commit: self
+                .changelog
+                .as_ref()
+                .map(|changelog| changelog.target.clone()),
+            operator: current_operator(),
+            duration: self.since.elapsed(),
+            error: error.map(ToString::to_string),
+            log: error.and_then(|_| self.deployer.runtime.failure_log()),
+        };
+        notifier.send(&notification, self.deployer.dry_run);
+    }
+
+    /// announce and log a failed deployment, a cancelled or unchanged one is
+    /// only logged; the notification goes first as the audit log takes the
+    /// output of the failed command
+    fn failed(&self, error: &DeployError) {
+        let event = match error {
+            DeployError::Cancelled(_) | DeployError::Unchanged(_) => None,
+            DeployError::Start(_) | DeployError::Verify(_)
+                if self.application.blue_green.is_some() =>
+            {
+                Some(NotificationEvent::RolledBack)
+            }
+            _ => Some(NotificationEvent::Failed),
+        };
+        if let Some(event) = event {
+            self.notify(event, Some(error));
+        }
+        self.audit(Some(error));
+    }
+
+    /// end the deployment before its first step in the audit log, e.g. when
+    /// the clone or the branch selection was declined; returns the error
+    pub fn abort(&self, error: DeployError) -> DeployError {
+        self.failed(&error);
+        error
+    }
+
+    /// clone the repository if it is missing and fetch all remotes, a clone
+    /// already fetched by another pipeline of the deployer is kept as is; a
+    /// failed fetch ends the deployment in the audit log
+    pub fn fetch(&mut self) -> Result<(), DeployError> {
+        let fetched = self.fetch_remotes();
+        if let Err(e) = &fetched {
+            self.failed(e);
+        }
+        fetched
+    }
+
+    fn fetch_remotes(&mut self) -> Result<(), DeployError> {
+        self.started(Step::Fetch);
+        let deployer = self.deployer;
+        if deployer.fetched.borrow().contains(&self.repository_path) {
+            println!("{} has already been fetched", self.repository_path);
+            self.finished(Step::Fetch);
+            return Ok(());
+        }
+        let repo_exists = check_repository(Path::new(&self.repository_path));
+        if !repo_exists {
+            if deployer.dry_run {
+                println!(
+                    "[dry-run] would clone {} into {}",
+                    self.application.repository_url, self.repository_path
+                );
+                return Err(DeployError::Cancelled(
+                    "[dry-run] the remaining steps require an existing clone".to_string(),
+                ));
+            }
+            deployer.record_git(format!(
+                "clone {} into {}",
+                self.application.repository_url, self.repository_path
+            ));
+            if !clone_repository(
+                &deployer.git_username,
+                &deployer.git_password,
+                self.application,
+                &self.repository_path,
+                deployer.non_interactive,
+            ) {
+                return Err(DeployError::Clone);
+            }
+        }
+        // a fresh clone only has the primary remote, the forks still need a fetch
+        if repo_exists || !self.application.forks.is_empty() {
+            for remote in self.application.remotes() {
+                deployer.record_git(format!(
+                    "fetch {} {}",
+                    remote.name,
+                    fetch_refspecs(self.application, &remote.name).join(" ")
+                ));
+            }
+            if !pull_repository(
+                &deployer.git_username,
+                &deployer.git_password,
+                self.application,
+                &self.repository_path,
+                deployer.dry_run,
+                deployer.non_interactive,
+            ) {
+                return Err(DeployError::Fetch);
+            }
+            println!("All branches have been fetched and updated successfully.");
+        }
+        deployer
+            .fetched
+            .borrow_mut()
+            .insert(self.repository_path.clone());
+        self.finished(Step::Fetch);
+        Ok(())
+    }
+
+    /// the remote branch matching `branch`, with or without the remote name,
+    /// the clone has to be fetched first
+    pub fn resolve_branch(&self, branch: &str) -> Result<String, DeployError> {
+        if !check_repository(Path::new(&self.repository_path)) {
+            return Err(DeployError::MissingStep(Step::Fetch));
+        }
+        resolve_branch(&self.repository_path, self.application, branch)
+            .map_err(git_failed)?
+            .ok_or_else(|| DeployError::UnknownBranch(branch.to_string()))
+    }
+
+    /// confirm the changelog, verify the signature and check out the branch
+    /// along with its submodules and LFS objects
+    pub fn checkout(&mut self, branch: &str) -> Result<(), DeployError> {
+        self.started(Step::Checkout);
+        let deployer = self.deployer;
+        let application = self.application;
+        let branch = self.resolve_branch(branch)?;
+        println!("Selected branch is {:?}", branch);
+
+        let state = load_state(&deployer.state_directory, &application.slug);
+        let deployed_commit = state.deployments.last().map(|d| d.commit.as_str());
+        // a registry tag without a commit, the changelog starts at HEAD
+        let deployed_commit = deployed_commit.filter(|commit| !commit.is_empty());
+        let deployed_commit = deployed_commit.filter(|commit| {
+            let available = ensure_commit(
+                &deployer.git_username,
+                &deployer.git_password,
+                application,
+                &self.repository_path,
+                commit,
+                deployer.dry_run,
+                deployer.non_interactive,
+            );
+            if !available {
+                println!(
+                    "The deployed commit {} is not available, the changelog starts at HEAD",
+                    commit
+                );
+            }
+            available
+        });
+        let changelog = branch_changelog(&self.repository_path, deployed_commit, &branch)
+            .map_err(git_failed)?;
+        if application.skip_unchanged
+            && deployed_commit.is_some_and(|commit| {
+                !path_changed(
+                    &self.repository_path,
+                    commit,
+                    &changelog.target,
+                    application.context.as_deref(),
+                )
+            })
+        {
+            return Err(DeployError::Unchanged(application.name.clone()));
+        }
+        (self.on_event)(Event::Changelog(&changelog));
+        if !deployer.dry_run && !(self.confirm)(Confirmation::Changelog(&changelog)) {
+            return Err(DeployError::Cancelled("Deployment cancelled".to_string()));
+        }
+
+        if application.require_signed {
+            let verification =
+                verify_revision(&self.repository_path, &changelog.target, application);
+            if !verification.verified {
+                return Err(DeployError::Unverified(verification.message));
+            }
+            (self.on_event)(Event::Signature(&verification));
+            self.signature = Some(verification);
+        }
+
+        let local_changes = working_tree_changes(&self.repository_path).map_err(git_failed)?;
+        if !local_changes.is_empty() {
+            (self.on_event)(Event::LocalChanges(&local_changes));
+            let policy = match self.dirty_policy {
+                Some(policy) => Some(policy),
+                None if deployer.dry_run => None,
+                None => (self.on_dirty)(&local_changes),
+            };
+            if deployer.dry_run {
+                if let Some(policy) = policy {
+                    println!("[dry-run] would apply: {}", policy);
+                }
+            } else if !policy
+                .is_some_and(|policy| handle_local_changes(&self.repository_path, policy))
+            {
+                return Err(DeployError::LocalChanges);
+            }
+        }
+        let confirm = &mut self.confirm;
+        if !check_divergence(
+            &self.repository_path,
+            &branch,
+            application.divergence_policy,
+            deployer.dry_run,
+            deployer.non_interactive,
+            |ahead| {
+                confirm(Confirmation::Reset {
+                    branch: &branch,
+                    ahead,
+                })
+            },
+        )
+        .map_err(git_failed)?
+        {
+            return Err(DeployError::Diverged);
+        }
+        deployer.record_git(format!("checkout {} at {}", branch, changelog.target));
+        branch_checkout(&self.repository_path, branch.clone(), deployer.dry_run)
+            .map_err(git_failed)?;
+        if Path::new(&self.repository_path)
+            .join(".gitmodules")
+            .exists()
+        {
+            deployer.record_git("submodule update --init --recursive".to_string());
+        }
+        if application.lfs {
+            deployer.record_git("lfs pull".to_string());
+        }
+        if !update_submodules(
+            &deployer.git_username,
+            &deployer.git_password,
+            &self.repository_path,
+            deployer.dry_run,
+            deployer.non_interactive,
+        ) || (application.lfs
+            && !pull_lfs(
+                &deployer.git_username,
+                &deployer.git_password,
+                &self.repository_path,
+                deployer.dry_run,
+            ))
+        {
+            return Err(DeployError::BuildContext);
+        }
+        self.branch = Some(branch);
+        self.changelog = Some(changelog);
+        self.finished(Step::Checkout);
+        Ok(())
+    }
+
+    /// generate the compose file, for blue/green applications the one of the
+    /// inactive color
+    pub fn render(&mut self) -> Result<(), DeployError> {
+        if self.branch.is_none() {
+            return Err(DeployError::MissingStep(Step::Checkout));
+        }
+        self.started(Step::Render);
+        let deployer = self.deployer;
+        let application = self.prebuilt.as_ref().unwrap_or(self.application);
+        let target = match &application.blue_green {
+            Some(blue_green) => {
+                let color = target_color(&deployer.state_directory, &application.slug);
+                println!("Deploying {} as {}", application.name, color);
+                let (compose_path, project) = render_color(
+                    &deployer.repository_root,
+                    &deployer.compose_directory,
+                    &deployer.secrets_directory,
+                    application,
+                    blue_green,
+                    color,
+                    deployer.dry_run,
+                );
+                Target {
+                    compose_path,
+                    project,
+                    color: Some(color),
+                }
+            }
+            None => Target {
+                compose_path: generate_compose(
+                    &application.build_directory(&deployer.repository_root),
+                    &deployer.compose_directory,
+                    &deployer.secrets_directory,
+                    &application.slug,
+                    &application.container,
+                    deployer.dry_run,
+                ),
+                project: application.slug.clone(),
+                color: None,
+            },
+        };
+        self.target = Some(target);
+        self.finished(Step::Render);
+        Ok(())
+    }
+
+    pub fn build(&mut self) -> Result<(), DeployError> {
+        let target = self
+            .target
+            .as_ref()
+            .ok_or(DeployError::MissingStep(Step::Render))?;
+        let (compose_path, project) = (target.compose_path.clone(), target.project.clone());
+        self.started(Step::Build);
+        if !self
+            .deployer
+            .runtime
+            .build(&compose_path, self.build_flags, self.deployer.dry_run)
+        {
+            return Err(DeployError::Build(project));
+        }
+        if !self.deployer.dry_run {
+            self.image = self
+                .deployer
+                .runtime
+                .inspect_image(&self.application.container.image)
+                .map(|image| image.id);
+        }
+        self.finished(Step::Build);
+        Ok(())
+    }
+
+    /// tag the built image with the commit and the branch and push it, a no-op
+    /// without a registry
+    pub fn push(&mut self) -> Result<(), DeployError> {
+        let registry = match &self.application.registry {
+            Some(registry) => registry,
+            None => return Ok(()),
+        };
+        let tags = match (&self.branch, &self.changelog) {
+            (Some(branch), Some(changelog)) => {
+                pushed_tags(self.application, branch, &changelog.target)
+            }
+            _ => return Err(DeployError::MissingStep(Step::Checkout)),
+        };
+        self.started(Step::Push);
+        let credentials = registry_credentials(registry).map_err(DeployError::Registry)?;
+        let repository = registry.image();
+        if !self.deployer.runtime.push_image(
+            &self.application.container.image,
+            &repository,
+            &tags,
+            credentials.as_ref(),
+            self.deployer.dry_run,
+        ) {
+            return Err(DeployError::Push(repository));
+        }
+        self.finished(Step::Push);
+        Ok(())
+    }
+
+    /// pull `tag` of the image from the registry, the following steps run the
+    /// pulled image; a commit tag is recorded as the deployed commit
+    pub fn pull(&mut self, tag: &str) -> Result<(), DeployError> {
+        let application = self.application;
+        let registry = application.registry.as_ref().ok_or_else(|| {
+            DeployError::Registry(format!("{} has no registry to pull from", application.name))
+        })?;
+        self.started(Step::Pull);
+        // the commit tag every pushed image gets, a branch tag has no commit
+        let commit = if is_commit_tag(tag) { tag } else { "" };
+        if application.require_signed {
+            if commit.is_empty() {
+                return Err(DeployError::Unverified(format!(
+                    "the tag `{}` is not a commit, only the commit tags of signed commits can be deployed",
+                    tag
+                )));
+            }
+            let verification = verify_revision(&self.repository_path, commit, application);
+            if !verification.verified {
+                return Err(DeployError::Unverified(verification.message));
+            }
+            (self.on_event)(Event::Signature(&verification));
+            self.signature = Some(verification);
+        }
+        let credentials = registry_credentials(registry).map_err(DeployError::Registry)?;
+        let repository = registry.image();
+        if !self.deployer.runtime.pull_image(
+            &repository,
+            tag,
+            credentials.as_ref(),
+            self.deployer.dry_run,
+        ) {
+            return Err(DeployError::Pull(format!("{}:{}", repository, tag)));
+        }
+        let reference = format!("{}:{}", repository, tag);
+        let prebuilt = registry_application(application, &reference);
+        if !self.deployer.dry_run {
+            self.image = self
+                .deployer
+                .runtime
+                .inspect_image(&reference)
+                .map(|image| image.id);
+        }
+        self.prebuilt = Some(prebuilt);
+        // the branch of an earlier deployment of the commit, or the branch tag
+        let state = load_state(&self.deployer.state_directory, &application.slug);
+        let branch = match state
+            .deployments
+            .iter()
+            .rev()
+            .find(|record| !commit.is_empty() && record.commit == commit)
+        {
+            Some(record) => record.branch.clone(),
+            None if commit.is_empty() => tag.to_string(),
+            None => String::new(),
+        };
+        self.branch = Some(branch);
+        self.changelog = Some(Changelog {
+            target: commit.to_string(),
+            ..Changelog::default()
+        });
+        self.finished(Step::Pull);
+        Ok(())
+    }
+
+    /// bring up the compose project, a failed blue/green color is torn down again
+    pub fn start(&mut self) -> Result<(), DeployError> {
+        let target = self
+            .target
+            .as_ref()
+            .ok_or(DeployError::MissingStep(Step::Render))?;
+        let (compose_path, project) = (target.compose_path.clone(), target.project.clone());
+        let dry_run = self.deployer.dry_run;
+        self.started(Step::Start);
+        if !self
+            .deployer
+            .runtime
+            .start(&compose_path, &project, dry_run)
+        {
+            if self.application.blue_green.is_some() {
+                self.deployer.runtime.stop(&compose_path, &project, dry_run);
+            }
+            return Err(DeployError::Start(project));
+        }
+        self.finished(Step::Start);
+        Ok(())
+    }
+
+    /// blue/green applications wait for the health check before the proxy is
+    /// switched and the previous color is torn down
+    pub fn verify(&mut self) -> Result<(), DeployError> {
+        let target = self
+            .target
+            .as_ref()
+            .ok_or(DeployError::MissingStep(Step::Render))?;
+        let (project, color) = (target.project.clone(), target.color);
+        self.started(Step::Verify);
+        let deployer = self.deployer;
+        if let (Some(blue_green), Some(color)) = (&self.application.blue_green, color) {
+            if !promote_color(
+                &deployer.runtime,
+                &deployer.compose_directory,
+                &deployer.state_directory,
+                self.application,
+                blue_green,
+                color,
+                deployer.dry_run,
+            ) {
+                return Err(DeployError::Verify(project));
+            }
+        }
+        self.finished(Step::Verify);
+        Ok(())
+    }
+
+    /// record the deployment in the state of the application
+    pub fn finish(self) -> Result<DeployOutcome, DeployError> {
+        let (branch, changelog, target) = match (self.branch, self.changelog, self.target) {
+            (Some(branch), Some(changelog), Some(target)) => (branch, changelog, target),
+            (None, _, _) | (_, None, _) => return Err(DeployError::MissingStep(Step::Checkout)),
+            (_, _, None) => return Err(DeployError::MissingStep(Step::Render)),
+        };
+        if !self.deployer.dry_run {
+            record_deployment(
+                &self.deployer.state_directory,
+                &self.application.slug,
+                DeploymentRecord {
+                    timestamp: Utc::now().to_rfc3339(),
+                    branch: branch.clone(),
+                    commit: changelog.target.clone(),
+                    image: self.image.clone(),
+                    reference: self
+                        .prebuilt
+                        .as_ref()
+                        .map(|prebuilt| prebuilt.container.image.clone()),
+                    signature: self.signature.clone(),
+                    changelog: changelog.entries.clone(),
+                },
+            );
+        }
+        Ok(DeployOutcome {
+            application: self.application.name.clone(),
+            commit: changelog.target.clone(),
+            branch,
+            compose_path: target.compose_path,
+            project: target.project,
+            color: target.color,
+            image: self.image,
+            signature: self.signature,
+            changelog,
+        })
+    }
+
+    /// every step after the fetch, for when the branch is chosen in between
+    pub fn deploy(mut self, branch: &str) -> Result<DeployOutcome, DeployError> {
+        if let Err(e) = self.deploy_steps(branch) {
+            self.failed(&e);
+            return Err(e);
+        }
+        self.notify(NotificationEvent::Succeeded, None);
+        self.audit(None);
+        self.finish()
+    }
+
+    fn deploy_steps(&mut self, branch: &str) -> Result<(), DeployError> {
+        self.checkout(branch)?;
+        self.notify(NotificationEvent::Started, None);
+        self.render()?;
+        self.build()?;
+        self.push()?;
+        self.start()?;
+        self.verify()
+    }
+
+    /// fetch and deploy the branch
+    pub fn run(mut self, branch: &str) -> Result<DeployOutcome, DeployError> {
+        self.fetch()?;
+        self.deploy(branch)
+    }
+
+    /// deploy an image pushed by an earlier deployment instead of building
+    /// one, the repository is not needed
+    pub fn deploy_from_registry(mut self, tag: &str) -> Result<DeployOutcome, DeployError> {
+        if let Err(e) = self.registry_steps(tag) {
+            self.failed(&e);
+            return Err(e);
+        }
+        self.notify(NotificationEvent::Succeeded, None);
+        self.audit(None);
+        self.finish()
+    }
+
+    fn registry_steps(&mut self, tag: &str) -> Result<(), DeployError> {
+        self.pull(tag)?;
+        self.notify(NotificationEvent::Started, None);
+        self.render()?;
+        self.start()?;
+        self.verify()
+    }
+}
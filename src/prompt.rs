@@ -0,0 +1,90 @@
+use autodeploy::{
+    objects::structs::{Application, DirtyPolicy},
+    utils::git::deployable_branches,
+};
+use inquire::{Confirm, Select};
+
+/// ask whether a missing repository should be cloned
+pub fn confirm_clone(repository_path: &str) -> bool {
+    let clone_allow = Select::new(
+        "Repository does not seem to exist.\nWould you like to clone it",
+        vec!["Yes", "No"],
+    )
+    .prompt();
+    match clone_allow {
+        Ok("Yes") => true,
+        Ok(_) => {
+            eprintln!(
+                "Please clone the repo manually at {repository_path} to proceed with deployment"
+            );
+            false
+        }
+        Err(_) => {
+            println!("There was an error, please try again, choose a valid option");
+            false
+        }
+    }
+}
+
+/// prompt the user to select a branch.
+pub fn prompt_branch_selection(repository_path: &str, application: &Application) -> Option<String> {
+    // repo is assumed to be fetched already
+    let branch_list = match deployable_branches(repository_path, application) {
+        Ok(branch_list) => branch_list,
+        Err(e) => {
+            eprintln!("Cannot fetch branches: {}", e.message());
+            return None;
+        }
+    };
+    // prompt the user to select the branch
+    let branch_selection = Select::new("choose the branch to be deployed", branch_list).prompt();
+
+    match branch_selection {
+        Ok(branch) => Some(branch.name),
+        Err(_) => {
+            println!("There was an error, please try again, choose a valid branch");
+            None
+        }
+    }
+}
+
+/// ask the user to confirm the deployment of the changelog
+pub fn confirm_changelog() -> bool {
+    match Confirm::new("Deploy these changes?")
+        .with_default(true)
+        .prompt()
+    {
+        Ok(answer) => answer,
+        Err(_) => {
+            println!("There was an error, please try again");
+            false
+        }
+    }
+}
+
+/// ask the user whether the diverged branch may be reset to the remote tip
+pub fn confirm_reset(branch: &str, ahead: usize) -> bool {
+    Confirm::new(&format!(
+        "Reset '{}' to the remote tip, dropping {} local commit(s)?",
+        branch, ahead
+    ))
+    .with_default(false)
+    .prompt()
+    .unwrap_or(false)
+}
+
+/// ask the user how local changes should be handled
+pub fn prompt_dirty_policy() -> Option<DirtyPolicy> {
+    let policy = Select::new(
+        "The repository has local changes that the checkout would overwrite",
+        vec![DirtyPolicy::Abort, DirtyPolicy::Stash, DirtyPolicy::Discard],
+    )
+    .prompt();
+    match policy {
+        Ok(policy) => Some(policy),
+        Err(_) => {
+            println!("There was an error, please try again, choose a valid option");
+            None
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    env::var,
+    fs::{OpenOptions, create_dir_all, metadata, read_to_string, remove_file, rename},
+    io::Write,
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::Path,
+};
+
+use crate::objects::structs::{AuditEntry, Operator};
+
+/// an append-only log of JSON lines, rotated to `<path>.1` … `<path>.<files>`
+/// before it grows beyond `max_size`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditLog {
+    path: String,
+    max_size: u64,
+    files: usize,
+}
+
+impl AuditLog {
+    /// a log rotated at 10MB keeping five rotated files
+    pub fn new(path: &str) -> AuditLog {
+        AuditLog {
+            path: path.to_string(),
+            max_size: 10 * 1024 * 1024,
+            files: 5,
+        }
+    }
+
+    pub fn rotate(mut self, max_size: u64, files: usize) -> AuditLog {
+        self.max_size = max_size;
+        self.files = files;
+        self
+    }
+
+    fn rotated(&self, index: usize) -> String {
+        format!("{}.{}", self.path, index)
+    }
+
+    /// shift the rotated files by one, the oldest is dropped
+    fn rotate_files(&self) -> Result<(), String> {
+        if self.files == 0 {
+            return remove_file(&self.path).map_err(|e| e.to_string());
+        }
+        for index in (1..self.files).rev() {
+            let from = self.rotated(index);
+            if Path::new(&from).exists() {
+                rename(&from, self.rotated(index + 1)).map_err(|e| e.to_string())?;
+            }
+        }
+        rename(&self.path, self.rotated(1)).map_err(|e| e.to_string())
+    }
+
+    /// append an entry, only the owner of the log can read it
+    pub fn append(&self, entry: &AuditEntry) -> Result<(), String> {
+        let mut line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+        line.push('\n');
+        if let Some(parent) = Path::new(&self.path)
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let size = metadata(&self.path).map_or(0, |metadata| metadata.len());
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            self.rotate_files()?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|e| format!("could not write `{}`: {}", self.path, e))
+    }
+
+    /// the entries of the rotated files and the log, oldest first, only the
+    /// ones of `application` if given; lines that do not parse are skipped
+    pub fn entries(&self, application: Option<&str>) -> Vec<AuditEntry> {
+        let mut paths: Vec<String> = (1..=self.files).rev().map(|i| self.rotated(i)).collect();
+        paths.push(self.path.clone());
+        paths
+            .iter()
+            .filter_map(|path| read_to_string(path).ok())
+            .flat_map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                    .collect::<Vec<AuditEntry>>()
+            })
+            .filter(|entry| {
+                application.is_none_or(|name| entry.application.as_deref() == Some(name))
+            })
+            .collect()
+    }
+}
+
+/// the name of a user id in `/etc/passwd`
+fn user_name(uid: u32) -> Option<String> {
+    let passwd = read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        (fields.get(2) == Some(&uid.to_string().as_str())).then(|| fields[0].to_string())
+    })
+}
+
+fn hostname() -> String {
+    read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| read_to_string("/etc/hostname"))
+        .map(|hostname| hostname.trim().to_string())
+        .ok()
+        .filter(|hostname| !hostname.is_empty())
+        .or_else(|| var("HOSTNAME").ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// who runs autodeploy, the user is the owner of the process rather than
+/// `$USER`, which can be set to anything
+pub fn current_operator() -> Operator {
+    let uid = metadata("/proc/self").map(|metadata| metadata.uid()).ok();
+    let user = uid
+        .and_then(user_name)
+        .or_else(|| var("USER").ok())
+        .or_else(|| uid.map(|uid| uid.to_string()))
+        .unwrap_or_else(|| "unknown".to_string());
+    Operator {
+        user,
+        sudo_user: var("SUDO_USER").ok(),
+        hostname: hostname(),
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    fs::{read_to_string, remove_file, write},
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    objects::structs::{Application, BlueGreen, Color, Container, Proxy},
+    utils::{
+        docker::{Runtime, execute_command, generate_compose},
+        state::{load_state, save_state},
+    },
+};
+
+/// compose project name used for a color of an application
+pub fn color_project(slug: &str, color: Color) -> String {
+    format!("{}-{}", slug, color)
+}
+
+/// the compose file and project currently serving traffic for an application
+pub fn active_target(
+    compose_directory: &str,
+    state_directory: &str,
+    slug: &str,
+) -> Option<(String, String)> {
+    let state = load_state(state_directory, slug);
+    state.active_color.map(|color| {
+        let project = color_project(slug, color);
+        (format!("{}/{}.yaml", compose_directory, project), project)
+    })
+}
+
+fn color_port(blue_green: &BlueGreen, color: Color) -> u16 {
+    match color {
+        Color::Blue => blue_green.blue_port,
+        Color::Green => blue_green.green_port,
+    }
+}
+
+/// the container of a color gets its own name and only publishes the color's
+/// port, so both stacks can run side by side
+fn color_container(container: &Container, blue_green: &BlueGreen, color: Color) -> Container {
+    let mut container = container.clone();
+    container.name = format!("{}-{}", container.name, color);
+    container.ports = vec![format!(
+        "{}:{}",
+        color_port(blue_green, color),
+        blue_green.container_port
+    )];
+    container
+}
+
+/// single plain HTTP request against the health endpoint, any 2xx is healthy
+fn check_health(host: &str, port: u16, path: &str) -> bool {
+    let address = match (host, port).to_socket_addrs() {
+        Ok(mut addresses) => match addresses.next() {
+            Some(address) => address,
+            None => return false,
+        },
+        Err(_) => return false,
+    };
+    let mut stream = match TcpStream::connect_timeout(&address, Duration::from_secs(2)) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok();
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    if stream.write_all(request.as_bytes()).is_err() {
+        return false;
+    }
+    let mut status_line = String::new();
+    if BufReader::new(stream).read_line(&mut status_line).is_err() {
+        return false;
+    }
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .is_some_and(|code| code.starts_with('2'))
+}
+
+/// poll the health endpoint until it responds or the timeout elapses
+pub fn wait_for_health(blue_green: &BlueGreen, color: Color) -> bool {
+    let port = color_port(blue_green, color);
+    let deadline = Instant::now() + Duration::from_secs(blue_green.health_timeout);
+    println!(
+        "Waiting for http://{}:{}{} to become healthy",
+        blue_green.host, port, blue_green.health_path
+    );
+    loop {
+        if check_health(&blue_green.host, port, &blue_green.health_path) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep(Duration::from_secs(2));
+    }
+}
+
+/// render the upstream definition understood by the configured reverse proxy
+pub fn render_proxy_config(slug: &str, blue_green: &BlueGreen, color: Color) -> String {
+    let upstream = format!("{}:{}", blue_green.host, color_port(blue_green, color));
+    match blue_green.proxy {
+        Proxy::Nginx => format!(
+            "# generated by autodeploy, active color: {}\nupstream {} {{\n    server {};\n}}\n",
+            color, slug, upstream
+        ),
+        Proxy::Caddy => format!(
+            "# generated by autodeploy, active color: {}\nreverse_proxy {}\n",
+            color, upstream
+        ),
+        Proxy::Traefik => format!(
+            "# generated by autodeploy, active color: {}\nhttp:\n  services:\n    {}:\n      loadBalancer:\n        servers:\n          - url: \"http://{}\"\n",
+            color, slug, upstream
+        ),
+    }
+}
+
+/// point the reverse proxy at the given color and reload it if required, the
+/// previous configuration is put back when the reload fails
+fn switch_proxy(
+    runtime: &Runtime,
+    slug: &str,
+    blue_green: &BlueGreen,
+    color: Color,
+    dry_run: bool,
+) -> bool {
+    let proxy_config = render_proxy_config(slug, blue_green, color);
+    let previous = read_to_string(&blue_green.proxy_config).ok();
+    if dry_run {
+        println!(
+            "[dry-run] would write {}:\n{}",
+            blue_green.proxy_config, proxy_config
+        );
+    } else if write(&blue_green.proxy_config, proxy_config).is_err() {
+        eprintln!(
+            "Could not write proxy configuration `{}`",
+            blue_green.proxy_config
+        );
+        return false;
+    }
+    let reloaded = match &blue_green.proxy_reload {
+        Some(reload) => {
+            let mut parts = reload.split_whitespace();
+            match parts.next() {
+                Some(command) => {
+                    execute_command(runtime.runner(), command, parts.collect(), dry_run)
+                }
+                None => true,
+            }
+        }
+        None => true,
+    };
+    if dry_run {
+        return reloaded;
+    }
+    if !reloaded {
+        let restored = match previous {
+            Some(previous) => write(&blue_green.proxy_config, previous),
+            None => remove_file(&blue_green.proxy_config),
+        };
+        if restored.is_err() {
+            eprintln!(
+                "Could not restore proxy configuration `{}`",
+                blue_green.proxy_config
+            );
+        }
+        return false;
+    }
+    println!("Proxy upstream switched to {}", color);
+    true
+}
+
+/// the color a new deployment of the application is brought up as
+pub fn target_color(state_directory: &str, slug: &str) -> Color {
+    load_state(state_directory, slug)
+        .active_color
+        .map_or(Color::Blue, Color::other)
+}
+
+/// generate the compose file of a color, returns the compose file and project
+pub fn render_color(
+    repo_directory: &str,
+    compose_directory: &str,
+    secrets_directory: &str,
+    application: &Application,
+    blue_green: &BlueGreen,
+    color: Color,
+    dry_run: bool,
+) -> (String, String) {
+    let project = color_project(&application.slug, color);
+    let container = color_container(&application.container, blue_green, color);
+    let compose_path = generate_compose(
+        &application.build_directory(repo_directory),
+        compose_directory,
+        secrets_directory,
+        &project,
+        &container,
+        dry_run,
+    );
+    (compose_path, project)
+}
+
+/// wait for a started color to become healthy, switch the proxy and tear down
+/// the previously active color, the new color is stopped again on failure
+pub fn promote_color(
+    runtime: &Runtime,
+    compose_directory: &str,
+    state_directory: &str,
+    application: &Application,
+    blue_green: &BlueGreen,
+    color: Color,
+    dry_run: bool,
+) -> bool {
+    let slug = &application.slug;
+    let project = color_project(slug, color);
+    let compose_path = format!("{}/{}.yaml", compose_directory, project);
+    if dry_run {
+        println!(
+            "[dry-run] would wait for http://{}:{}{} to become healthy",
+            blue_green.host,
+            color_port(blue_green, color),
+            blue_green.health_path
+        );
+    } else if !wait_for_health(blue_green, color) {
+        eprintln!(
+            "{} did not become healthy, keeping the current deployment",
+            project
+        );
+        runtime.stop(&compose_path, &project, dry_run);
+        return false;
+    }
+    if !switch_proxy(runtime, slug, blue_green, color, dry_run) {
+        eprintln!("Switching the proxy failed, keeping the current deployment");
+        runtime.stop(&compose_path, &project, dry_run);
+        return false;
+    }
+
+    let mut state = load_state(state_directory, slug);
+    match state.active_color {
+        Some(previous) => {
+            let previous_project = color_project(slug, previous);
+            let previous_path = format!("{}/{}.yaml", compose_directory, previous_project);
+            runtime.stop(&previous_path, &previous_project, dry_run);
+        }
+        None => {
+            // migrating from a regular deployment, take down the old project
+            let legacy_path = format!("{}/{}.yaml", compose_directory, slug);
+            if Path::new(&legacy_path).exists() {
+                runtime.stop(&legacy_path, slug, dry_run);
+            }
+        }
+    }
+    if dry_run {
+        return true;
+    }
+    state.active_color = Some(color);
+    save_state(state_directory, slug, &state);
+    println!("{} is now served by {}", application.name, color);
+    true
+}
+
+/// bring up the inactive color, wait for it to become healthy, switch the
+/// proxy and tear down the previously active color, the existing image is reused
+pub fn switch_color(
+    runtime: &Runtime,
+    repo_directory: &str,
+    compose_directory: &str,
+    secrets_directory: &str,
+    state_directory: &str,
+    application: &Application,
+    dry_run: bool,
+) -> bool {
+    let blue_green = match &application.blue_green {
+        Some(blue_green) => blue_green,
+        None => {
+            eprintln!("{} is not a blue/green application", application.name);
+            return false;
+        }
+    };
+    let target = target_color(state_directory, &application.slug);
+    println!("Deploying {} as {}", application.name, target);
+    let (compose_path, project) = render_color(
+        repo_directory,
+        compose_directory,
+        secrets_directory,
+        application,
+        blue_green,
+        target,
+        dry_run,
+    );
+    if !runtime.start(&compose_path, &project, dry_run) {
+        eprintln!("Starting {} failed", project);
+        runtime.stop(&compose_path, &project, dry_run);
+        return false;
+    }
+    promote_color(
+        runtime,
+        compose_directory,
+        state_directory,
+        application,
+        blue_green,
+        target,
+        dry_run,
+    )
+}
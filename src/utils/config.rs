@@ -0,0 +1,193 @@
+use std::{
+    env::var_os,
+    fs::read_to_string,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    objects::structs::{ChannelKind, GlobalConfiguration, ProjectConfiguation},
+    utils::{
+        git::valid_fetch_pattern,
+        secrets::{SECRET_PREFIX, secret_reference, valid_secret_name},
+    },
+};
+
+pub const GLOBAL_CONFIGURATION_FILE: &str = "global.toml";
+
+/// where `global.toml` is looked up when no path is given, in order: the
+/// current directory, `$XDG_CONFIG_HOME/autodeploy` (`~/.config/autodeploy`)
+/// and `/etc/autodeploy`
+pub fn global_configuration_candidates() -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::from(GLOBAL_CONFIGURATION_FILE)];
+    let config_home = var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(config_home) = config_home {
+        candidates.push(
+            config_home
+                .join("autodeploy")
+                .join(GLOBAL_CONFIGURATION_FILE),
+        );
+    }
+    candidates.push(Path::new("/etc/autodeploy").join(GLOBAL_CONFIGURATION_FILE));
+    candidates
+}
+
+/// the given global configuration or the first candidate that exists
+pub fn locate_global_configuration(path: Option<&Path>) -> Result<PathBuf, String> {
+    if let Some(path) = path {
+        return Ok(path.to_path_buf());
+    }
+    let candidates = global_configuration_candidates();
+    match candidates.iter().find(|candidate| candidate.is_file()) {
+        Some(candidate) => Ok(candidate.clone()),
+        None => Err(format!(
+            "Could not find `{}`, looked in {}",
+            GLOBAL_CONFIGURATION_FILE,
+            candidates
+                .iter()
+                .map(|candidate| format!("`{}`", candidate.display()))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// a path from a configuration file, relative paths are relative to the
+/// directory of that file
+pub fn resolve_path(base: &Path, path: &str) -> String {
+    if base.as_os_str().is_empty() || Path::new(path).is_absolute() {
+        return path.to_string();
+    }
+    base.join(path.strip_prefix("./").unwrap_or(path))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn base_directory(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
+
+pub fn load_global_configuration(path: &Path) -> Result<GlobalConfiguration, String> {
+    let contents =
+        read_to_string(path).map_err(|_| format!("Could not read file `{}`", path.display()))?;
+    let mut config: GlobalConfiguration = toml::from_str(&contents)
+        .map_err(|_| format!("Unable to load data from `{}`", path.display()))?;
+    let base = base_directory(path);
+    config.configuration_file = resolve_path(base, &config.configuration_file);
+    config.compose_directory = resolve_path(base, &config.compose_directory);
+    config.state_directory = resolve_path(base, &config.state_directory);
+    config.secrets_directory = resolve_path(base, &config.secrets_directory);
+    config.audit_log = resolve_path(base, &config.audit_log);
+    for channel in &config.notifications.channels {
+        if channel.url.is_empty() {
+            return Err(format!(
+                "The {} notification channel has no `url`",
+                channel.kind
+            ));
+        }
+        if let Some(name) = channel.url.strip_prefix(SECRET_PREFIX) {
+            if !valid_secret_name(name) {
+                return Err(format!(
+                    "Invalid secret `{}` of the {} notification channel",
+                    name, channel.kind
+                ));
+            }
+        }
+        if channel.kind == ChannelKind::Email && (channel.from.is_none() || channel.to.is_empty()) {
+            return Err("The email notification channel needs `from` and `to`".to_string());
+        }
+    }
+    Ok(config)
+}
+
+pub fn load_project_configuration(path: &str) -> Result<ProjectConfiguation, String> {
+    let contents = read_to_string(path)
+        .map_err(|_| "Could not read project configuration file".to_string())?;
+    let mut config: ProjectConfiguation = toml::from_str(&contents)
+        .map_err(|_| "Invalid Project Configuration Detected".to_string())?;
+    let base = base_directory(Path::new(path));
+    config.repository_path = resolve_path(base, &config.repository_path);
+    for application in config.application.iter_mut() {
+        if let Some(context) = &application.context {
+            let inside = Path::new(context)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+            if !inside {
+                return Err(format!(
+                    "Invalid context `{}` of {}, it has to be a path inside the repository",
+                    context, application.name
+                ));
+            }
+        }
+        for pattern in application.fetch_branches.iter().flatten() {
+            if !valid_fetch_pattern(pattern) {
+                return Err(format!(
+                    "Invalid fetch branch `{}` of {}, it has to be a branch name with at most one `*`",
+                    pattern, application.name
+                ));
+            }
+        }
+        let secrets = application
+            .container
+            .environment
+            .iter()
+            .flatten()
+            .filter_map(|entry| secret_reference(entry).map(|(_, name)| name))
+            .chain(
+                application
+                    .container
+                    .secrets
+                    .iter()
+                    .flatten()
+                    .map(String::as_str),
+            );
+        for name in secrets {
+            if !valid_secret_name(name) {
+                return Err(format!(
+                    "Invalid secret `{}` of {}, it has to be a file name in the secrets directory",
+                    name, application.name
+                ));
+            }
+        }
+        for file in [
+            &mut application.allowed_signers,
+            &mut application.gpg_keyring,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *file = resolve_path(base, file);
+        }
+        for secret in application
+            .container
+            .build
+            .secrets
+            .iter_mut()
+            .flat_map(|secrets| secrets.values_mut())
+        {
+            if let Some(file) = secret.file.as_mut() {
+                *file = resolve_path(base, file);
+            }
+        }
+        if let Some(blue_green) = application.blue_green.as_mut() {
+            blue_green.proxy_config = resolve_path(base, &blue_green.proxy_config);
+        }
+    }
+    for (index, application) in config.application.iter().enumerate() {
+        let shared = config.application[..index].iter().find(|other| {
+            other.repository_directory() == application.repository_directory()
+                && other.repository_url != application.repository_url
+        });
+        if let Some(other) = shared {
+            return Err(format!(
+                "{} and {} share the clone `{}` but not the repository url",
+                other.name,
+                application.name,
+                application.repository_directory()
+            ));
+        }
+    }
+    Ok(config)
+}
@@ -1,94 +1,644 @@
 use std::{
-    collections::HashMap,
-    fs::{create_dir_all, File},
-    io,
-    io::{BufRead, Write},
+    collections::{BTreeMap, HashMap},
+    env::{split_paths, var_os},
+    ffi::OsStr,
+    fs::{create_dir_all, read_to_string},
+    os::unix::fs::PermissionsExt,
     path::Path,
-    process::{Command, Stdio},
+    rc::Rc,
 };
 
-use crate::{check_file, ComposeConfiguation, Container};
+use similar::TextDiff;
 
+use crate::{
+    objects::structs::{BuildSecret, ComposeConfiguation, Container, Engine},
+    utils::{
+        engine::{
+            EngineClient, ImageInspect, api_build, api_remove, api_start, api_stop, registry_auth,
+        },
+        file::{check_file, relative_path, write_private},
+        registry::RegistryCredentials,
+        runner::{CommandRunner, JournalRunner},
+        secrets::{secret_environment, secret_reference},
+    },
+};
+
+/// write the compose file of a container built from `build_directory`, the
+/// build context and the secrets in `secrets_directory` are relative to the
+/// compose file; the values of the secrets are never written to it
 pub fn generate_compose(
-    repo_directory: &str,
+    build_directory: &str,
     compose_directory: &str,
-    slug: &str,
+    secrets_directory: &str,
+    compose_name: &str,
     container_config: &Container,
+    dry_run: bool,
 ) -> String {
     let mut services: HashMap<String, Container> = HashMap::new();
-    let build_context: String = format!(".{}/{}", repo_directory, slug).to_owned();
+    let build_context = relative_path(Path::new(compose_directory), Path::new(build_directory));
     let mut container: Container = container_config.clone();
-    container.build.context = build_context;
+    container.build.context = build_context.to_string_lossy().to_string();
+    let relative = |file: &Path| {
+        relative_path(Path::new(compose_directory), file)
+            .to_string_lossy()
+            .to_string()
+    };
+    // only the variable is named, compose takes its value from the
+    // environment `up` runs in
+    let mut secret_environment = BTreeMap::new();
+    if let Some(environment) = container.environment.as_mut() {
+        for entry in environment.iter_mut() {
+            if let Some((key, name)) = secret_reference(entry) {
+                let file = relative(&Path::new(secrets_directory).join(name));
+                secret_environment.insert(key.to_string(), file);
+                *entry = key.to_string();
+            }
+        }
+    }
     services.insert("app".to_string(), container);
-    let compose = ComposeConfiguation { services };
+    let mut secrets = container_config.build.secrets.clone();
+    for secret in secrets.iter_mut().flat_map(|secrets| secrets.values_mut()) {
+        if let Some(file) = secret.file.as_mut() {
+            *file = relative(Path::new(file));
+        }
+    }
+    for name in container_config.secrets.iter().flatten() {
+        secrets.get_or_insert_with(BTreeMap::new).insert(
+            name.clone(),
+            BuildSecret {
+                file: Some(relative(&Path::new(secrets_directory).join(name))),
+                environment: None,
+            },
+        );
+    }
+    let compose = ComposeConfiguation {
+        services,
+        secrets,
+        secret_environment,
+    };
     let yaml = serde_yaml::to_string(&compose).unwrap();
+    let base_path = format!("{}/{}.yaml", compose_directory, compose_name).to_string();
+    if dry_run {
+        print_compose_diff(&base_path, &yaml);
+        return base_path;
+    }
     if !Path::new(&compose_directory).exists() {
         // Create the folder if it doesn't exist
-        create_dir_all(format!("./{}", compose_directory)).unwrap();
+        create_dir_all(compose_directory).unwrap();
         println!("Directory created:{}", compose_directory);
     } else {
         println!("Directory already exists: {}", compose_directory);
     }
-    let base_path = format!("{}/{}.yaml", compose_directory, slug).to_string();
-    let mut file = File::create(&base_path).unwrap();
-    file.write_all(yaml.as_bytes()).unwrap();
+    write_private(&base_path, &yaml).unwrap();
     println!("Generating Compose Complete");
     base_path
 }
-pub fn execute_command(command: &str, args: Vec<&str>) {
-    let mut child = Command::new(command)
-        .args(&args)
-        .stdin(Stdio::null()) // No input needed
-        .stdout(Stdio::piped()) // Capture output
-        .stderr(Stdio::piped()) // Capture error output
-        .spawn()
-        .unwrap();
-    if let Some(stdout) = child.stdout.as_mut() {
-        let output = io::BufReader::new(stdout).lines();
-        for line in output {
-            println!("{}", line.unwrap());
-        }
+/// show how the generated compose file differs from the one on disk
+fn print_compose_diff(compose_path: &str, yaml: &str) {
+    let existing = read_to_string(compose_path).unwrap_or_default();
+    if existing == yaml {
+        println!("[dry-run] {} is unchanged", compose_path);
+        return;
+    }
+    println!("[dry-run] changes to {}:", compose_path);
+    let diff = TextDiff::from_lines(existing.as_str(), yaml);
+    print!(
+        "{}",
+        diff.unified_diff()
+            .header(compose_path, &format!("{} (generated)", compose_path))
+    );
+}
+/// run a command through the runner, dry-run only prints it
+pub fn execute_command(
+    runner: &dyn CommandRunner,
+    command: &str,
+    args: Vec<&str>,
+    dry_run: bool,
+) -> bool {
+    if dry_run {
+        println!("[dry-run] {} {}", command, args.join(" "));
+        return true;
     }
-    // Read and print the standard error output
-    if let Some(stderr) = child.stderr.as_mut() {
-        let error_output = io::BufReader::new(stderr).lines();
-        for line in error_output {
-            eprintln!("DEBUG: {}", line.unwrap());
+    runner.run(command, &args).success
+}
+
+/// the binary and leading arguments running `compose` for an engine\
+/// `podman-compose` is a standalone binary, the other engines ship `compose` as
+/// a subcommand; all of them take `-f` and `-p` before the compose command
+pub fn compose_command(engine: Engine) -> (&'static str, Vec<&'static str>) {
+    match engine {
+        Engine::Docker | Engine::Auto => ("docker", vec!["compose"]),
+        Engine::Podman => ("podman", vec!["compose"]),
+        Engine::PodmanCompose => ("podman-compose", vec![]),
+        Engine::Nerdctl => ("nerdctl", vec!["compose"]),
+    }
+}
+
+/// a size such as `512MB` or `10GB` in bytes, the units are powers of 1024
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let factor: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("unknown unit in `{}`", value)),
+    };
+    number
+        .parse::<f64>()
+        .map(|number| (number * factor as f64) as u64)
+        .map_err(|_| format!("invalid size `{}`", value))
+}
+
+/// the binary managing the images of an engine
+fn engine_binary(engine: Engine) -> &'static str {
+    match engine {
+        Engine::Docker | Engine::Auto => "docker",
+        Engine::Podman | Engine::PodmanCompose => "podman",
+        Engine::Nerdctl => "nerdctl",
+    }
+}
+
+/// the first engine whose binary is found in the directories of `path`
+pub fn detect_engine(path: &OsStr) -> Option<Engine> {
+    [
+        Engine::Docker,
+        Engine::Podman,
+        Engine::Nerdctl,
+        Engine::PodmanCompose,
+    ]
+    .into_iter()
+    .find(|&engine| {
+        let (binary, _) = compose_command(engine);
+        split_paths(path).any(|directory| {
+            directory.join(binary).metadata().is_ok_and(|metadata| {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            })
+        })
+    })
+}
+
+/// the configured engine, `auto` is resolved against `PATH`
+pub fn resolve_engine(engine: Engine) -> Engine {
+    if engine != Engine::Auto {
+        return engine;
+    }
+    match var_os("PATH").as_deref().and_then(detect_engine) {
+        Some(engine) => engine,
+        None => {
+            eprintln!("No container engine found on PATH, falling back to docker");
+            Engine::Docker
         }
     }
 }
 
-pub fn build_compose(compose_file_path: &str) {
-    let command = "docker";
-    let args = ["compose", "-f", compose_file_path, "build"];
-    if check_file(&compose_file_path) {
-        execute_command(command, args.to_vec());
+fn run_compose(
+    runner: &dyn CommandRunner,
+    engine: Engine,
+    compose_file_path: &str,
+    arguments: &[&str],
+    dry_run: bool,
+) -> bool {
+    let (command, mut args) = compose_command(engine);
+    args.extend(["-f", compose_file_path]);
+    args.extend(arguments);
+    (dry_run || check_file(compose_file_path)) && execute_command(runner, command, args, dry_run)
+}
+
+/// one-off toggles of a build on top of the build section of the compose file
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BuildFlags {
+    pub no_cache: bool,
+    pub pull: bool,
+}
+
+pub fn build_compose(
+    runner: &dyn CommandRunner,
+    engine: Engine,
+    compose_file_path: &str,
+    flags: BuildFlags,
+    dry_run: bool,
+) -> bool {
+    let mut arguments = vec!["build"];
+    if flags.no_cache {
+        arguments.push("--no-cache");
     }
+    if flags.pull {
+        arguments.push("--pull");
+    }
+    run_compose(runner, engine, compose_file_path, &arguments, dry_run)
 }
 
-pub fn start_compose(compose_file_path: &str, project: &str) {
-    let command = "docker";
-    let args = [
-        "compose",
-        "-f",
-        compose_file_path,
-        "-p",
-        project,
-        "up",
-        "-d",
-    ];
-    if check_file(&compose_file_path) {
-        execute_command(command, args.to_vec());
+/// `up` with the secrets of the compose file in its environment
+pub fn start_compose(
+    runner: &dyn CommandRunner,
+    engine: Engine,
+    compose_file_path: &str,
+    project: &str,
+    dry_run: bool,
+) -> bool {
+    let arguments = ["-p", project, "up", "-d"];
+    if dry_run || !check_file(compose_file_path) {
+        return run_compose(runner, engine, compose_file_path, &arguments, dry_run);
     }
+    let environment = match secret_environment(compose_file_path) {
+        Ok(environment) => environment,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let (command, mut args) = compose_command(engine);
+    args.extend(["-f", compose_file_path]);
+    args.extend(arguments);
+    runner.run_with_env(command, &args, &environment).success
+}
+pub fn stop_compose(
+    runner: &dyn CommandRunner,
+    engine: Engine,
+    compose_file_path: &str,
+    project: &str,
+    dry_run: bool,
+) -> bool {
+    run_compose(
+        runner,
+        engine,
+        compose_file_path,
+        &["-p", project, "down"],
+        dry_run,
+    )
 }
-pub fn stop_compose(compose_file_path: &str, project: &str) {
-    let command = "docker";
-    let args = ["compose", "-f", compose_file_path, "-p", project, "down"];
-    if check_file(&compose_file_path) {
-        execute_command(command, args.to_vec());
+/// `down` with the named volumes and the images of the services when asked to
+pub fn remove_compose(
+    runner: &dyn CommandRunner,
+    engine: Engine,
+    compose_file_path: &str,
+    project: &str,
+    volumes: bool,
+    images: bool,
+    dry_run: bool,
+) -> bool {
+    let mut arguments = vec!["-p", project, "down"];
+    if volumes {
+        arguments.push("--volumes");
     }
+    if images {
+        arguments.extend(["--rmi", "all"]);
+    }
+    run_compose(runner, engine, compose_file_path, &arguments, dry_run)
+}
+pub fn restart_compose(
+    runner: &dyn CommandRunner,
+    engine: Engine,
+    compose_file_path: &str,
+    project: &str,
+    dry_run: bool,
+) -> bool {
+    stop_compose(runner, engine, compose_file_path, project, dry_run)
+        && start_compose(runner, engine, compose_file_path, project, dry_run)
 }
-pub fn restart_compose(compose_file_path: &str, project: &str) {
-    stop_compose(&compose_file_path, &project);
-    start_compose(&compose_file_path, &project);
+
+/// runs the containers of the generated compose files, through the compose
+/// CLI of the engine by default or the Engine API when configured
+pub struct Runtime {
+    runner: JournalRunner,
+    engine: Engine,
+    api: Option<EngineClient>,
+}
+
+impl Runtime {
+    pub fn compose(runner: Rc<dyn CommandRunner>) -> Runtime {
+        Runtime {
+            runner: JournalRunner::new(runner),
+            engine: Engine::Docker,
+            api: None,
+        }
+    }
+
+    /// the engine whose compose CLI is used, `auto` has to be resolved first
+    pub fn engine(mut self, engine: Engine) -> Runtime {
+        self.engine = engine;
+        self
+    }
+
+    /// talk to the Engine API, other commands such as the proxy reload still
+    /// go through the runner
+    pub fn api(runner: Rc<dyn CommandRunner>, api: EngineClient) -> Runtime {
+        Runtime {
+            runner: JournalRunner::new(runner),
+            engine: Engine::Docker,
+            api: Some(api),
+        }
+    }
+
+    pub fn runner(&self) -> &dyn CommandRunner {
+        &self.runner
+    }
+
+    /// the output of the latest failed command since `take_commands`
+    pub fn failure_log(&self) -> Option<String> {
+        self.runner.failure()
+    }
+
+    /// the commands run and the Engine API requests changing something since
+    /// the last call
+    pub fn take_commands(&self) -> Vec<String> {
+        let mut commands = self.runner.take();
+        if let Some(api) = &self.api {
+            commands.extend(api.take_requests());
+        }
+        commands
+    }
+
+    pub fn build(&self, compose_file_path: &str, flags: BuildFlags, dry_run: bool) -> bool {
+        match &self.api {
+            Some(api) => {
+                (dry_run || check_file(compose_file_path))
+                    && api_build(api, compose_file_path, flags, dry_run)
+            }
+            None => build_compose(
+                self.runner(),
+                self.engine,
+                compose_file_path,
+                flags,
+                dry_run,
+            ),
+        }
+    }
+
+    pub fn start(&self, compose_file_path: &str, project: &str, dry_run: bool) -> bool {
+        match &self.api {
+            Some(api) => {
+                (dry_run || check_file(compose_file_path))
+                    && api_start(api, compose_file_path, project, dry_run)
+            }
+            None => start_compose(
+                self.runner(),
+                self.engine,
+                compose_file_path,
+                project,
+                dry_run,
+            ),
+        }
+    }
+
+    pub fn stop(&self, compose_file_path: &str, project: &str, dry_run: bool) -> bool {
+        match &self.api {
+            Some(api) => {
+                (dry_run || check_file(compose_file_path))
+                    && api_stop(api, compose_file_path, project, dry_run)
+            }
+            None => stop_compose(
+                self.runner(),
+                self.engine,
+                compose_file_path,
+                project,
+                dry_run,
+            ),
+        }
+    }
+
+    /// stop the project and remove its named volumes and images when asked to
+    pub fn remove(
+        &self,
+        compose_file_path: &str,
+        project: &str,
+        volumes: bool,
+        images: bool,
+        dry_run: bool,
+    ) -> bool {
+        match &self.api {
+            Some(api) => {
+                (dry_run || check_file(compose_file_path))
+                    && api_remove(api, compose_file_path, project, volumes, images, dry_run)
+            }
+            None => remove_compose(
+                self.runner(),
+                self.engine,
+                compose_file_path,
+                project,
+                volumes,
+                images,
+                dry_run,
+            ),
+        }
+    }
+
+    /// the id and size of an image, `None` if it is missing
+    pub fn inspect_image(&self, image: &str) -> Option<ImageInspect> {
+        match &self.api {
+            Some(api) => api.inspect_image(image).ok().flatten(),
+            None => {
+                let output = self.runner.run(
+                    engine_binary(self.engine),
+                    &["image", "inspect", "--format", "{{.Id}} {{.Size}}", image],
+                );
+                let (id, size) = output.stdout.trim().split_once(' ')?;
+                Some(ImageInspect {
+                    id: id.to_string(),
+                    size: size.parse().ok()?,
+                })
+            }
+        }
+    }
+
+    /// remove an image no container uses anymore
+    pub fn remove_image(&self, image: &str, dry_run: bool) -> bool {
+        match &self.api {
+            Some(_) if dry_run => {
+                println!(
+                    "[dry-run] would remove the image {} through the Engine API",
+                    image
+                );
+                true
+            }
+            Some(api) => match api.remove_image(image, false) {
+                Ok(_) => true,
+                Err(e) => {
+                    eprintln!("Removing the image {} failed: {}", image, e);
+                    false
+                }
+            },
+            None => execute_command(
+                self.runner(),
+                engine_binary(self.engine),
+                vec!["image", "rm", image],
+                dry_run,
+            ),
+        }
+    }
+
+    /// log in to the registry of `repository` with the credentials, the
+    /// Engine API sends them along with every request instead
+    fn registry_login(
+        &self,
+        repository: &str,
+        credentials: Option<&RegistryCredentials>,
+        dry_run: bool,
+    ) -> bool {
+        let credentials = match credentials {
+            Some(credentials) if self.api.is_none() => credentials,
+            _ => return true,
+        };
+        let server = repository.split('/').next().unwrap_or(repository);
+        let binary = engine_binary(self.engine);
+        let args = [
+            "login",
+            server,
+            "--username",
+            &credentials.username,
+            "--password-stdin",
+        ];
+        if dry_run {
+            println!("[dry-run] {} {}", binary, args.join(" "));
+            return true;
+        }
+        self.runner
+            .run_with_input(binary, &args, &credentials.password)
+            .success
+    }
+
+    /// tag the image as `repository:tag` for every tag and push them
+    pub fn push_image(
+        &self,
+        image: &str,
+        repository: &str,
+        tags: &[String],
+        credentials: Option<&RegistryCredentials>,
+        dry_run: bool,
+    ) -> bool {
+        if !self.registry_login(repository, credentials, dry_run) {
+            return false;
+        }
+        let server = repository.split('/').next().unwrap_or(repository);
+        for tag in tags {
+            let reference = format!("{}:{}", repository, tag);
+            let pushed = match &self.api {
+                Some(_) if dry_run => {
+                    println!(
+                        "[dry-run] would push {} as {} through the Engine API",
+                        image, reference
+                    );
+                    true
+                }
+                Some(api) => match api.tag_image(image, repository, tag).and_then(|_| {
+                    api.push_image(repository, tag, &registry_auth(server, credentials))
+                }) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        eprintln!("Pushing {} failed: {}", reference, e);
+                        false
+                    }
+                },
+                None => {
+                    let binary = engine_binary(self.engine);
+                    execute_command(
+                        self.runner(),
+                        binary,
+                        vec!["tag", image, &reference],
+                        dry_run,
+                    ) && execute_command(self.runner(), binary, vec!["push", &reference], dry_run)
+                }
+            };
+            if !pushed {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// pull `repository:tag`
+    pub fn pull_image(
+        &self,
+        repository: &str,
+        tag: &str,
+        credentials: Option<&RegistryCredentials>,
+        dry_run: bool,
+    ) -> bool {
+        if !self.registry_login(repository, credentials, dry_run) {
+            return false;
+        }
+        let server = repository.split('/').next().unwrap_or(repository);
+        let reference = format!("{}:{}", repository, tag);
+        match &self.api {
+            Some(_) if dry_run => {
+                println!("[dry-run] would pull {} through the Engine API", reference);
+                true
+            }
+            Some(api) => match api.pull_image(repository, tag, &registry_auth(server, credentials))
+            {
+                Ok(_) => true,
+                Err(e) => {
+                    eprintln!("Pulling {} failed: {}", reference, e);
+                    false
+                }
+            },
+            None => execute_command(
+                self.runner(),
+                engine_binary(self.engine),
+                vec!["pull", &reference],
+                dry_run,
+            ),
+        }
+    }
+
+    /// prune the build cache down to `keep_storage` bytes, podman and nerdctl
+    /// cannot keep part of it and prune all of it
+    pub fn prune_build_cache(&self, keep_storage: u64, dry_run: bool) -> bool {
+        match &self.api {
+            Some(_) if dry_run => {
+                println!(
+                    "[dry-run] would prune the build cache down to {} bytes through the Engine API",
+                    keep_storage
+                );
+                true
+            }
+            Some(api) => match api.prune_build_cache(keep_storage) {
+                Ok(reclaimed) => {
+                    println!("Build cache pruned, {} bytes reclaimed", reclaimed);
+                    true
+                }
+                Err(e) => {
+                    eprintln!("Pruning the build cache failed: {}", e);
+                    false
+                }
+            },
+            None if keep_storage > 0 && !matches!(self.engine, Engine::Docker | Engine::Auto) => {
+                eprintln!(
+                    "{} cannot keep part of the build cache, prune it with a size of 0 to remove all of it",
+                    engine_binary(self.engine)
+                );
+                false
+            }
+            None => {
+                let keep_storage = keep_storage.to_string();
+                let args = match self.engine {
+                    Engine::Docker | Engine::Auto => {
+                        vec![
+                            "builder",
+                            "prune",
+                            "--force",
+                            "--keep-storage",
+                            &keep_storage,
+                        ]
+                    }
+                    Engine::Podman | Engine::PodmanCompose => {
+                        vec!["image", "prune", "--build-cache", "--force"]
+                    }
+                    Engine::Nerdctl => vec!["builder", "prune", "--force"],
+                };
+                execute_command(self.runner(), engine_binary(self.engine), args, dry_run)
+            }
+        }
+    }
+
+    pub fn restart(&self, compose_file_path: &str, project: &str, dry_run: bool) -> bool {
+        self.stop(compose_file_path, project, dry_run)
+            && self.start(compose_file_path, project, dry_run)
+    }
 }
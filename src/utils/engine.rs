@@ -0,0 +1,1057 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{read_dir, read_to_string},
+    io::{self, BufRead, BufReader, Read, Write},
+    iter::once,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf, absolute},
+};
+
+use base64::{Engine as _, engine::general_purpose::URL_SAFE};
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+
+use crate::utils::{
+    docker::BuildFlags, registry::RegistryCredentials, secrets::secret_environment,
+};
+
+/// oldest Engine API version providing everything used below
+const API_VERSION: &str = "v1.41";
+
+/// lines of the container log shown when a container does not come up
+const LOG_TAIL: usize = 50;
+
+/// how an image is built through `POST /build`
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BuildOptions {
+    pub dockerfile: String,
+    pub build_args: Vec<String>,
+    pub target: Option<String>,
+    pub platform: Option<String>,
+    pub cache_from: Vec<String>,
+    pub no_cache: bool,
+    pub pull: bool,
+}
+
+/// client for the Docker Engine API on a unix socket
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineClient {
+    socket: String,
+    /// the requests sent, other than the ones only reading
+    requests: RefCell<Vec<String>>,
+}
+
+/// status and body of an Engine API response
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// the parts of `GET /containers/{name}/json` autodeploy looks at
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerInspect {
+    pub id: String,
+    pub name: String,
+    pub state: ContainerState,
+}
+
+/// the parts of `GET /images/{name}/json` autodeploy looks at
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageInspect {
+    pub id: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerState {
+    pub status: String,
+    pub running: bool,
+    #[serde(default)]
+    pub exit_code: i64,
+}
+
+/// a service of a generated compose file, as serialized from `Container`
+#[derive(Debug, Clone, Deserialize)]
+struct ComposeService {
+    container_name: String,
+    image: String,
+    build: Option<ComposeBuild>,
+    restart: Option<String>,
+    user: Option<String>,
+    stdin_open: Option<bool>,
+    tty: Option<bool>,
+    #[serde(default)]
+    ports: Vec<String>,
+    volumes: Option<Vec<String>>,
+    environment: Option<Vec<String>>,
+    #[serde(default)]
+    secrets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ComposeBuild {
+    context: String,
+    dockerfile: String,
+    args: Option<Vec<String>>,
+    target: Option<String>,
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    no_cache: bool,
+    #[serde(default)]
+    pull: bool,
+    #[serde(default)]
+    cache_from: Vec<String>,
+    #[serde(default)]
+    cache_to: Vec<String>,
+    #[serde(default)]
+    secrets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ComposeSecret {
+    file: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ComposeFile {
+    services: HashMap<String, ComposeService>,
+    #[serde(default)]
+    secrets: HashMap<String, ComposeSecret>,
+}
+
+impl EngineClient {
+    /// `socket` is a path or a `unix://` address such as `unix:///var/run/docker.sock`
+    pub fn new(socket: &str) -> EngineClient {
+        EngineClient {
+            socket: socket.strip_prefix("unix://").unwrap_or(socket).to_string(),
+            requests: RefCell::new(vec![]),
+        }
+    }
+
+    /// the requests sent since the last call, other than the ones only reading
+    pub fn take_requests(&self) -> Vec<String> {
+        self.requests.take()
+    }
+
+    /// send a request, the body of the response is passed to `on_chunk` as it
+    /// arrives, returns the status code
+    fn stream(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        on_chunk: &mut dyn FnMut(&[u8]),
+    ) -> Result<u16, String> {
+        if method != "GET" {
+            self.requests
+                .borrow_mut()
+                .push(format!("{} {}", method, path));
+        }
+        let mut stream = UnixStream::connect(&self.socket)
+            .map_err(|e| format!("could not connect to {}: {}", self.socket, e))?;
+        let mut head = format!(
+            "{} /{}{} HTTP/1.1\r\nHost: docker\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            API_VERSION,
+            path,
+            body.len()
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream
+            .write_all(head.as_bytes())
+            .and_then(|_| stream.write_all(body))
+            .map_err(|e| e.to_string())?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader
+            .read_line(&mut status_line)
+            .map_err(|e| e.to_string())?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| format!("invalid response `{}`", status_line.trim()))?;
+        let mut chunked = false;
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                break;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "transfer-encoding" => chunked = value.trim().eq_ignore_ascii_case("chunked"),
+                    "content-length" => length = value.trim().parse::<usize>().ok(),
+                    _ => {}
+                }
+            }
+        }
+
+        if chunked {
+            loop {
+                let mut size = String::new();
+                reader.read_line(&mut size).map_err(|e| e.to_string())?;
+                let size = size.trim().split(';').next().unwrap_or_default();
+                let size = usize::from_str_radix(size, 16)
+                    .map_err(|_| format!("invalid chunk size `{}`", size))?;
+                if size == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; size + 2];
+                reader.read_exact(&mut chunk).map_err(|e| e.to_string())?;
+                on_chunk(&chunk[..size]);
+            }
+        } else {
+            let mut body = vec![];
+            match length {
+                Some(length) => {
+                    body.resize(length, 0);
+                    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+                }
+                None => {
+                    reader.read_to_end(&mut body).map_err(|e| e.to_string())?;
+                }
+            }
+            if !body.is_empty() {
+                on_chunk(&body);
+            }
+        }
+        Ok(status)
+    }
+
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Result<EngineResponse, String> {
+        let mut response = vec![];
+        let headers: Vec<(&str, &str)> = content_type
+            .map(|content_type| ("Content-Type", content_type))
+            .into_iter()
+            .collect();
+        let status = self.stream(method, path, &headers, body, &mut |chunk| {
+            response.extend_from_slice(chunk)
+        })?;
+        Ok(EngineResponse {
+            status,
+            body: response,
+        })
+    }
+
+    /// a request whose response has to have one of the `expected` statuses
+    fn expect(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+        expected: &[u16],
+    ) -> Result<EngineResponse, String> {
+        let response = match body {
+            Some(body) => self.request(
+                method,
+                path,
+                Some("application/json"),
+                body.to_string().as_bytes(),
+            )?,
+            None => self.request(method, path, None, &[])?,
+        };
+        if expected.contains(&response.status) {
+            Ok(response)
+        } else {
+            Err(error_message(&response))
+        }
+    }
+
+    /// build the image `tag` from a directory, the build output is printed as
+    /// it arrives
+    pub fn build(&self, context: &Path, tag: &str, options: &BuildOptions) -> Result<(), String> {
+        let archive = context_archive(context, &options.dockerfile)?;
+        let build_args: HashMap<&str, &str> = options
+            .build_args
+            .iter()
+            .map(|arg| arg.split_once('=').unwrap_or((arg.as_str(), "")))
+            .collect();
+        let mut path = format!(
+            "/build?t={}&dockerfile={}&buildargs={}&rm=1",
+            encode(tag),
+            encode(&options.dockerfile),
+            encode(&serde_json::to_string(&build_args).unwrap())
+        );
+        if let Some(target) = &options.target {
+            path.push_str(&format!("&target={}", encode(target)));
+        }
+        if let Some(platform) = &options.platform {
+            path.push_str(&format!("&platform={}", encode(platform)));
+        }
+        if !options.cache_from.is_empty() {
+            path.push_str(&format!(
+                "&cachefrom={}",
+                encode(&serde_json::to_string(&options.cache_from).unwrap())
+            ));
+        }
+        if options.no_cache {
+            path.push_str("&nocache=1");
+        }
+        if options.pull {
+            path.push_str("&pull=1");
+        }
+        self.stream_messages(
+            "POST",
+            &path,
+            &[("Content-Type", "application/x-tar")],
+            &archive,
+        )
+    }
+
+    /// a request answered with a stream of JSON messages, as the build, push
+    /// and pull endpoints do, the messages are printed as they arrive and the
+    /// first error is returned
+    fn stream_messages(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<(), String> {
+        let mut pending = vec![];
+        let mut error = None;
+        let status = self.stream(method, path, headers, body, &mut |chunk| {
+            pending.extend_from_slice(chunk);
+            // one JSON message per line
+            while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                report_message(&line, &mut error);
+            }
+        })?;
+        if !pending.is_empty() {
+            report_message(&pending, &mut error);
+        }
+        match error {
+            Some(error) => Err(error),
+            None if status == 200 => Ok(()),
+            None => Err(format!("the request failed with status {}", status)),
+        }
+    }
+
+    /// create a container from a `POST /containers/create` body, returns its id
+    pub fn create_container(&self, name: &str, config: &Value) -> Result<String, String> {
+        let response = self.expect(
+            "POST",
+            &format!("/containers/create?name={}", encode(name)),
+            Some(config),
+            &[201],
+        )?;
+        let created: Value = serde_json::from_slice(&response.body).map_err(|e| e.to_string())?;
+        Ok(created["Id"].as_str().unwrap_or_default().to_string())
+    }
+
+    pub fn start_container(&self, name: &str) -> Result<(), String> {
+        self.expect(
+            "POST",
+            &format!("/containers/{}/start", encode(name)),
+            None,
+            &[204, 304],
+        )
+        .map(|_| ())
+    }
+
+    /// stop a container, a missing container counts as stopped
+    pub fn stop_container(&self, name: &str) -> Result<(), String> {
+        self.expect(
+            "POST",
+            &format!("/containers/{}/stop", encode(name)),
+            None,
+            &[204, 304, 404],
+        )
+        .map(|_| ())
+    }
+
+    /// force remove a container, a missing container counts as removed
+    pub fn remove_container(&self, name: &str) -> Result<(), String> {
+        self.expect(
+            "DELETE",
+            &format!("/containers/{}?force=true", encode(name)),
+            None,
+            &[204, 404],
+        )
+        .map(|_| ())
+    }
+
+    /// the state of a container, `None` if it does not exist
+    pub fn inspect_container(&self, name: &str) -> Result<Option<ContainerInspect>, String> {
+        let response = self.expect(
+            "GET",
+            &format!("/containers/{}/json", encode(name)),
+            None,
+            &[200, 404],
+        )?;
+        if response.status == 404 {
+            return Ok(None);
+        }
+        serde_json::from_slice(&response.body)
+            .map(Some)
+            .map_err(|e| e.to_string())
+    }
+
+    /// the id and size of an image, `None` if it does not exist
+    pub fn inspect_image(&self, name: &str) -> Result<Option<ImageInspect>, String> {
+        let response = self.expect(
+            "GET",
+            &format!("/images/{}/json", encode(name)),
+            None,
+            &[200, 404],
+        )?;
+        if response.status == 404 {
+            return Ok(None);
+        }
+        serde_json::from_slice(&response.body)
+            .map(Some)
+            .map_err(|e| e.to_string())
+    }
+
+    /// remove an image, `force` also removes it while containers use it, a
+    /// missing image counts as removed
+    pub fn remove_image(&self, name: &str, force: bool) -> Result<(), String> {
+        self.expect(
+            "DELETE",
+            &format!("/images/{}?force={}", encode(name), force),
+            None,
+            &[200, 404],
+        )
+        .map(|_| ())
+    }
+
+    /// prune the build cache down to `keep_storage` bytes, returns the bytes
+    /// reclaimed
+    pub fn prune_build_cache(&self, keep_storage: u64) -> Result<u64, String> {
+        let response = self.expect(
+            "POST",
+            &format!("/build/prune?keep-storage={}", keep_storage),
+            None,
+            &[200],
+        )?;
+        let pruned: Value = serde_json::from_slice(&response.body).map_err(|e| e.to_string())?;
+        Ok(pruned["SpaceReclaimed"].as_u64().unwrap_or_default())
+    }
+
+    /// tag an image as `repository:tag`
+    pub fn tag_image(&self, image: &str, repository: &str, tag: &str) -> Result<(), String> {
+        self.expect(
+            "POST",
+            &format!(
+                "/images/{}/tag?repo={}&tag={}",
+                encode(image),
+                encode(repository),
+                encode(tag)
+            ),
+            None,
+            &[200, 201],
+        )
+        .map(|_| ())
+    }
+
+    /// push `repository:tag`, `auth` is the `X-Registry-Auth` header
+    pub fn push_image(&self, repository: &str, tag: &str, auth: &str) -> Result<(), String> {
+        self.stream_messages(
+            "POST",
+            &format!("/images/{}/push?tag={}", encode(repository), encode(tag)),
+            &[("X-Registry-Auth", auth)],
+            &[],
+        )
+    }
+
+    /// pull `repository:tag`, `auth` is the `X-Registry-Auth` header
+    pub fn pull_image(&self, repository: &str, tag: &str, auth: &str) -> Result<(), String> {
+        self.stream_messages(
+            "POST",
+            &format!(
+                "/images/create?fromImage={}&tag={}",
+                encode(repository),
+                encode(tag)
+            ),
+            &[("X-Registry-Auth", auth)],
+            &[],
+        )
+    }
+
+    /// remove a named volume, a missing volume counts as removed
+    pub fn remove_volume(&self, name: &str) -> Result<(), String> {
+        self.expect(
+            "DELETE",
+            &format!("/volumes/{}", encode(name)),
+            None,
+            &[204, 404],
+        )
+        .map(|_| ())
+    }
+
+    /// the last `tail` lines of the stdout and stderr of a container
+    pub fn container_logs(&self, name: &str, tail: usize) -> Result<String, String> {
+        let response = self.expect(
+            "GET",
+            &format!(
+                "/containers/{}/logs?stdout=1&stderr=1&tail={}",
+                encode(name),
+                tail
+            ),
+            None,
+            &[200],
+        )?;
+        Ok(demultiplex(&response.body))
+    }
+}
+
+/// the `X-Registry-Auth` header for a registry, an empty login makes the
+/// engine fall back to anonymous access
+pub fn registry_auth(server: &str, credentials: Option<&RegistryCredentials>) -> String {
+    let auth = match credentials {
+        Some(credentials) => json!({
+            "username": credentials.username,
+            "password": credentials.password,
+            "serveraddress": server,
+        }),
+        None => json!({}),
+    };
+    URL_SAFE.encode(auth.to_string())
+}
+
+/// percent-encode a query parameter or path segment
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// the `message` of an Engine API error
+fn error_message(response: &EngineResponse) -> String {
+    serde_json::from_slice::<Value>(&response.body)
+        .ok()
+        .and_then(|body| body["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("the request failed with status {}", response.status))
+}
+
+/// print a message of the build, push or pull output, progress updates are
+/// skipped and errors are kept to be returned
+fn report_message(line: &[u8], error: &mut Option<String>) {
+    let message: Value = match serde_json::from_slice(line) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+    let progress = message["progressDetail"]
+        .as_object()
+        .is_some_and(|detail| !detail.is_empty());
+    if let Some(stream) = message["stream"].as_str() {
+        print!("{}", stream);
+    } else if let Some(status) = message["status"].as_str().filter(|_| !progress) {
+        match message["id"].as_str() {
+            Some(id) => println!("{}: {}", id, status),
+            None => println!("{}", status),
+        }
+    }
+    if let Some(message) = message["error"].as_str().or(message["message"].as_str()) {
+        *error = Some(message.trim().to_string());
+    }
+}
+
+/// the logs of a container without a TTY are framed with an 8 byte header
+/// carrying the stream and the length of each frame
+fn demultiplex(body: &[u8]) -> String {
+    let framed = body.len() >= 8 && body[0] <= 2 && body[1..4] == [0, 0, 0];
+    if !framed {
+        return String::from_utf8_lossy(body).to_string();
+    }
+    let mut output = String::new();
+    let mut rest = body;
+    while rest.len() >= 8 {
+        let length = u32::from_be_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let end = (8 + length).min(rest.len());
+        output.push_str(&String::from_utf8_lossy(&rest[8..end]));
+        rest = &rest[end..];
+    }
+    output
+}
+
+/// a line of `.dockerignore`, a later rule takes precedence over earlier ones
+struct IgnoreRule {
+    pattern: Pattern,
+    /// `!pattern`, brings back paths an earlier rule ignored
+    exception: bool,
+}
+
+/// the rules of the `.dockerignore` of the context, `.git` is always ignored
+fn ignore_rules(context: &Path) -> Vec<IgnoreRule> {
+    let contents = read_to_string(context.join(".dockerignore")).unwrap_or_default();
+    once(".git")
+        .chain(contents.lines())
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (exception, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern.trim()),
+                None => (false, line),
+            };
+            let pattern = pattern
+                .trim_start_matches("./")
+                .trim_start_matches('/')
+                .trim_end_matches('/');
+            Pattern::new(pattern)
+                .ok()
+                .map(|pattern| IgnoreRule { pattern, exception })
+        })
+        .collect()
+}
+
+/// whether a path relative to the context is ignored, a rule matching a
+/// directory applies to everything below it
+fn ignored(rules: &[IgnoreRule], path: &str) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    let prefixes: Vec<&str> = path
+        .match_indices('/')
+        .map(|(index, _)| &path[..index])
+        .chain(once(path))
+        .collect();
+    rules.iter().fold(false, |ignored, rule| {
+        let matched = prefixes
+            .iter()
+            .any(|prefix| rule.pattern.matches_with(prefix, options));
+        if matched { !rule.exception } else { ignored }
+    })
+}
+
+/// add the files of `directory` below `context` that are not ignored, the
+/// paths in `keep` are added regardless
+fn append_context(
+    archive: &mut tar::Builder<Vec<u8>>,
+    context: &Path,
+    directory: &Path,
+    rules: &[IgnoreRule],
+    keep: &[&str],
+) -> io::Result<()> {
+    let mut entries = read_dir(directory)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = path
+            .strip_prefix(context)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .to_string();
+        let excluded = ignored(rules, &name) && !keep.contains(&name.as_str());
+        if entry.file_type()?.is_dir() {
+            if !excluded {
+                archive.append_dir(&name, &path)?;
+            }
+            // an exception can bring back files below an ignored directory
+            if !excluded || rules.iter().any(|rule| rule.exception) {
+                append_context(archive, context, &path, rules, keep)?;
+            }
+        } else if !excluded {
+            archive.append_path_with_name(&path, &name)?;
+        }
+    }
+    Ok(())
+}
+
+/// tar archive of the build context without the paths ignored by its
+/// `.dockerignore`, like the compose CLI sends it; the Dockerfile and the
+/// `.dockerignore` itself are always included
+fn context_archive(context: &Path, dockerfile: &str) -> Result<Vec<u8>, String> {
+    let mut archive = tar::Builder::new(vec![]);
+    archive.follow_symlinks(false);
+    let keep = [dockerfile.trim_start_matches("./"), ".dockerignore"];
+    append_context(
+        &mut archive,
+        context,
+        context,
+        &ignore_rules(context),
+        &keep,
+    )
+    .map_err(|e| format!("could not archive {}: {}", context.display(), e))?;
+    archive.into_inner().map_err(|e| e.to_string())
+}
+
+/// a named volume of a project, prefixed like the compose CLI does so that
+/// projects do not share volumes of the same name
+fn volume_name(project: &str, source: &str) -> String {
+    format!("{}_{}", project, source)
+}
+
+fn load_compose(compose_file_path: &str) -> Result<ComposeFile, String> {
+    let contents = read_to_string(compose_file_path).map_err(|e| e.to_string())?;
+    serde_yaml::from_str(&contents).map_err(|e| format!("invalid {}: {}", compose_file_path, e))
+}
+
+/// paths in the compose file are relative to its directory
+fn compose_directory(compose_file_path: &str) -> PathBuf {
+    let directory = Path::new(compose_file_path)
+        .parent()
+        .unwrap_or(Path::new("."));
+    absolute(directory).unwrap_or(directory.to_path_buf())
+}
+
+/// `POST /containers/create` body for a compose service, `environment` holds
+/// the values of the variables the compose file takes from secrets
+fn container_config(
+    service: &ComposeService,
+    project: &str,
+    key: &str,
+    compose: &ComposeFile,
+    directory: &Path,
+    environment: &[(String, String)],
+) -> Value {
+    let mut exposed_ports = Map::new();
+    let mut port_bindings = Map::new();
+    for port in &service.ports {
+        let (mapping, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+        // `container`, `host:container` or `ip:host:container`
+        let parts: Vec<&str> = mapping.rsplitn(3, ':').collect();
+        let container_port = format!("{}/{}", parts[0], protocol);
+        exposed_ports.insert(container_port.clone(), json!({}));
+        if let Some(host_port) = parts.get(1) {
+            let bindings = port_bindings
+                .entry(container_port)
+                .or_insert_with(|| json!([]));
+            if let Some(bindings) = bindings.as_array_mut() {
+                bindings.push(json!({
+                    "HostIp": parts.get(2).copied().unwrap_or_default(),
+                    "HostPort": host_port,
+                }));
+            }
+        }
+    }
+    let mut binds: Vec<String> = service
+        .volumes
+        .iter()
+        .flatten()
+        .map(|volume| match volume.split_once(':') {
+            Some((source, rest)) if source.starts_with('.') => {
+                format!("{}:{}", directory.join(source).display(), rest)
+            }
+            Some((source, rest)) if !source.starts_with('/') => {
+                format!("{}:{}", volume_name(project, source), rest)
+            }
+            _ => volume.clone(),
+        })
+        .collect();
+    for name in &service.secrets {
+        if let Some(file) = compose
+            .secrets
+            .get(name)
+            .and_then(|secret| secret.file.as_ref())
+        {
+            binds.push(format!(
+                "{}:/run/secrets/{}:ro",
+                directory.join(file).display(),
+                name
+            ));
+        }
+    }
+    let env: Vec<String> = service
+        .environment
+        .iter()
+        .flatten()
+        .map(
+            |entry| match environment.iter().find(|(key, _)| key == entry) {
+                Some((key, value)) => format!("{}={}", key, value),
+                None => entry.clone(),
+            },
+        )
+        .collect();
+    let restart = service.restart.as_deref().unwrap_or("no");
+    let (restart_name, retries) = match restart.split_once(':') {
+        Some((name, retries)) => (name, retries.parse::<u64>().unwrap_or_default()),
+        None => (restart, 0),
+    };
+    let mut config = json!({
+        "Image": service.image,
+        "Env": env,
+        "Tty": service.tty.unwrap_or_default(),
+        "OpenStdin": service.stdin_open.unwrap_or_default(),
+        "ExposedPorts": exposed_ports,
+        "Labels": {
+            "com.docker.compose.project": project,
+            "com.docker.compose.service": key,
+        },
+        "HostConfig": {
+            "PortBindings": port_bindings,
+            "Binds": binds,
+            "RestartPolicy": {"Name": restart_name, "MaximumRetryCount": retries},
+        },
+    });
+    if let Some(user) = &service.user {
+        config["User"] = json!(user);
+    }
+    config
+}
+
+/// build the images of a compose file through the Engine API, BuildKit
+/// secrets are not available to its builder
+pub fn api_build(
+    client: &EngineClient,
+    compose_file_path: &str,
+    flags: BuildFlags,
+    dry_run: bool,
+) -> bool {
+    let compose = match load_compose(compose_file_path) {
+        Ok(compose) => compose,
+        Err(e) if dry_run => {
+            println!(
+                "[dry-run] would build the images of {}: {}",
+                compose_file_path, e
+            );
+            return true;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let directory = compose_directory(compose_file_path);
+    for service in compose.services.values() {
+        let build = match &service.build {
+            Some(build) => build,
+            None => continue,
+        };
+        if !build.secrets.is_empty() {
+            eprintln!(
+                "Building {} needs BuildKit secrets, which the Engine API backend does not support",
+                service.image
+            );
+            return false;
+        }
+        if !build.cache_to.is_empty() {
+            println!(
+                "The cache of {} is not exported, `cache_to` needs BuildKit",
+                service.image
+            );
+        }
+        let context = directory.join(&build.context);
+        if dry_run {
+            println!(
+                "[dry-run] would build {} from {} through the Engine API",
+                service.image,
+                context.display()
+            );
+            continue;
+        }
+        println!("Building {} from {}", service.image, context.display());
+        let options = BuildOptions {
+            dockerfile: build.dockerfile.clone(),
+            build_args: build.args.clone().unwrap_or_default(),
+            target: build.target.clone(),
+            platform: build.platforms.first().cloned(),
+            cache_from: build.cache_from.clone(),
+            no_cache: build.no_cache || flags.no_cache,
+            pull: build.pull || flags.pull,
+        };
+        if let Err(e) = client.build(&context, &service.image, &options) {
+            eprintln!("Building {} failed: {}", service.image, e);
+            return false;
+        }
+    }
+    true
+}
+
+/// recreate and start the containers of a compose file, a container that does
+/// not keep running has its logs printed
+pub fn api_start(
+    client: &EngineClient,
+    compose_file_path: &str,
+    project: &str,
+    dry_run: bool,
+) -> bool {
+    let compose = match load_compose(compose_file_path) {
+        Ok(compose) => compose,
+        Err(e) if dry_run => {
+            println!("[dry-run] would start {}: {}", project, e);
+            return true;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let directory = compose_directory(compose_file_path);
+    let environment = if dry_run {
+        vec![]
+    } else {
+        match secret_environment(compose_file_path) {
+            Ok(environment) => environment,
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        }
+    };
+    for (key, service) in &compose.services {
+        let name = &service.container_name;
+        if dry_run {
+            println!(
+                "[dry-run] would recreate and start {} from {} through the Engine API",
+                name, service.image
+            );
+            continue;
+        }
+        let config = container_config(service, project, key, &compose, &directory, &environment);
+        let started = client
+            .remove_container(name)
+            .and_then(|_| client.create_container(name, &config))
+            .and_then(|_| client.start_container(name))
+            .and_then(|_| client.inspect_container(name));
+        match started {
+            Ok(Some(inspect)) if inspect.state.running => {
+                println!("Container {} is {}", name, inspect.state.status);
+            }
+            Ok(inspect) => {
+                let status = inspect.map_or("missing".to_string(), |inspect| {
+                    format!(
+                        "{} (exit code {})",
+                        inspect.state.status, inspect.state.exit_code
+                    )
+                });
+                eprintln!("Container {} is not running: {}", name, status);
+                if let Ok(logs) = client.container_logs(name, LOG_TAIL) {
+                    eprint!("{}", logs);
+                }
+                return false;
+            }
+            Err(e) => {
+                eprintln!("Starting {} failed: {}", name, e);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// stop and remove the containers of a compose file
+pub fn api_stop(
+    client: &EngineClient,
+    compose_file_path: &str,
+    project: &str,
+    dry_run: bool,
+) -> bool {
+    let compose = match load_compose(compose_file_path) {
+        Ok(compose) => compose,
+        Err(e) if dry_run => {
+            println!("[dry-run] would stop {}: {}", project, e);
+            return true;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    for service in compose.services.values() {
+        let name = &service.container_name;
+        if dry_run {
+            println!(
+                "[dry-run] would stop and remove {} through the Engine API",
+                name
+            );
+            continue;
+        }
+        if let Err(e) = client
+            .stop_container(name)
+            .and_then(|_| client.remove_container(name))
+        {
+            eprintln!("Stopping {} failed: {}", name, e);
+            return false;
+        }
+        println!("Container {} removed", name);
+    }
+    true
+}
+
+/// stop and remove the containers of a compose file along with their named
+/// volumes and images when asked to
+pub fn api_remove(
+    client: &EngineClient,
+    compose_file_path: &str,
+    project: &str,
+    volumes: bool,
+    images: bool,
+    dry_run: bool,
+) -> bool {
+    if !api_stop(client, compose_file_path, project, dry_run) {
+        return false;
+    }
+    let compose = match load_compose(compose_file_path) {
+        Ok(compose) => compose,
+        Err(_) if dry_run => return true,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let remove = |kind: &str, name: &str, removal: &dyn Fn() -> Result<(), String>| {
+        if dry_run {
+            println!(
+                "[dry-run] would remove the {} {} through the Engine API",
+                kind, name
+            );
+            return true;
+        }
+        match removal() {
+            Ok(_) => {
+                println!("Removed the {} {}", kind, name);
+                true
+            }
+            Err(e) => {
+                eprintln!("Removing the {} {} failed: {}", kind, name, e);
+                false
+            }
+        }
+    };
+    for service in compose.services.values() {
+        // bind mounts are paths, everything else is a named volume
+        let named_volumes = service
+            .volumes
+            .iter()
+            .flatten()
+            .filter_map(|volume| volume.split_once(':').map(|(source, _)| source))
+            .filter(|source| !source.starts_with('.') && !source.starts_with('/'));
+        if volumes {
+            for name in named_volumes.map(|source| volume_name(project, source)) {
+                if !remove("volume", &name, &|| client.remove_volume(&name)) {
+                    return false;
+                }
+            }
+        }
+        if images
+            && !remove("image", &service.image, &|| {
+                client.remove_image(&service.image, true)
+            })
+        {
+            return false;
+        }
+    }
+    true
+}
@@ -1,4 +1,10 @@
-use std::path::Path;
+use std::{
+    fs::{OpenOptions, Permissions, read_dir, symlink_metadata},
+    io::{self, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Component, Path, PathBuf, absolute},
+};
+
 pub fn check_file(path: &str) -> bool {
     if Path::exists(Path::new(path)) {
         true
@@ -7,3 +13,74 @@ pub fn check_file(path: &str) -> bool {
         false
     }
 }
+
+/// write a file only its owner can read, an existing file is truncated and
+/// its mode reset to `0600`
+pub fn write_private(path: &str, contents: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())
+}
+
+/// absolute form of a path with `.` and `..` resolved lexically
+fn normalize(path: &Path) -> PathBuf {
+    let path = absolute(path).unwrap_or(path.to_path_buf());
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// `path` relative to the directory `base`, both may be absolute or relative
+/// to the current directory
+pub fn relative_path(base: &Path, path: &Path) -> PathBuf {
+    let base = normalize(base);
+    let path = normalize(path);
+    let common = base
+        .components()
+        .zip(path.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    for component in path.components().skip(common) {
+        relative.push(component);
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+/// bytes used by a file or a directory tree, symlinks are not followed
+pub fn disk_usage(path: &Path) -> u64 {
+    let metadata = match symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| disk_usage(&entry.path()))
+                .sum()
+        })
+        .unwrap_or_default()
+}
@@ -1,10 +1,22 @@
-use std::{path::Path, process::exit};
+use std::{cmp::Reverse, fmt, path::Path, process::Command, rc::Rc, time::Duration};
 
+use chrono::{FixedOffset, TimeZone, Utc};
 use git2::{
+    AutotagOption, BranchType, Cred, DiffOptions, DiffStatsFormat, FetchOptions, FetchPrune, Oid,
+    Reference, RemoteCallbacks, Repository, Signature, Sort, StashFlags, StatusOptions,
+    SubmoduleUpdateOptions,
     build::{CheckoutBuilder, RepoBuilder},
-    AutotagOption, BranchType, Cred, FetchOptions, RemoteCallbacks, Repository,
 };
-use inquire::Select;
+use glob::Pattern;
+use indicatif::HumanDuration;
+
+use crate::{
+    objects::structs::{Application, Changelog, ChangelogEntry, DirtyPolicy, DivergencePolicy},
+    utils::progress::TransferProgress,
+};
+
+/// `--unshallow`, fetch the complete history of a shallow clone
+const GIT_FETCH_DEPTH_UNSHALLOW: i32 = 2147483647;
 
 /// Check if the repository exists on the local filesystem
 pub fn check_repository(path: &Path) -> bool {
@@ -14,70 +26,258 @@ pub fn check_repository(path: &Path) -> bool {
         Err(_) => false,
     }
 }
-/// handle the user choice and clone the repo as required\
-/// wrapper around the git2 library
-pub fn prompt_clone_repository(
-    git_username: &str,
-    git_password: &str,
-    repo_url: &str,
-    repository_path: &str,
-) {
-    let clone_allow = Select::new(
-        "Repository does not seem to exist.\nWould you like to clone it",
-        vec!["Yes", "No"],
-    )
-    .prompt();
-    match clone_allow {
-        Ok(clone_allow_option) => {
-            if clone_allow_option == "Yes" {
-                println!("Cloning in progress");
-                let mut callbacks = RemoteCallbacks::new();
-                callbacks
-                    .credentials(|_, _, _| Cred::userpass_plaintext(git_username, git_password));
-                let mut fo = FetchOptions::new();
-                fo.remote_callbacks(callbacks);
-                let mut builder = RepoBuilder::new();
-                builder.fetch_options(fo);
-                let clone_status = builder.clone(repo_url, Path::new(repository_path));
-                match clone_status {
-                    Ok(_repo) => {
-                        println!("Cloning repository is complete");
-                    }
-                    Err(_) => {
-                        eprintln!("FATAL Cloning the repostiory failed");
-                    }
+/// fetch options authenticating with the configured credentials and reporting
+/// the transfer progress
+fn fetch_options<'a>(
+    git_username: &'a str,
+    git_password: &'a str,
+    progress: &Rc<TransferProgress>,
+) -> FetchOptions<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|_, _, _| Cred::userpass_plaintext(git_username, git_password));
+    let transfer = Rc::clone(progress);
+    callbacks.transfer_progress(move |stats| transfer.transfer(stats));
+    let sideband = Rc::clone(progress);
+    callbacks.sideband_progress(move |data| sideband.sideband(data));
+    let mut fo = FetchOptions::new();
+    fo.remote_callbacks(callbacks);
+    fo
+}
+
+/// a `fetch_branches` entry has to be a branch name, optionally with a single
+/// `*`, to form a refspec
+pub fn valid_fetch_pattern(pattern: &str) -> bool {
+    pattern.matches('*').count() <= 1
+        && Reference::is_valid_name(&format!("refs/heads/{}", pattern.replace('*', "x")))
+}
+
+/// refspecs fetching the configured branches of a remote, every branch by
+/// default
+pub fn fetch_refspecs(application: &Application, remote: &str) -> Vec<String> {
+    match &application.fetch_branches {
+        Some(patterns) => patterns
+            .iter()
+            .map(|pattern| format!("refs/heads/{}:refs/remotes/{}/{}", pattern, remote, pattern))
+            .collect(),
+        None => vec![format!("refs/heads/*:refs/remotes/{}/*", remote)],
+    }
+}
+
+/// make the remotes of the clone match the configuration, the url of a remote
+/// changes when a repository moves to a new host
+fn reconcile_remotes(repo: &Repository, application: &Application, dry_run: bool) -> bool {
+    for remote in application.remotes() {
+        match repo.find_remote(&remote.name) {
+            Ok(existing) => {
+                let current_url = existing.url().unwrap_or_default().to_string();
+                if current_url == remote.url {
+                    continue;
+                }
+                if dry_run {
+                    println!(
+                        "[dry-run] would change the url of remote {} from {} to {}",
+                        remote.name, current_url, remote.url
+                    );
+                } else if let Err(e) = repo.remote_set_url(&remote.name, &remote.url) {
+                    eprintln!(
+                        "Updating the url of remote {} failed: {}",
+                        remote.name,
+                        e.message()
+                    );
+                    return false;
+                } else {
+                    println!(
+                        "Remote {} moved from {} to {}",
+                        remote.name, current_url, remote.url
+                    );
+                }
+            }
+            Err(_) => {
+                if dry_run {
+                    println!(
+                        "[dry-run] would add remote {} ({})",
+                        remote.name, remote.url
+                    );
+                } else if let Err(e) = repo.remote(&remote.name, &remote.url) {
+                    eprintln!("Adding remote {} failed: {}", remote.name, e.message());
+                    return false;
+                } else {
+                    println!("Remote {} added ({})", remote.name, remote.url);
                 }
-            } else if clone_allow_option == "No" {
-                eprintln!("Please clone the repo manually at {repository_path} to proceed with deployment");
-                exit(0);
             }
         }
-        Err(_) => println!("There was an error, please try again, choose a valid option"),
     }
+    true
 }
 
-/// check if the reposity can be pulled from the remote.
-pub fn pull_repository(git_username: &str, git_password: &str, repository_path: &str) -> bool {
-    let repo = Repository::open(Path::new(repository_path)).unwrap();
-    let mut remote = repo.find_remote("origin").unwrap();
-    let mut callbacks = RemoteCallbacks::new();
-    callbacks.credentials(|_, _, _| Cred::userpass_plaintext(git_username, git_password));
-    let mut fo = FetchOptions::new();
-    fo.remote_callbacks(callbacks);
-    fo.download_tags(AutotagOption::All); // Fetch all tags
+fn matches_any(patterns: &[String], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(name)))
+}
+
+/// whether a remote branch such as `origin/main` may be deployed, it has to be
+/// one of the fetched branches, allowed and not denied
+pub fn is_deployable_branch(application: &Application, branch: &str) -> bool {
+    let name = branch.split_once('/').map_or(branch, |(_, name)| name);
+    application
+        .fetch_branches
+        .as_ref()
+        .is_none_or(|patterns| matches_any(patterns, name))
+        && application
+            .branch_allowlist
+            .as_ref()
+            .is_none_or(|patterns| matches_any(patterns, name))
+        && !application
+            .branch_denylist
+            .as_ref()
+            .is_some_and(|patterns| matches_any(patterns, name))
+}
+
+/// a branch offered for deployment along with its latest commit
+pub struct BranchOption {
+    pub name: String,
+    pub subject: String,
+    pub time: i64,
+}
+
+impl fmt::Display for BranchOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let age = (Utc::now().timestamp() - self.time).max(0) as u64;
+        write!(
+            f,
+            "{}  {} ({} ago)",
+            self.name,
+            self.subject,
+            HumanDuration(Duration::from_secs(age))
+        )
+    }
+}
+
+/// single-branch clone, only the configured branches are fetched and nothing is
+/// checked out since the remote's default branch may not be among them
+fn clone_branches(
+    application: &Application,
+    repository_path: &str,
+    fo: &mut FetchOptions,
+) -> Result<Repository, git2::Error> {
+    let refspecs = fetch_refspecs(application, &application.remote);
+    let repo = Repository::init(repository_path)?;
+    {
+        let mut remote = repo.remote_with_fetch(
+            &application.remote,
+            &application.repository_url,
+            &refspecs[0],
+        )?;
+        for refspec in &refspecs[1..] {
+            repo.remote_add_fetch(&application.remote, refspec)?;
+        }
+        remote.fetch(&refspecs, Some(fo), None)?;
+    }
+    Ok(repo)
+}
+
+/// clone the primary remote of the application, only the configured branches
+/// when `fetch_branches` is set
+pub fn clone_repository(
+    git_username: &str,
+    git_password: &str,
+    application: &Application,
+    repository_path: &str,
+    non_interactive: bool,
+) -> bool {
+    let progress = TransferProgress::new("Cloning", non_interactive);
+    let mut fo = fetch_options(git_username, git_password, &progress);
+    if let Some(depth) = application.clone_depth {
+        fo.depth(depth);
+    }
+    let clone_status = if application.fetch_branches.is_some() {
+        clone_branches(application, repository_path, &mut fo)
+    } else {
+        let mut builder = RepoBuilder::new();
+        builder.fetch_options(fo);
+        builder.remote_create(|repo, _, url| repo.remote(&application.remote, url));
+        builder.clone(&application.repository_url, Path::new(repository_path))
+    };
+    progress.finish();
+    match clone_status {
+        Ok(_repo) => {
+            println!("Cloning repository is complete");
+            true
+        }
+        Err(e) => {
+            eprintln!("FATAL Cloning the repostiory failed: {}", e.message());
+            false
+        }
+    }
+}
 
-    // Fetch all branches from the remote
-    let fetch_status = remote.fetch(&["refs/heads/*:refs/remotes/origin/*"], Some(&mut fo), None);
-    if fetch_status.is_err() {
-        println!("Failed to fetch from remote: {:?}", fetch_status.err());
+pub fn pull_repository(
+    git_username: &str,
+    git_password: &str,
+    application: &Application,
+    repository_path: &str,
+    dry_run: bool,
+    non_interactive: bool,
+) -> bool {
+    let repo = match Repository::open(Path::new(repository_path)) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("Could not open {}: {}", repository_path, e.message());
+            return false;
+        }
+    };
+    if !reconcile_remotes(&repo, application, dry_run) {
         return false;
     }
+    for remote_config in application.remotes() {
+        let refspecs = fetch_refspecs(application, &remote_config.name);
+        if dry_run {
+            println!(
+                "[dry-run] would fetch from {} with refspecs:",
+                remote_config.url
+            );
+            for refspec in refspecs {
+                println!("[dry-run]   {}", refspec);
+            }
+            continue;
+        }
+        let mut remote = match repo.find_remote(&remote_config.name) {
+            Ok(remote) => remote,
+            Err(e) => {
+                eprintln!("Remote {} is missing: {}", remote_config.name, e.message());
+                return false;
+            }
+        };
+        let progress =
+            TransferProgress::new(&format!("Fetching {}", remote_config.name), non_interactive);
+        let mut fo = fetch_options(git_username, git_password, &progress);
+        // a clone deepened for the changelog stays complete
+        if let Some(depth) = application.clone_depth.filter(|_| repo.is_shallow()) {
+            fo.depth(depth);
+        }
+        fo.download_tags(AutotagOption::All); // Fetch all tags
+        fo.prune(FetchPrune::On); // Drop branches deleted on the remote
+
+        // Fetch all branches from the remote
+        let fetch_status = remote.fetch(&refspecs, Some(&mut fo), None);
+        progress.finish();
+        if fetch_status.is_err() {
+            println!("Failed to fetch from remote: {:?}", fetch_status.err());
+            return false;
+        }
+    }
     let branches = repo.branches(Some(BranchType::Local)).unwrap();
 
     for branch_result in branches {
         let (branch, branch_type) = branch_result.unwrap();
         if branch_type == BranchType::Local {
             let branch_name = branch.name().unwrap().unwrap_or("<unknown>");
+            if branch.is_head() {
+                // moving the checked out branch would leave the working tree behind
+                // and make it look modified, the checkout moves it instead
+                continue;
+            }
 
             // Find the corresponding remote-tracking branch
             let upstream_name = format!("refs/remotes/{}", branch_name);
@@ -91,6 +291,15 @@ pub fn pull_repository(git_username: &str, git_password: &str, repository_path:
                         .graph_descendant_of(upstream_commit.id(), local_commit.id())
                         .unwrap()
                 {
+                    if dry_run {
+                        println!(
+                            "[dry-run] would fast-forward branch {} from {} to {}",
+                            branch_name,
+                            local_commit.id(),
+                            upstream_commit.id()
+                        );
+                        continue;
+                    }
                     println!("Fast-forwarding branch: {}", branch_name);
 
                     // Fast-forward the branch
@@ -98,11 +307,23 @@ pub fn pull_repository(git_username: &str, git_password: &str, repository_path:
                     branch_ref
                         .set_target(upstream_commit.id(), "Fast-forwarding")
                         .unwrap();
+                } else if local_commit.id() == upstream_commit.id() {
+                    println!("Branch '{}' is up-to-date.", branch_name);
                 } else {
-                    println!(
-                        "Branch '{}' is up-to-date or cannot be fast-forwarded.",
-                        branch_name
-                    );
+                    let (ahead, behind) = repo
+                        .graph_ahead_behind(local_commit.id(), upstream_commit.id())
+                        .unwrap();
+                    if behind == 0 {
+                        println!(
+                            "Branch '{}' is {} commit(s) ahead of the remote.",
+                            branch_name, ahead
+                        );
+                    } else {
+                        println!(
+                            "Branch '{}' has diverged from the remote: {} ahead, {} behind.",
+                            branch_name, ahead, behind
+                        );
+                    }
                 }
             } else {
                 println!("No upstream branch found for '{}'", branch_name);
@@ -112,83 +333,518 @@ pub fn pull_repository(git_username: &str, git_password: &str, repository_path:
     true
 }
 
-/// prompt the user to select a branch.
-pub fn prompt_branch_selection(repository_path: &str) -> Option<String> {
-    // first fetch the repo and then prompt the branches for selection
-    // repo is assumed to exist on the filesystem
-    let repo = Repository::open(repository_path).unwrap();
-    let branches = repo.branches(Some(BranchType::Remote));
-    let mut branch_list: Vec<String> = vec![];
-    match branches {
-        Ok(branches) => {
-            for branch_result in branches {
-                match branch_result {
-                    Ok((branch, _)) => {
-                        match branch.name() {
-                            Ok(Some(name)) => branch_list.push(name.to_string()),
-                            Ok(None) => {
-                                println!("cannot parse/fetch the branch try again")
-                            }
-                            Err(e) => {
-                                // Handle error from branch.name()
-                                eprintln!("Error getting branch name: {:?}", e);
-                            }
+/// resolve a branch given on the command line, `main` and `origin/main` both
+/// refer to the branch of the primary remote, branches of forks are prefixed
+/// with the fork's name
+pub fn resolve_branch(
+    repository_path: &str,
+    application: &Application,
+    branch: &str,
+) -> Result<Option<String>, git2::Error> {
+    let repo = Repository::open(repository_path)?;
+    Ok([
+        branch.to_string(),
+        format!("{}/{}", application.remote, branch),
+    ]
+    .into_iter()
+    .find(|name| {
+        is_deployable_branch(application, name)
+            && repo.find_branch(name, BranchType::Remote).is_ok()
+    }))
+}
+
+/// the remote branches that may be deployed, most recently updated first
+pub fn deployable_branches(
+    repository_path: &str,
+    application: &Application,
+) -> Result<Vec<BranchOption>, git2::Error> {
+    let repo = Repository::open(repository_path)?;
+    let mut branch_list: Vec<BranchOption> = vec![];
+    for branch_result in repo.branches(Some(BranchType::Remote))? {
+        match branch_result {
+            Ok((branch, _)) => {
+                match branch.name() {
+                    Ok(Some(name)) => {
+                        if let Ok(commit) = branch.get().peel_to_commit() {
+                            branch_list.push(BranchOption {
+                                name: name.to_string(),
+                                subject: commit.summary().unwrap_or_default().to_string(),
+                                time: commit.time().seconds(),
+                            })
                         }
                     }
+                    Ok(None) => {
+                        println!("cannot parse/fetch the branch try again")
+                    }
                     Err(e) => {
-                        // Handle error from branch_result
-                        eprintln!("Error processing branch result: {:?}", e);
+                        // Handle error from branch.name()
+                        eprintln!("Error getting branch name: {:?}", e);
                     }
                 }
             }
+            Err(e) => {
+                // Handle error from branch_result
+                eprintln!("Error processing branch result: {:?}", e);
+            }
         }
-        Err(_) => {
-            eprintln!("Cannot fetch branches");
+    }
+    // remove <remote>/HEAD and branches of remotes that are no longer configured
+    let remotes = application.remotes();
+    branch_list.retain(|branch| {
+        !branch.name.ends_with("/HEAD")
+            && remotes
+                .iter()
+                .any(|remote| branch.name.starts_with(&format!("{}/", remote.name)))
+    });
+    // branches fetched before `fetch_branches` was configured are stale
+    branch_list.retain(|branch| is_deployable_branch(application, &branch.name));
+    // most recently updated branches first
+    branch_list.sort_by_key(|branch| Reverse(branch.time));
+    Ok(branch_list)
+}
+
+/// the commits and file changes between the deployed commit and the tip of the
+/// selected branch, the current HEAD is used when nothing was deployed yet
+pub fn branch_changelog(
+    repository_path: &str,
+    deployed_commit: Option<&str>,
+    branch_selection: &str,
+) -> Result<Changelog, git2::Error> {
+    let repo = Repository::open(Path::new(repository_path))?;
+    let target = repo
+        .find_branch(branch_selection, BranchType::Remote)?
+        .get()
+        .peel_to_commit()?;
+    let base = match deployed_commit {
+        Some(commit) => Oid::from_str(commit)
+            .and_then(|oid| repo.find_commit(oid))
+            .ok(),
+        None => repo.head().and_then(|head| head.peel_to_commit()).ok(),
+    };
+    let mut changelog = Changelog {
+        target: target.id().to_string(),
+        ..Default::default()
+    };
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    revwalk.push(target.id())?;
+    if let Some(base) = &base {
+        revwalk.hide(base.id())?;
+    }
+    for oid in revwalk.flatten() {
+        if let Ok(commit) = repo.find_commit(oid) {
+            let time = commit.time();
+            let date = FixedOffset::east_opt(time.offset_minutes() * 60)
+                .and_then(|offset| offset.timestamp_opt(time.seconds(), 0).single())
+                .map(|date| date.format("%Y-%m-%d %H:%M %z").to_string())
+                .unwrap_or_default();
+            changelog.entries.push(ChangelogEntry {
+                commit: oid.to_string(),
+                subject: commit.summary().unwrap_or_default().to_string(),
+                author: commit.author().name().unwrap_or("<unknown>").to_string(),
+                date,
+            });
         }
     }
-    // remove origin/HEAD from the listed options
-    branch_list.retain(|branch| branch != "origin/HEAD");
-    let filtered_branch_list: Vec<&str> =
-        branch_list.iter().map(|branch| branch.as_str()).collect();
-    // prompt the user to select the branch
-    let branch_selection =
-        Select::new("choose the branch to be deployed", filtered_branch_list).prompt();
+    if let Some(base) = &base {
+        changelog.stats = repo
+            .diff_tree_to_tree(Some(&base.tree()?), Some(&target.tree()?), None)
+            .and_then(|diff| diff.stats())
+            .and_then(|stats| stats.to_buf(DiffStatsFormat::FULL, 80))
+            .ok()
+            .and_then(|buf| buf.as_str().map(str::to_string));
+    }
+    Ok(changelog)
+}
+
+/// whether any file below `path` of the repository differs between two
+/// commits, commits that cannot be found count as changed
+pub fn path_changed(repository_path: &str, from: &str, to: &str, path: Option<&str>) -> bool {
+    let repo = match Repository::open(Path::new(repository_path)) {
+        Ok(repo) => repo,
+        Err(_) => return true,
+    };
+    let tree = |commit: &str| {
+        Oid::from_str(commit)
+            .and_then(|oid| repo.find_commit(oid))
+            .and_then(|commit| commit.tree())
+    };
+    let (from, to) = match (tree(from), tree(to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => return true,
+    };
+    let mut options = DiffOptions::new();
+    if let Some(path) = path {
+        options.pathspec(path.trim_end_matches('/'));
+    }
+    repo.diff_tree_to_tree(Some(&from), Some(&to), Some(&mut options))
+        .map(|diff| diff.deltas().len() > 0)
+        .unwrap_or(true)
+}
+
+/// print the commits that are about to go live
+pub fn print_changelog(changelog: &Changelog) {
+    if changelog.entries.is_empty() {
+        println!("No new commits, {} is already deployed", changelog.target);
+        return;
+    }
+    println!("Commits to be deployed:");
+    for entry in &changelog.entries {
+        println!(
+            "  {} {} ({}, {})",
+            &entry.commit[..7],
+            entry.subject,
+            entry.author,
+            entry.date
+        );
+    }
+    if let Some(stats) = &changelog.stats {
+        println!("\n{}", stats);
+    }
+}
+
+/// list the modified, staged and untracked files in the working tree
+pub fn working_tree_changes(repository_path: &str) -> Result<Vec<String>, git2::Error> {
+    let repo = Repository::open(Path::new(repository_path))?;
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .include_ignored(false)
+        .exclude_submodules(true);
+    let statuses = repo.statuses(Some(&mut options))?;
+    Ok(statuses
+        .iter()
+        .map(|entry| {
+            let marker = if entry.status().is_wt_new() {
+                "??"
+            } else if entry.status().is_index_new() {
+                "A"
+            } else if entry.status().is_wt_deleted() || entry.status().is_index_deleted() {
+                "D"
+            } else {
+                "M"
+            };
+            format!("{} {}", marker, entry.path().unwrap_or("<unknown>"))
+        })
+        .collect())
+}
 
-    match branch_selection {
-        Ok(branch) => Some(branch.to_owned()),
-        Err(_) => {
-            println!("There was an error, please try again, choose a valid branch");
-            None
+/// apply the policy to the local changes, false when the deployment must not
+/// continue
+pub fn handle_local_changes(repository_path: &str, policy: DirtyPolicy) -> bool {
+    let mut repo = match Repository::open(Path::new(repository_path)) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("Could not open {}: {}", repository_path, e.message());
+            return false;
+        }
+    };
+    match policy {
+        DirtyPolicy::Abort => false,
+        DirtyPolicy::Stash => {
+            let signature = repo
+                .signature()
+                .or_else(|_| Signature::now("autodeploy", "autodeploy@localhost"))
+                .unwrap();
+            match repo.stash_save(
+                &signature,
+                "autodeploy: local changes before deployment",
+                Some(StashFlags::INCLUDE_UNTRACKED),
+            ) {
+                Ok(oid) => {
+                    println!("Local changes stashed as {}", oid);
+                    true
+                }
+                Err(e) => {
+                    eprintln!("Stashing the local changes failed: {}", e.message());
+                    false
+                }
+            }
+        }
+        DirtyPolicy::Discard => {
+            match repo.checkout_head(Some(CheckoutBuilder::new().force().remove_untracked(true))) {
+                Ok(_) => {
+                    println!("Local changes discarded");
+                    true
+                }
+                Err(e) => {
+                    eprintln!("Discarding the local changes failed: {}", e.message());
+                    false
+                }
+            }
         }
     }
 }
 
-pub fn branch_checkout(repository_path: &str, branch_selection: String) {
-    let repo = Repository::open(Path::new(repository_path)).unwrap();
-    let remote_branch_ref = repo
-        .find_branch(branch_selection.as_str(), BranchType::Remote)
-        .unwrap();
-    let remote_branch_commit = repo
-        .reference_to_annotated_commit(remote_branch_ref.get())
-        .unwrap();
+/// make sure a commit is available locally, shallow clones are deepened to
+/// the full history when it is missing
+pub fn ensure_commit(
+    git_username: &str,
+    git_password: &str,
+    application: &Application,
+    repository_path: &str,
+    commit: &str,
+    dry_run: bool,
+    non_interactive: bool,
+) -> bool {
+    let repo = match Repository::open(Path::new(repository_path)) {
+        Ok(repo) => repo,
+        Err(_) => return false,
+    };
+    let oid = match Oid::from_str(commit) {
+        Ok(oid) => oid,
+        Err(_) => return false,
+    };
+    if repo.find_commit(oid).is_ok() {
+        return true;
+    }
+    if !repo.is_shallow() {
+        return false;
+    }
+    if dry_run {
+        println!(
+            "[dry-run] would deepen the shallow clone to find commit {}",
+            commit
+        );
+        return false;
+    }
+    println!("Commit {} is missing, deepening the shallow clone", commit);
+    let mut remote = match repo.find_remote(&application.remote) {
+        Ok(remote) => remote,
+        Err(e) => {
+            eprintln!("Remote {} is missing: {}", application.remote, e.message());
+            return false;
+        }
+    };
+    let progress = TransferProgress::new("Deepening", non_interactive);
+    let mut fo = fetch_options(git_username, git_password, &progress);
+    fo.depth(GIT_FETCH_DEPTH_UNSHALLOW);
+    let fetch_status = remote.fetch(
+        &fetch_refspecs(application, &application.remote),
+        Some(&mut fo),
+        None,
+    );
+    progress.finish();
+    if let Err(e) = fetch_status {
+        eprintln!("Deepening the clone failed: {}", e.message());
+        return false;
+    }
+    repo.find_commit(oid).is_ok()
+}
+
+/// local commits of the branch that are missing on the remote, the checkout
+/// moves the branch to the remote tip and would drop them; `confirm` is asked
+/// with the number of local commits under the `prompt` policy\
+/// returns false when the deployment must not continue
+pub fn check_divergence(
+    repository_path: &str,
+    branch_selection: &str,
+    policy: DivergencePolicy,
+    dry_run: bool,
+    non_interactive: bool,
+    confirm: impl FnOnce(usize) -> bool,
+) -> Result<bool, git2::Error> {
+    let repo = Repository::open(Path::new(repository_path))?;
+    let local_branch = match repo.find_branch(branch_selection, BranchType::Local) {
+        Ok(local_branch) => local_branch,
+        Err(_) => return Ok(true),
+    };
+    let local_commit = local_branch.get().peel_to_commit()?;
+    let remote_commit = repo
+        .find_branch(branch_selection, BranchType::Remote)?
+        .get()
+        .peel_to_commit()?;
+    let (ahead, behind) = repo.graph_ahead_behind(local_commit.id(), remote_commit.id())?;
+    if ahead == 0 {
+        return Ok(true);
+    }
+    println!(
+        "Local branch '{}' has diverged from the remote: {} ahead, {} behind.",
+        branch_selection, ahead, behind
+    );
+    let reset = match policy {
+        DivergencePolicy::ResetToRemote => true,
+        DivergencePolicy::Abort => false,
+        DivergencePolicy::Prompt if dry_run || non_interactive => {
+            println!("The divergence policy `prompt` cannot be answered without prompting");
+            false
+        }
+        DivergencePolicy::Prompt => confirm(ahead),
+    };
+    if reset {
+        println!(
+            "'{}' will be reset to the remote tip {}",
+            branch_selection,
+            remote_commit.id()
+        );
+    }
+    Ok(reset)
+}
+
+pub fn branch_checkout(
+    repository_path: &str,
+    branch_selection: String,
+    dry_run: bool,
+) -> Result<(), git2::Error> {
+    let repo = Repository::open(Path::new(repository_path))?;
+    let remote_branch_ref = repo.find_branch(branch_selection.as_str(), BranchType::Remote)?;
+    let remote_branch_commit = repo.reference_to_annotated_commit(remote_branch_ref.get())?;
 
     // Get the actual commit from the annotated commit
-    let commit = repo.find_commit(remote_branch_commit.id()).unwrap();
+    let commit = repo.find_commit(remote_branch_commit.id())?;
+    if dry_run {
+        println!(
+            "[dry-run] would check out {} at {} ({})",
+            branch_selection,
+            commit.id(),
+            commit.summary().unwrap_or_default()
+        );
+        return Ok(());
+    }
 
     if let Ok(local_branch) = repo.find_branch(branch_selection.as_str(), BranchType::Local) {
-        // Checkout the existing local branch
-        repo.set_head(local_branch.get().name().unwrap()).unwrap();
+        // Move the existing local branch to the remote tip and check it out, the
+        // deployed branch always matches the remote exactly
+        let local_reference = local_branch
+            .into_reference()
+            .set_target(commit.id(), "autodeploy: move to remote tip")?;
+        repo.set_head(local_reference.name().unwrap_or_default())?;
     } else {
         // Create a new local branch that tracks the remote branch
-        let local_branch = repo
-            .branch(branch_selection.as_str(), &commit, false)
-            .unwrap();
-        repo.set_head(local_branch.get().name().unwrap()).unwrap();
+        let local_branch = repo.branch(branch_selection.as_str(), &commit, false)?;
+        repo.set_head(local_branch.get().name().unwrap_or_default())?;
     }
 
     // Checkout the new local branch
-    let (object, reference) = repo.revparse_ext(branch_selection.as_str()).unwrap();
-    repo.checkout_tree(&object, Some(&mut CheckoutBuilder::new().force()))
-        .unwrap();
-    repo.set_head(reference.unwrap().name().unwrap()).unwrap();
+    let (object, reference) = repo.revparse_ext(branch_selection.as_str())?;
+    repo.checkout_tree(&object, Some(&mut CheckoutBuilder::new().force()))?;
+    if let Some(reference) = reference.as_ref().and_then(|reference| reference.name()) {
+        repo.set_head(reference)?;
+    }
+    Ok(())
+}
+
+/// initialize and update the submodules of the checked out commit recursively,
+/// using the same credentials as the repository itself
+pub fn update_submodules(
+    git_username: &str,
+    git_password: &str,
+    repository_path: &str,
+    dry_run: bool,
+    non_interactive: bool,
+) -> bool {
+    let repo = match Repository::open(Path::new(repository_path)) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("Could not open {}: {}", repository_path, e.message());
+            return false;
+        }
+    };
+    update_submodules_of(&repo, git_username, git_password, dry_run, non_interactive)
+}
+
+fn update_submodules_of(
+    repo: &Repository,
+    git_username: &str,
+    git_password: &str,
+    dry_run: bool,
+    non_interactive: bool,
+) -> bool {
+    let submodules = match repo.submodules() {
+        Ok(submodules) => submodules,
+        Err(e) => {
+            eprintln!("Could not read the submodules: {}", e.message());
+            return false;
+        }
+    };
+    for mut submodule in submodules {
+        let name = submodule.name().unwrap_or("<unknown>").to_string();
+        if dry_run {
+            println!(
+                "[dry-run] would update submodule {} from {}",
+                name,
+                submodule.url().unwrap_or("<unknown>")
+            );
+            continue;
+        }
+        // pick up url changes from .gitmodules before updating
+        if let Err(e) = submodule.sync() {
+            eprintln!("Syncing submodule {} failed: {}", name, e.message());
+            return false;
+        }
+        let progress = TransferProgress::new(&format!("Submodule {}", name), non_interactive);
+        let mut options = SubmoduleUpdateOptions::new();
+        options.fetch(fetch_options(git_username, git_password, &progress));
+        let update_status = submodule.update(true, Some(&mut options));
+        progress.finish();
+        if let Err(e) = update_status {
+            eprintln!("Updating submodule {} failed: {}", name, e.message());
+            return false;
+        }
+        println!("Submodule {} updated", name);
+        match submodule.open() {
+            Ok(submodule_repo) => {
+                if !update_submodules_of(
+                    &submodule_repo,
+                    git_username,
+                    git_password,
+                    dry_run,
+                    non_interactive,
+                ) {
+                    return false;
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not open submodule {}: {}", name, e.message());
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// replace the Git LFS pointer files of the working tree with their content,
+/// libgit2 does not run the LFS filters so this shells out to `git lfs`
+pub fn pull_lfs(
+    git_username: &str,
+    git_password: &str,
+    repository_path: &str,
+    dry_run: bool,
+) -> bool {
+    let lfs_installed = Command::new("git")
+        .args(["lfs", "version"])
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !lfs_installed {
+        eprintln!("Git LFS is not installed, LFS files are left as pointers");
+        return true;
+    }
+    if dry_run {
+        println!("[dry-run] git lfs pull in {}", repository_path);
+        return true;
+    }
+    println!("Pulling Git LFS objects");
+    // the credentials are handed over through the environment so they never
+    // show up in the process list
+    let status = Command::new("git")
+        .args([
+            "-c",
+            "credential.helper=",
+            "-c",
+            "credential.helper=!f() { echo username=$AUTODEPLOY_GIT_USERNAME; echo password=$AUTODEPLOY_GIT_PASSWORD; }; f",
+            "lfs",
+            "pull",
+        ])
+        .current_dir(repository_path)
+        .env("AUTODEPLOY_GIT_USERNAME", git_username)
+        .env("AUTODEPLOY_GIT_PASSWORD", git_password)
+        .status();
+    match status {
+        Ok(status) if status.success() => true,
+        _ => {
+            eprintln!("Pulling Git LFS objects failed");
+            false
+        }
+    }
 }
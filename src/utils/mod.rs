@@ -1,3 +1,14 @@
+pub mod audit;
+pub mod bluegreen;
+pub mod config;
 pub mod docker;
+pub mod engine;
 pub mod file;
 pub mod git;
+pub mod notify;
+pub mod progress;
+pub mod registry;
+pub mod runner;
+pub mod secrets;
+pub mod signature;
+pub mod state;
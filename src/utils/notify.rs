@@ -0,0 +1,241 @@
+use std::{io::Write, path::Path, rc::Rc, time::Duration};
+
+use chrono::Utc;
+use indicatif::HumanDuration;
+use serde_json::{Value, json};
+use tempfile::NamedTempFile;
+
+use crate::{
+    objects::structs::{Channel, ChannelKind, NotificationEvent, Notifications, Operator},
+    utils::{
+        registry::environment_credentials,
+        runner::CommandRunner,
+        secrets::{SECRET_PREFIX, read_secret},
+    },
+};
+
+/// seconds a channel has to accept a notification
+const TIMEOUT: &str = "10";
+
+/// the longest message Discord accepts
+const DISCORD_LIMIT: usize = 2000;
+
+/// what happened to a deployment
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub event: NotificationEvent,
+    pub application: String,
+    pub branch: Option<String>,
+    pub commit: Option<String>,
+    pub operator: Operator,
+    pub duration: Duration,
+    pub error: Option<String>,
+    /// the last lines of output of the failed command
+    pub log: Option<String>,
+}
+
+impl Notification {
+    /// the template with its placeholders replaced, missing values become `-`
+    pub fn render(&self, template: &str) -> String {
+        let operator = match &self.operator.sudo_user {
+            Some(sudo_user) => format!("{} (sudo by {})", self.operator.user, sudo_user),
+            None => self.operator.user.clone(),
+        };
+        let commit = self.commit.as_deref().unwrap_or("-");
+        let duration = HumanDuration(self.duration).to_string();
+        [
+            ("{application}", self.application.as_str()),
+            ("{branch}", self.branch.as_deref().unwrap_or("-")),
+            ("{commit}", commit),
+            ("{short_commit}", &commit[..commit.len().min(12)]),
+            ("{operator}", &operator),
+            ("{hostname}", &self.operator.hostname),
+            ("{duration}", &duration),
+            ("{error}", self.error.as_deref().unwrap_or("-")),
+        ]
+        .iter()
+        .fold(template.to_string(), |message, (placeholder, value)| {
+            message.replace(placeholder, value)
+        })
+    }
+}
+
+/// the message followed by the log in a code block
+fn with_log(message: &str, log: Option<&str>) -> String {
+    match log {
+        Some(log) => format!("{}\n```\n{}\n```", message, log),
+        None => message.to_string(),
+    }
+}
+
+/// the body posted to an incoming webhook of the kind
+pub fn payload(kind: ChannelKind, message: &str, notification: &Notification) -> Value {
+    let text = with_log(message, notification.log.as_deref());
+    match kind {
+        ChannelKind::Slack => json!({ "text": text }),
+        ChannelKind::Discord => {
+            json!({ "content": text.chars().take(DISCORD_LIMIT).collect::<String>() })
+        }
+        ChannelKind::Teams => json!({
+            "@type": "MessageCard",
+            "@context": "https://schema.org/extensions",
+            "summary": message,
+            "themeColor": match notification.event {
+                NotificationEvent::Started => "0076D7",
+                NotificationEvent::Succeeded => "2EB886",
+                NotificationEvent::Failed | NotificationEvent::RolledBack => "D40E0D",
+            },
+            "text": text,
+        }),
+        ChannelKind::Webhook | ChannelKind::Email => json!({
+            "event": notification.event,
+            "message": message,
+            "application": notification.application,
+            "branch": notification.branch,
+            "commit": notification.commit,
+            "operator": notification.operator,
+            "duration_ms": notification.duration.as_millis() as u64,
+            "error": notification.error,
+            "log": notification.log,
+        }),
+    }
+}
+
+/// the email sent to the `to` of the channel, with CRLF line endings
+pub fn email(channel: &Channel, message: &str, notification: &Notification) -> String {
+    let mut body = message.to_string();
+    if let Some(log) = &notification.log {
+        body.push_str(&format!("\n\n{}", log));
+    }
+    format!(
+        "From: {}\nTo: {}\nDate: {}\nSubject: [autodeploy] {} {}\nContent-Type: text/plain; charset=utf-8\n\n{}\n",
+        channel.from.as_deref().unwrap_or_default(),
+        channel.to.join(", "),
+        Utc::now().to_rfc2822(),
+        notification.application,
+        notification.event,
+        body
+    )
+    .replace('\n', "\r\n")
+}
+
+/// a curl config, it is passed on standard input so that neither the webhook
+/// URL nor the SMTP password show up in the process list
+fn curl_config(options: &[(&str, String)]) -> String {
+    options
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r")
+                .replace('\t', "\\t");
+            format!("{} = \"{}\"\n", name, value)
+        })
+        .collect()
+}
+
+/// sends the notifications of deployments through `curl`
+pub struct Notifier {
+    notifications: Notifications,
+    secrets_directory: String,
+    runner: Rc<dyn CommandRunner>,
+}
+
+impl Notifier {
+    pub fn new(notifications: Notifications, runner: Rc<dyn CommandRunner>) -> Notifier {
+        Notifier {
+            notifications,
+            secrets_directory: "./secrets".to_string(),
+            runner,
+        }
+    }
+
+    /// where the URLs given as `secret:NAME` are read from
+    pub fn secrets_directory(mut self, secrets_directory: &str) -> Notifier {
+        self.secrets_directory = secrets_directory.to_string();
+        self
+    }
+
+    /// notify every channel subscribed to the event, a failing channel is
+    /// reported and does not affect the deployment
+    pub fn send(&self, notification: &Notification, dry_run: bool) {
+        let message =
+            notification.render(self.notifications.templates.template(notification.event));
+        let channels = self.notifications.channels.iter().filter(|channel| {
+            channel
+                .events
+                .as_ref()
+                .is_none_or(|events| events.contains(&notification.event))
+        });
+        for channel in channels {
+            if dry_run {
+                println!("[dry-run] would notify {}: {}", channel.kind, message);
+                continue;
+            }
+            if let Err(e) = self.deliver(channel, &message, notification) {
+                eprintln!("Notifying {} failed: {}", channel.kind, e);
+            }
+        }
+    }
+
+    fn deliver(
+        &self,
+        channel: &Channel,
+        message: &str,
+        notification: &Notification,
+    ) -> Result<(), String> {
+        let url = match channel.url.strip_prefix(SECRET_PREFIX) {
+            Some(name) => read_secret(&Path::new(&self.secrets_directory).join(name))?,
+            None => channel.url.clone(),
+        };
+        let mut args = vec![
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--max-time",
+            TIMEOUT,
+            "--config",
+            "-",
+        ];
+        let mut options = vec![("url", url)];
+        // the mail is uploaded from a file, standard input holds the config
+        let mut mail = None;
+        match channel.kind {
+            ChannelKind::Email => {
+                let mut file = NamedTempFile::new().map_err(|e| e.to_string())?;
+                file.write_all(email(channel, message, notification).as_bytes())
+                    .map_err(|e| e.to_string())?;
+                options.push(("mail-from", channel.from.clone().unwrap_or_default()));
+                options.extend(channel.to.iter().map(|to| ("mail-rcpt", to.clone())));
+                options.push(("upload-file", file.path().display().to_string()));
+                if let Some(prefix) = &channel.credentials {
+                    let credentials = environment_credentials(prefix)?;
+                    options.push((
+                        "user",
+                        format!("{}:{}", credentials.username, credentials.password),
+                    ));
+                    args.push("--ssl-reqd");
+                }
+                mail = Some(file);
+            }
+            kind => {
+                options.push(("header", "Content-Type: application/json".to_string()));
+                options.push((
+                    "data-binary",
+                    payload(kind, message, notification).to_string(),
+                ));
+            }
+        }
+        let output = self
+            .runner
+            .run_with_input("curl", &args, &curl_config(&options));
+        drop(mail);
+        if output.success {
+            Ok(())
+        } else {
+            Err(output.stderr.trim().to_string())
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use git2::Progress;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+
+/// how often the progress is logged in non-interactive mode
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// reports the progress of a clone or fetch, a progress bar in interactive mode
+/// and periodic log lines otherwise
+pub struct TransferProgress {
+    label: String,
+    started: Instant,
+    bar: Option<ProgressBar>,
+    last_log: Cell<Instant>,
+    received_objects: Cell<usize>,
+    received_bytes: Cell<usize>,
+    sideband: RefCell<String>,
+}
+
+impl TransferProgress {
+    pub fn new(label: &str, non_interactive: bool) -> Rc<TransferProgress> {
+        let bar = if non_interactive {
+            println!("{}", label);
+            None
+        } else {
+            let bar = ProgressBar::new(0);
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "{prefix} [{bar:30}] {pos}/{len} objects {msg} ({elapsed})",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+            );
+            bar.set_prefix(label.to_string());
+            Some(bar)
+        };
+        Rc::new(TransferProgress {
+            label: label.to_string(),
+            started: Instant::now(),
+            bar,
+            last_log: Cell::new(Instant::now()),
+            received_objects: Cell::new(0),
+            received_bytes: Cell::new(0),
+            sideband: RefCell::new(String::new()),
+        })
+    }
+
+    /// `transfer_progress` callback, returning false would cancel the transfer
+    pub fn transfer(&self, stats: Progress) -> bool {
+        self.received_objects.set(stats.received_objects());
+        self.received_bytes.set(stats.received_bytes());
+        let receiving = stats.received_objects() < stats.total_objects();
+        match &self.bar {
+            Some(bar) => {
+                bar.set_length(stats.total_objects() as u64);
+                if receiving {
+                    bar.set_position(stats.received_objects() as u64);
+                    bar.set_message(format!(
+                        "received, {}",
+                        HumanBytes(stats.received_bytes() as u64)
+                    ));
+                } else {
+                    bar.set_position(stats.indexed_objects() as u64);
+                    bar.set_message("indexed".to_string());
+                }
+            }
+            None => {
+                if self.last_log.get().elapsed() >= LOG_INTERVAL {
+                    self.last_log.set(Instant::now());
+                    println!(
+                        "{}: {}/{} objects received ({}), {}/{} indexed",
+                        self.label,
+                        stats.received_objects(),
+                        stats.total_objects(),
+                        HumanBytes(stats.received_bytes() as u64),
+                        stats.indexed_objects(),
+                        stats.total_objects()
+                    );
+                }
+            }
+        }
+        true
+    }
+
+    /// `sideband_progress` callback, the messages of the remote such as
+    /// "Counting objects"
+    pub fn sideband(&self, data: &[u8]) -> bool {
+        let mut sideband = self.sideband.borrow_mut();
+        sideband.push_str(&String::from_utf8_lossy(data));
+        // the remote rewrites the current line with `\r` and ends it with `\n`
+        while let Some(end) = sideband.find(['\r', '\n']) {
+            let line = sideband[..end].trim().to_string();
+            let finished = sideband[end..].starts_with('\n');
+            sideband.replace_range(..=end, "");
+            if line.is_empty() {
+                continue;
+            }
+            match &self.bar {
+                Some(bar) => bar.set_message(format!("remote: {}", line)),
+                None if finished => println!("remote: {}", line),
+                None => {}
+            }
+        }
+        true
+    }
+
+    /// clear the progress bar and print the throughput of the transfer
+    pub fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+        let elapsed = self.started.elapsed();
+        let bytes = self.received_bytes.get() as u64;
+        let throughput = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        println!(
+            "{}: {} objects, {} in {} ({}/s)",
+            self.label,
+            self.received_objects.get(),
+            HumanBytes(bytes),
+            HumanDuration(elapsed),
+            HumanBytes(throughput as u64)
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use std::env::var;
+
+use crate::objects::structs::Registry;
+
+/// login of a registry, read from the environment
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegistryCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// the credentials the registry refers to, `None` without a reference
+pub fn registry_credentials(registry: &Registry) -> Result<Option<RegistryCredentials>, String> {
+    registry
+        .credentials
+        .as_deref()
+        .map(environment_credentials)
+        .transpose()
+}
+
+/// the `<prefix>_USERNAME` and `<prefix>_PASSWORD` environment variables
+pub fn environment_credentials(prefix: &str) -> Result<RegistryCredentials, String> {
+    let read = |suffix: &str| {
+        let name = format!("{}_{}", prefix, suffix);
+        var(&name).map_err(|_| format!("{} must be set", name))
+    };
+    Ok(RegistryCredentials {
+        username: read("USERNAME")?,
+        password: read("PASSWORD")?,
+    })
+}
+
+/// whether a tag is a full commit id, the tag every pushed image gets
+pub fn is_commit_tag(tag: &str) -> bool {
+    tag.len() == 40 && tag.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// a branch as an image tag, characters a tag cannot contain become `-`
+pub fn branch_tag(branch: &str) -> String {
+    let tag: String = branch
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => c,
+            _ => '-',
+        })
+        .collect();
+    let tag = tag.trim_start_matches(['.', '-']);
+    tag.chars().take(128).collect()
+}
+
+/// the tags an image built from `commit` of `branch` is pushed as
+pub fn image_tags(commit: &str, branch: &str) -> Vec<String> {
+    let mut tags = vec![commit.to_string()];
+    let branch = branch_tag(branch);
+    if !branch.is_empty() && branch != commit {
+        tags.push(branch);
+    }
+    tags
+}
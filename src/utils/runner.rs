@@ -0,0 +1,250 @@
+use std::{
+    cell::RefCell,
+    io::{BufRead, BufReader, Write},
+    process::{Command, Stdio},
+    rc::Rc,
+};
+
+/// output of a finished command
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// runs the external commands, docker and the proxy reload, so they can be
+/// replaced in tests
+pub trait CommandRunner {
+    fn run(&self, command: &str, args: &[&str]) -> CommandOutput;
+
+    /// run a command with `input` on its standard input, e.g. a password
+    fn run_with_input(&self, command: &str, args: &[&str], input: &str) -> CommandOutput;
+
+    /// run a command with additional environment variables, e.g. secrets
+    fn run_with_env(
+        &self,
+        command: &str,
+        args: &[&str],
+        environment: &[(String, String)],
+    ) -> CommandOutput;
+}
+
+/// runs the commands on the host, streaming their output
+#[derive(Default, Debug, Clone, Copy)]
+pub struct SystemRunner;
+
+impl SystemRunner {
+    fn execute(
+        command: &str,
+        args: &[&str],
+        input: Option<&str>,
+        environment: &[(String, String)],
+    ) -> CommandOutput {
+        let mut child = match Command::new(command)
+            .args(args)
+            .envs(environment.iter().map(|(key, value)| (key, value)))
+            .stdin(match input {
+                Some(_) => Stdio::piped(),
+                None => Stdio::null(), // No input needed
+            })
+            .stdout(Stdio::piped()) // Capture output
+            .stderr(Stdio::piped()) // Capture error output
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("Could not run `{}`: {}", command, e);
+                return CommandOutput::default();
+            }
+        };
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            // dropping stdin closes it, the command sees the end of its input
+            if let Err(e) = stdin.write_all(input.as_bytes()) {
+                eprintln!("Could not write to `{}`: {}", command, e);
+            }
+        }
+        let mut output = CommandOutput::default();
+        if let Some(stdout) = child.stdout.as_mut() {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                println!("{}", line);
+                output.stdout.push_str(&line);
+                output.stdout.push('\n');
+            }
+        }
+        // Read and print the standard error output
+        if let Some(stderr) = child.stderr.as_mut() {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("DEBUG: {}", line);
+                output.stderr.push_str(&line);
+                output.stderr.push('\n');
+            }
+        }
+        output.success = child.wait().is_ok_and(|status| status.success());
+        output
+    }
+}
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, command: &str, args: &[&str]) -> CommandOutput {
+        SystemRunner::execute(command, args, None, &[])
+    }
+
+    fn run_with_input(&self, command: &str, args: &[&str], input: &str) -> CommandOutput {
+        SystemRunner::execute(command, args, Some(input), &[])
+    }
+
+    fn run_with_env(
+        &self,
+        command: &str,
+        args: &[&str],
+        environment: &[(String, String)],
+    ) -> CommandOutput {
+        SystemRunner::execute(command, args, None, environment)
+    }
+}
+
+/// a command and its arguments as one line
+fn command_line(command: &str, args: &[&str]) -> String {
+    std::iter::once(command)
+        .chain(args.iter().copied())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// lines of output kept of a failed command
+const FAILURE_LINES: usize = 20;
+
+/// passes the commands on to another runner and keeps their command lines,
+/// the audit log attributes them to the operation that ran them
+pub struct JournalRunner {
+    runner: Rc<dyn CommandRunner>,
+    journal: RefCell<Vec<String>>,
+    failure: RefCell<Option<String>>,
+}
+
+impl JournalRunner {
+    pub fn new(runner: Rc<dyn CommandRunner>) -> JournalRunner {
+        JournalRunner {
+            runner,
+            journal: RefCell::new(vec![]),
+            failure: RefCell::new(None),
+        }
+    }
+
+    /// the command lines run since the last call
+    pub fn take(&self) -> Vec<String> {
+        self.failure.take();
+        self.journal.take()
+    }
+
+    /// the last lines of output of the latest failed command since `take`
+    pub fn failure(&self) -> Option<String> {
+        self.failure.borrow().clone()
+    }
+
+    fn record(&self, command: &str, args: &[&str], output: CommandOutput) -> CommandOutput {
+        let line = command_line(command, args);
+        if !output.success {
+            let lines: Vec<&str> = output.stdout.lines().chain(output.stderr.lines()).collect();
+            let tail = lines[lines.len().saturating_sub(FAILURE_LINES)..].join("\n");
+            *self.failure.borrow_mut() = Some(format!("$ {}\n{}", line, tail));
+        }
+        self.journal.borrow_mut().push(line);
+        output
+    }
+}
+
+impl CommandRunner for JournalRunner {
+    fn run(&self, command: &str, args: &[&str]) -> CommandOutput {
+        self.record(command, args, self.runner.run(command, args))
+    }
+
+    fn run_with_input(&self, command: &str, args: &[&str], input: &str) -> CommandOutput {
+        let output = self.runner.run_with_input(command, args, input);
+        self.record(command, args, output)
+    }
+
+    fn run_with_env(
+        &self,
+        command: &str,
+        args: &[&str],
+        environment: &[(String, String)],
+    ) -> CommandOutput {
+        let output = self.runner.run_with_env(command, args, environment);
+        self.record(command, args, output)
+    }
+}
+
+/// records the commands instead of running them, every command succeeds
+/// unless it contains one of the configured failures
+#[derive(Default, Debug)]
+pub struct RecordingRunner {
+    invocations: RefCell<Vec<String>>,
+    environment: RefCell<Vec<(String, String)>>,
+    failures: Vec<String>,
+    responses: Vec<(String, String)>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> RecordingRunner {
+        RecordingRunner::default()
+    }
+
+    /// fail every command line containing `fragment`
+    pub fn fail_when(mut self, fragment: &str) -> RecordingRunner {
+        self.failures.push(fragment.to_string());
+        self
+    }
+
+    /// answer every command line containing `fragment` with `stdout`
+    pub fn respond_when(mut self, fragment: &str, stdout: &str) -> RecordingRunner {
+        self.responses
+            .push((fragment.to_string(), stdout.to_string()));
+        self
+    }
+
+    /// the command lines run so far, in order
+    pub fn invocations(&self) -> Vec<String> {
+        self.invocations.borrow().clone()
+    }
+
+    /// the environment variables passed to the commands so far
+    pub fn environment(&self) -> Vec<(String, String)> {
+        self.environment.borrow().clone()
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn run(&self, command: &str, args: &[&str]) -> CommandOutput {
+        let line = command_line(command, args);
+        let success = !self.failures.iter().any(|f| line.contains(f.as_str()));
+        let stdout = self
+            .responses
+            .iter()
+            .find(|(fragment, _)| line.contains(fragment.as_str()))
+            .map(|(_, stdout)| stdout.clone())
+            .unwrap_or_default();
+        self.invocations.borrow_mut().push(line);
+        CommandOutput {
+            success,
+            stdout,
+            stderr: String::new(),
+        }
+    }
+
+    /// the input is not recorded, it usually is a secret
+    fn run_with_input(&self, command: &str, args: &[&str], _input: &str) -> CommandOutput {
+        self.run(command, args)
+    }
+
+    fn run_with_env(
+        &self,
+        command: &str,
+        args: &[&str],
+        environment: &[(String, String)],
+    ) -> CommandOutput {
+        self.environment.borrow_mut().extend_from_slice(environment);
+        self.run(command, args)
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    collections::BTreeMap,
+    fs::{metadata, read_to_string},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+
+use serde::Deserialize;
+
+/// prefix of an environment value taken from a secret, `KEY=secret:NAME`
+pub const SECRET_PREFIX: &str = "secret:";
+
+/// the variable and the secret of an environment entry referring to one
+pub fn secret_reference(entry: &str) -> Option<(&str, &str)> {
+    let (key, value) = entry.split_once('=')?;
+    Some((key, value.strip_prefix(SECRET_PREFIX)?))
+}
+
+/// a secret is a file in the secrets directory, its name may not leave it
+pub fn valid_secret_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// the value of a secret file without its trailing newline
+pub fn read_secret(path: &Path) -> Result<String, String> {
+    let value = read_to_string(path)
+        .map_err(|_| format!("Could not read the secret `{}`", path.display()))?;
+    if metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o077 != 0) {
+        println!(
+            "The secret `{}` is readable by other users, consider `chmod 600`",
+            path.display()
+        );
+    }
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    Ok(value.strip_suffix('\r').unwrap_or(value).to_string())
+}
+
+#[derive(Deserialize)]
+struct SecretEnvironment {
+    #[serde(rename = "x-secret-environment", default)]
+    environment: BTreeMap<String, String>,
+}
+
+/// the environment variables a generated compose file takes from secrets,
+/// with the values read from the secret files
+pub fn secret_environment(compose_file_path: &str) -> Result<Vec<(String, String)>, String> {
+    let contents = read_to_string(compose_file_path)
+        .map_err(|_| format!("Could not read file `{}`", compose_file_path))?;
+    let compose: SecretEnvironment = serde_yaml::from_str(&contents)
+        .map_err(|e| format!("invalid {}: {}", compose_file_path, e))?;
+    let directory = Path::new(compose_file_path)
+        .parent()
+        .unwrap_or(Path::new(""));
+    compose
+        .environment
+        .iter()
+        .map(|(key, file)| Ok((key.clone(), read_secret(&directory.join(file))?)))
+        .collect()
+}
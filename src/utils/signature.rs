@@ -0,0 +1,226 @@
+use std::{
+    fs::write,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use git2::{ObjectType, Oid, Repository};
+use tempfile::tempdir;
+
+use crate::objects::structs::{Application, SignatureVerification};
+
+const PGP_SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----";
+const SSH_SIGNATURE: &str = "-----BEGIN SSH SIGNATURE-----";
+
+/// verify the signature of the commit, or of an annotated tag pointing at it,
+/// against the allowed signers or the keyring of the application
+pub fn verify_revision(
+    repository_path: &str,
+    commit: &str,
+    application: &Application,
+) -> SignatureVerification {
+    let found = Repository::open(Path::new(repository_path))
+        .ok()
+        .zip(Oid::from_str(commit).ok())
+        .filter(|(repo, oid)| repo.find_commit(*oid).is_ok());
+    let (repo, oid) = match found {
+        Some(found) => found,
+        None => {
+            return SignatureVerification {
+                verified: false,
+                object: format!("commit {}", commit),
+                format: None,
+                signer: None,
+                message: format!("commit {} is not in the repository", commit),
+            };
+        }
+    };
+    let mut failure = format!("commit {} is not signed", commit);
+
+    if let Ok((signature, signed_data)) = repo.extract_signature(&oid, None) {
+        match verify_signature(&signature, &signed_data, application) {
+            Ok((format, signer)) => {
+                return SignatureVerification {
+                    verified: true,
+                    object: format!("commit {}", commit),
+                    format: Some(format),
+                    signer: Some(signer),
+                    message: "good signature".to_string(),
+                };
+            }
+            Err(e) => failure = format!("commit {}: {}", commit, e),
+        }
+    }
+
+    // fall back to signed annotated tags of the commit
+    for (name, signature, signed_data) in signed_tags(&repo, oid) {
+        match verify_signature(signature.as_bytes(), signed_data.as_bytes(), application) {
+            Ok((format, signer)) => {
+                return SignatureVerification {
+                    verified: true,
+                    object: format!("tag {}", name),
+                    format: Some(format),
+                    signer: Some(signer),
+                    message: "good signature".to_string(),
+                };
+            }
+            Err(e) => failure = format!("tag {}: {}", name, e),
+        }
+    }
+
+    SignatureVerification {
+        verified: false,
+        object: format!("commit {}", commit),
+        format: None,
+        signer: None,
+        message: failure,
+    }
+}
+
+/// annotated tags pointing at the commit along with their signature and the
+/// signed part of the tag object
+fn signed_tags(repo: &Repository, commit: Oid) -> Vec<(String, String, String)> {
+    let mut tags = vec![];
+    let odb = match repo.odb() {
+        Ok(odb) => odb,
+        Err(_) => return tags,
+    };
+    let references = match repo.references_glob("refs/tags/*") {
+        Ok(references) => references,
+        Err(_) => return tags,
+    };
+    for reference in references.flatten() {
+        let tag = match reference.peel(ObjectType::Tag) {
+            Ok(object) => match object.into_tag() {
+                Ok(tag) => tag,
+                Err(_) => continue,
+            },
+            Err(_) => continue,
+        };
+        if tag.target_id() != commit {
+            continue;
+        }
+        let raw = match odb.read(tag.id()) {
+            Ok(object) => String::from_utf8_lossy(object.data()).to_string(),
+            Err(_) => continue,
+        };
+        // the signature is appended to the tag message
+        let start = match raw.find(PGP_SIGNATURE).or_else(|| raw.find(SSH_SIGNATURE)) {
+            Some(start) => start,
+            None => continue,
+        };
+        tags.push((
+            tag.name().unwrap_or("<unknown>").to_string(),
+            raw[start..].to_string(),
+            raw[..start].to_string(),
+        ));
+    }
+    tags
+}
+
+/// verify a detached signature, returns the signature format and the signer
+fn verify_signature(
+    signature: &[u8],
+    signed_data: &[u8],
+    application: &Application,
+) -> Result<(String, String), String> {
+    let armor = String::from_utf8_lossy(signature);
+    if armor.starts_with(SSH_SIGNATURE) {
+        match &application.allowed_signers {
+            Some(allowed_signers) => verify_ssh(signature, signed_data, allowed_signers)
+                .map(|signer| ("ssh".to_string(), signer)),
+            None => Err("signed with SSH but no `allowed_signers` is configured".to_string()),
+        }
+    } else if armor.starts_with(PGP_SIGNATURE) {
+        match &application.gpg_keyring {
+            Some(keyring) => verify_gpg(signature, signed_data, keyring)
+                .map(|signer| ("gpg".to_string(), signer)),
+            None => Err("signed with GPG but no `gpg_keyring` is configured".to_string()),
+        }
+    } else {
+        Err("unknown signature format".to_string())
+    }
+}
+
+/// run a command feeding the signed data on stdin
+fn run_with_input(command: &mut Command, input: &[u8]) -> Result<String, String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run {:?}: {}", command.get_program(), e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input).map_err(|e| e.to_string())?;
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+fn verify_ssh(
+    signature: &[u8],
+    signed_data: &[u8],
+    allowed_signers: &str,
+) -> Result<String, String> {
+    let directory = tempdir().map_err(|e| e.to_string())?;
+    let signature_path = directory.path().join("signature");
+    write(&signature_path, signature).map_err(|e| e.to_string())?;
+    let principals = run_with_input(
+        Command::new("ssh-keygen")
+            .args(["-Y", "find-principals", "-f", allowed_signers, "-s"])
+            .arg(&signature_path),
+        &[],
+    )
+    .map_err(|_| "the signing key is not in the allowed signers".to_string())?;
+    let principal = match principals.lines().next() {
+        Some(principal) => principal.trim().to_string(),
+        None => return Err("the signing key is not in the allowed signers".to_string()),
+    };
+    run_with_input(
+        Command::new("ssh-keygen")
+            .args(["-Y", "verify", "-n", "git", "-f", allowed_signers, "-I"])
+            .arg(&principal)
+            .arg("-s")
+            .arg(&signature_path),
+        signed_data,
+    )?;
+    Ok(principal)
+}
+
+fn verify_gpg(signature: &[u8], signed_data: &[u8], keyring: &str) -> Result<String, String> {
+    let directory = tempdir().map_err(|e| e.to_string())?;
+    let signature_path = directory.path().join("signature.asc");
+    write(&signature_path, signature).map_err(|e| e.to_string())?;
+    let keyring = Path::new(keyring)
+        .canonicalize()
+        .map_err(|e| format!("could not read keyring {}: {}", keyring, e))?;
+    // a throwaway home keeps the operator's own keys out of the verification
+    let status = run_with_input(
+        Command::new("gpg")
+            .arg("--homedir")
+            .arg(directory.path())
+            .args(["--batch", "--no-default-keyring", "--status-fd", "1"])
+            .arg("--keyring")
+            .arg(&keyring)
+            .arg("--verify")
+            .arg(&signature_path)
+            .arg("-"),
+        signed_data,
+    )?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("[GNUPG:] GOODSIG "))
+        .map(|signer| {
+            // `<key id> <user id>`
+            signer
+                .split_once(' ')
+                .map_or(signer, |(_, user)| user)
+                .to_string()
+        })
+        .ok_or_else(|| "no good signature found".to_string())
+}
@@ -0,0 +1,44 @@
+use std::{
+    fs::{create_dir_all, read_to_string},
+    path::Path,
+};
+
+use crate::{
+    objects::structs::{ApplicationState, DeploymentRecord},
+    utils::file::write_private,
+};
+
+/// load the persisted state of an application, a missing or unreadable state
+/// file is treated as a fresh application
+pub fn load_state(state_directory: &str, slug: &str) -> ApplicationState {
+    let state_path = format!("{}/{}.toml", state_directory, slug);
+    match read_to_string(&state_path) {
+        Ok(contents) => match toml::from_str(&contents) {
+            Ok(state) => state,
+            Err(_) => {
+                eprintln!("Invalid state file `{}`, ignoring it", state_path);
+                ApplicationState::default()
+            }
+        },
+        Err(_) => ApplicationState::default(),
+    }
+}
+
+/// persist the state of an application
+pub fn save_state(state_directory: &str, slug: &str, state: &ApplicationState) {
+    if !Path::new(state_directory).exists() {
+        create_dir_all(state_directory).unwrap();
+    }
+    let state_path = format!("{}/{}.toml", state_directory, slug);
+    let contents = toml::to_string(state).unwrap();
+    if write_private(&state_path, &contents).is_err() {
+        eprintln!("Could not write state file `{}`", state_path);
+    }
+}
+
+/// append a successful deployment to the history of an application
+pub fn record_deployment(state_directory: &str, slug: &str, record: DeploymentRecord) {
+    let mut state = load_state(state_directory, slug);
+    state.deployments.push(record);
+    save_state(state_directory, slug, &state);
+}
@@ -0,0 +1,224 @@
+use std::{
+    fs::{create_dir_all, metadata, write},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    rc::Rc,
+};
+
+use autodeploy::{
+    objects::structs::{Application, AuditEntry, Outcome},
+    pipeline::{DeployError, Deployer},
+    utils::{audit::AuditLog, docker::Runtime, runner::RecordingRunner},
+};
+use git2::{Repository, Signature};
+use tempfile::{TempDir, tempdir};
+
+fn entry(operation: &str, application: &str) -> AuditEntry {
+    AuditEntry {
+        timestamp: "2026-10-19T03:00:00+00:00".to_string(),
+        operation: operation.to_string(),
+        application: Some(application.to_string()),
+        ..AuditEntry::default()
+    }
+}
+
+fn application() -> Application {
+    toml::from_str(
+        r#"
+name = "demo"
+url = "https://example.com/demo.git"
+directory_name = "demo"
+[container]
+name = "demo"
+image = "demo"
+restart_policy = "no"
+ports = []
+[container.build]
+dockerfile = "Dockerfile"
+"#,
+    )
+    .unwrap()
+}
+
+/// a deployer whose compose file of `demo` exists, logging to `audit.log`
+fn deployer(directory: &TempDir, runner: Rc<RecordingRunner>) -> (Deployer, AuditLog) {
+    let compose_directory = directory.path().join("compose_files");
+    create_dir_all(&compose_directory).unwrap();
+    write(compose_directory.join("demo.yaml"), "services: {}\n").unwrap();
+    let audit_log = AuditLog::new(directory.path().join("audit.log").to_str().unwrap());
+    let deployer = Deployer::new("./repositories", "user", "password")
+        .runtime(Runtime::compose(runner))
+        .compose_directory(compose_directory.to_str().unwrap())
+        .audit_log(audit_log.clone());
+    (deployer, audit_log)
+}
+
+#[test]
+fn entries_are_appended_and_filtered() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("logs/audit.log");
+    let audit_log = AuditLog::new(path.to_str().unwrap());
+    audit_log.append(&entry("deploy", "api")).unwrap();
+    audit_log.append(&entry("stop", "web")).unwrap();
+    audit_log.append(&entry("restart", "api")).unwrap();
+
+    let operations = |application| {
+        audit_log
+            .entries(application)
+            .into_iter()
+            .map(|entry| entry.operation)
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(operations(None), vec!["deploy", "stop", "restart"]);
+    assert_eq!(operations(Some("api")), vec!["deploy", "restart"]);
+    assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[test]
+fn full_log_is_rotated() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("audit.log");
+    let line = serde_json::to_string(&entry("deploy", "api"))
+        .unwrap()
+        .len() as u64
+        + 1;
+    // two entries fit into a file, two rotated files are kept
+    let audit_log = AuditLog::new(path.to_str().unwrap()).rotate(2 * line, 2);
+    for application in ["a", "b", "c", "d", "e", "f", "g"] {
+        audit_log.append(&entry("deploy", application)).unwrap();
+    }
+
+    let applications: Vec<String> = audit_log
+        .entries(None)
+        .into_iter()
+        .filter_map(|entry| entry.application)
+        .collect();
+    assert_eq!(applications, vec!["c", "d", "e", "f", "g"]);
+    assert!(Path::new(&format!("{}.2", path.display())).exists());
+    assert!(!Path::new(&format!("{}.3", path.display())).exists());
+}
+
+#[test]
+fn invalid_lines_are_skipped() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("audit.log");
+    let audit_log = AuditLog::new(path.to_str().unwrap());
+    write(&path, "not json\n").unwrap();
+    audit_log.append(&entry("stop", "api")).unwrap();
+    assert_eq!(audit_log.entries(None).len(), 1);
+}
+
+#[test]
+fn operations_are_logged_with_their_commands() {
+    let directory = tempdir().unwrap();
+    let runner = Rc::new(RecordingRunner::new().fail_when("up"));
+    let (deployer, audit_log) = deployer(&directory, runner);
+    let application = application();
+    deployer.stop(&application).unwrap();
+    assert!(deployer.restart(&application).is_err());
+
+    let entries = audit_log.entries(Some("demo"));
+    assert_eq!(entries.len(), 2);
+    let compose_path = directory.path().join("compose_files/demo.yaml");
+    assert_eq!(entries[0].operation, "stop");
+    assert_eq!(entries[0].outcome, Outcome::Success);
+    assert_eq!(
+        entries[0].commands,
+        vec![format!(
+            "docker compose -f {} -p demo down",
+            compose_path.display()
+        )]
+    );
+    assert_eq!(entries[1].operation, "restart");
+    assert_eq!(entries[1].outcome, Outcome::Failure);
+    assert_eq!(entries[1].commands.len(), 2);
+    assert_eq!(entries[1].error.as_deref(), Some("Restarting demo failed"));
+    assert!(!entries[1].operator.user.is_empty());
+    assert!(!entries[1].operator.hostname.is_empty());
+}
+
+#[test]
+fn dry_runs_are_not_logged() {
+    let directory = tempdir().unwrap();
+    let (deployer, audit_log) = deployer(&directory, Rc::new(RecordingRunner::new()));
+    deployer.dry_run(true).stop(&application()).unwrap();
+    assert!(audit_log.entries(None).is_empty());
+}
+
+#[test]
+fn git_operations_are_logged() {
+    let directory = tempdir().unwrap();
+    let origin = Repository::init(directory.path().join("origin")).unwrap();
+    let root = origin.workdir().unwrap();
+    write(root.join("Dockerfile"), "FROM scratch\n").unwrap();
+    let mut index = origin.index().unwrap();
+    index.add_path(Path::new("Dockerfile")).unwrap();
+    let tree = origin.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("autodeploy", "autodeploy@example.com").unwrap();
+    let commit = origin
+        .commit(Some("HEAD"), &signature, &signature, "initial", &tree, &[])
+        .unwrap()
+        .to_string();
+    let branch = origin.head().unwrap().shorthand().unwrap().to_string();
+
+    let mut application = application();
+    application.repository_url = root.display().to_string();
+    let (deployer, audit_log) = deployer(&directory, Rc::new(RecordingRunner::new()));
+    let mut deployer = deployer
+        .state_directory(directory.path().join("state").to_str().unwrap())
+        .non_interactive(true);
+    deployer.repository_root = directory.path().join("repositories").display().to_string();
+    deployer.pipeline(&application).run(&branch).unwrap();
+
+    let entries = audit_log.entries(Some("demo"));
+    let repository_path = directory.path().join("repositories/demo");
+    assert_eq!(
+        entries[0].git,
+        vec![
+            format!(
+                "clone {} into {}",
+                root.display(),
+                repository_path.display()
+            ),
+            format!("checkout origin/{} at {}", branch, commit),
+        ]
+    );
+}
+
+#[test]
+fn aborted_deployments_are_logged() {
+    let directory = tempdir().unwrap();
+    let (deployer, audit_log) = deployer(&directory, Rc::new(RecordingRunner::new()));
+    let application = application();
+    let cancelled = DeployError::Cancelled("No branch was selected".to_string());
+    assert_eq!(
+        deployer.pipeline(&application).abort(cancelled.clone()),
+        cancelled
+    );
+    deployer
+        .pipeline(&application)
+        .abort(DeployError::MissingBranch);
+
+    let entries = audit_log.entries(Some("demo"));
+    let outcomes: Vec<(&str, Outcome, Option<&str>)> = entries
+        .iter()
+        .map(|entry| {
+            (
+                entry.operation.as_str(),
+                entry.outcome,
+                entry.error.as_deref(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        outcomes,
+        vec![
+            ("deploy", Outcome::Cancelled, Some("No branch was selected")),
+            (
+                "deploy",
+                Outcome::Failure,
+                Some("A branch is required in non-interactive mode")
+            ),
+        ]
+    );
+}
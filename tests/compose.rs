@@ -0,0 +1,1150 @@
+use std::{
+    collections::BTreeMap,
+    env::join_paths,
+    fs::{
+        Permissions, create_dir_all, metadata, read_to_string, remove_file, set_permissions, write,
+    },
+    io::{Read, Write},
+    net::TcpListener,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    rc::Rc,
+    thread,
+};
+
+use autodeploy::{
+    objects::structs::{Application, BuildSecret, Engine, Registry},
+    pipeline::{DeployError, Deployer, Removal},
+    utils::{
+        bluegreen::switch_color,
+        docker::{
+            BuildFlags, Runtime, build_compose, detect_engine, generate_compose, parse_size,
+            restart_compose, start_compose, stop_compose,
+        },
+        file::relative_path,
+        registry::{RegistryCredentials, branch_tag, image_tags, registry_credentials},
+        runner::RecordingRunner,
+        state::load_state,
+    },
+};
+use tempfile::{TempDir, tempdir};
+
+fn application(extra: &str) -> Application {
+    toml::from_str(&format!(
+        r#"
+name = "demo"
+url = "https://example.com/demo.git"
+directory_name = "demo"
+{}
+[container]
+name = "demo"
+image = "demo"
+restart_policy = "no"
+ports = ["8080:8080"]
+[container.build]
+dockerfile = "Dockerfile"
+"#,
+        extra
+    ))
+    .unwrap()
+}
+
+/// a compose file has to exist for the compose commands to run
+fn compose_file(directory: &TempDir, name: &str) -> String {
+    let compose_directory = directory.path().join("compose_files");
+    create_dir_all(&compose_directory).unwrap();
+    let path = compose_directory.join(format!("{}.yaml", name));
+    write(&path, "services: {}\n").unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// answers every request with 200 until the test ends
+fn health_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut buffer = [0; 512];
+            let _ = stream.read(&mut buffer);
+            let _ = stream.write_all(b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n");
+        }
+    });
+    port
+}
+
+fn blue_green(directory: &TempDir, port: u16, reload: &str) -> Application {
+    let proxy_config = directory.path().join("upstream.conf");
+    application(&format!(
+        r#"
+[blue_green]
+container_port = 8080
+blue_port = {port}
+green_port = {port}
+health_timeout = 0
+proxy = "nginx"
+proxy_config = "{}"
+proxy_reload = "{reload}"
+"#,
+        proxy_config.display()
+    ))
+}
+
+#[test]
+fn build_runs_compose_build() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new();
+    assert!(build_compose(
+        &runner,
+        Engine::Docker,
+        &path,
+        BuildFlags::default(),
+        false
+    ));
+    assert_eq!(
+        runner.invocations(),
+        vec![format!("docker compose -f {} build", path)]
+    );
+}
+
+#[test]
+fn start_runs_compose_up_detached() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new();
+    assert!(start_compose(&runner, Engine::Docker, &path, "demo", false));
+    assert_eq!(
+        runner.invocations(),
+        vec![format!("docker compose -f {} -p demo up -d", path)]
+    );
+}
+
+#[test]
+fn stop_runs_compose_down() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new();
+    assert!(stop_compose(&runner, Engine::Docker, &path, "demo", false));
+    assert_eq!(
+        runner.invocations(),
+        vec![format!("docker compose -f {} -p demo down", path)]
+    );
+}
+
+#[test]
+fn restart_stops_before_starting() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new();
+    assert!(restart_compose(
+        &runner,
+        Engine::Docker,
+        &path,
+        "demo",
+        false
+    ));
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            format!("docker compose -f {} -p demo down", path),
+            format!("docker compose -f {} -p demo up -d", path),
+        ]
+    );
+}
+
+#[test]
+fn restart_does_not_start_when_stopping_fails() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new().fail_when(" down");
+    assert!(!restart_compose(
+        &runner,
+        Engine::Docker,
+        &path,
+        "demo",
+        false
+    ));
+    assert_eq!(
+        runner.invocations(),
+        vec![format!("docker compose -f {} -p demo down", path)]
+    );
+}
+
+#[test]
+fn failed_command_is_reported() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new().fail_when(" build");
+    assert!(!build_compose(
+        &runner,
+        Engine::Docker,
+        &path,
+        BuildFlags::default(),
+        false
+    ));
+}
+
+#[test]
+fn missing_compose_file_runs_nothing() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("missing.yaml");
+    let runner = RecordingRunner::new();
+    assert!(!start_compose(
+        &runner,
+        Engine::Docker,
+        path.to_str().unwrap(),
+        "demo",
+        false
+    ));
+    assert!(runner.invocations().is_empty());
+}
+
+#[test]
+fn dry_run_runs_nothing() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("missing.yaml");
+    let runner = RecordingRunner::new();
+    assert!(build_compose(
+        &runner,
+        Engine::Docker,
+        path.to_str().unwrap(),
+        BuildFlags::default(),
+        true
+    ));
+    assert!(restart_compose(
+        &runner,
+        Engine::Docker,
+        path.to_str().unwrap(),
+        "demo",
+        true
+    ));
+    assert!(runner.invocations().is_empty());
+}
+
+#[test]
+fn podman_runs_podman_compose_subcommand() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new();
+    assert!(restart_compose(
+        &runner,
+        Engine::Podman,
+        &path,
+        "demo",
+        false
+    ));
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            format!("podman compose -f {} -p demo down", path),
+            format!("podman compose -f {} -p demo up -d", path),
+        ]
+    );
+}
+
+#[test]
+fn podman_compose_runs_the_standalone_binary() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new();
+    assert!(build_compose(
+        &runner,
+        Engine::PodmanCompose,
+        &path,
+        BuildFlags::default(),
+        false
+    ));
+    assert!(start_compose(
+        &runner,
+        Engine::PodmanCompose,
+        &path,
+        "demo",
+        false
+    ));
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            format!("podman-compose -f {} build", path),
+            format!("podman-compose -f {} -p demo up -d", path),
+        ]
+    );
+}
+
+#[test]
+fn nerdctl_runs_nerdctl_compose() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new();
+    assert!(stop_compose(&runner, Engine::Nerdctl, &path, "demo", false));
+    assert_eq!(
+        runner.invocations(),
+        vec![format!("nerdctl compose -f {} -p demo down", path)]
+    );
+}
+
+#[test]
+fn runtime_uses_the_configured_engine() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = Rc::new(RecordingRunner::new());
+    let runtime = Runtime::compose(runner.clone()).engine(Engine::Nerdctl);
+    assert!(runtime.build(&path, BuildFlags::default(), false));
+    assert_eq!(
+        runner.invocations(),
+        vec![format!("nerdctl compose -f {} build", path)]
+    );
+}
+
+#[test]
+fn build_cache_pruning_follows_the_engine() {
+    let pruned = |engine: Engine, keep_storage: u64| {
+        let runner = Rc::new(RecordingRunner::new());
+        let runtime = Runtime::compose(runner.clone()).engine(engine);
+        let pruned = runtime.prune_build_cache(keep_storage, false);
+        (pruned, runner.invocations())
+    };
+    assert_eq!(
+        pruned(Engine::Docker, 1 << 30),
+        (
+            true,
+            vec!["docker builder prune --force --keep-storage 1073741824".to_string()]
+        )
+    );
+    assert_eq!(
+        pruned(Engine::Docker, 0),
+        (
+            true,
+            vec!["docker builder prune --force --keep-storage 0".to_string()]
+        )
+    );
+    assert_eq!(
+        pruned(Engine::Podman, 0),
+        (
+            true,
+            vec!["podman image prune --build-cache --force".to_string()]
+        )
+    );
+    assert_eq!(
+        pruned(Engine::PodmanCompose, 0),
+        (
+            true,
+            vec!["podman image prune --build-cache --force".to_string()]
+        )
+    );
+    assert_eq!(
+        pruned(Engine::Nerdctl, 0),
+        (true, vec!["nerdctl builder prune --force".to_string()])
+    );
+}
+
+#[test]
+fn engines_without_a_size_threshold_keep_the_build_cache() {
+    for engine in [Engine::Podman, Engine::PodmanCompose, Engine::Nerdctl] {
+        let runner = Rc::new(RecordingRunner::new());
+        let runtime = Runtime::compose(runner.clone()).engine(engine);
+        assert!(!runtime.prune_build_cache(1 << 30, false));
+        assert!(runner.invocations().is_empty());
+    }
+}
+
+#[test]
+fn push_logs_in_and_pushes_every_tag() {
+    let runner = Rc::new(RecordingRunner::new());
+    let runtime = Runtime::compose(runner.clone());
+    let credentials = RegistryCredentials {
+        username: "deploy".to_string(),
+        password: "secret".to_string(),
+    };
+    assert!(runtime.push_image(
+        "demo:latest",
+        "registry.example.com/team/demo",
+        &image_tags("abc123", "feature/login"),
+        Some(&credentials),
+        false
+    ));
+    assert!(runtime.pull_image("registry.example.com/team/demo", "abc123", None, false));
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            "docker login registry.example.com --username deploy --password-stdin",
+            "docker tag demo:latest registry.example.com/team/demo:abc123",
+            "docker push registry.example.com/team/demo:abc123",
+            "docker tag demo:latest registry.example.com/team/demo:feature-login",
+            "docker push registry.example.com/team/demo:feature-login",
+            "docker pull registry.example.com/team/demo:abc123",
+        ]
+    );
+}
+
+#[test]
+fn failed_push_stops_the_remaining_tags() {
+    let runner = Rc::new(RecordingRunner::new().fail_when("push"));
+    let runtime = Runtime::compose(runner.clone());
+    assert!(!runtime.push_image(
+        "demo:latest",
+        "team/demo",
+        &image_tags("abc123", "main"),
+        None,
+        false
+    ));
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            "docker tag demo:latest team/demo:abc123",
+            "docker push team/demo:abc123",
+        ]
+    );
+}
+
+#[test]
+fn branches_become_valid_tags() {
+    assert_eq!(branch_tag("main"), "main");
+    assert_eq!(branch_tag("feature/login"), "feature-login");
+    assert_eq!(branch_tag("-release/1.0"), "release-1.0");
+    assert_eq!(branch_tag(&"a".repeat(200)).len(), 128);
+    assert_eq!(image_tags("abc123", "/"), vec!["abc123"]);
+}
+
+#[test]
+fn registry_credentials_come_from_the_environment() {
+    let mut registry = Registry {
+        url: "https://registry.example.com".to_string(),
+        repository: "team/demo".to_string(),
+        credentials: None,
+    };
+    assert_eq!(registry.image(), "registry.example.com/team/demo");
+    assert_eq!(registry_credentials(&registry), Ok(None));
+
+    registry.credentials = Some("AUTODEPLOY_TEST_REGISTRY_MISSING".to_string());
+    assert_eq!(
+        registry_credentials(&registry),
+        Err("AUTODEPLOY_TEST_REGISTRY_MISSING_USERNAME must be set".to_string())
+    );
+}
+
+#[test]
+fn compose_file_without_a_dockerfile_has_no_build() {
+    let directory = tempdir().unwrap();
+    let mut application = application("");
+    application.container.build.dockerfile = String::new();
+    let path = generate_compose(
+        "./repositories/demo",
+        directory.path().to_str().unwrap(),
+        "./secrets",
+        "demo",
+        &application.container,
+        false,
+    );
+    assert!(!read_to_string(path).unwrap().contains("build"));
+}
+
+/// a compose file of an application with a secret variable and a secret
+/// file, the secrets are written to `secrets/`
+fn secret_compose_file(directory: &TempDir) -> String {
+    let secrets_directory = directory.path().join("secrets");
+    create_dir_all(&secrets_directory).unwrap();
+    write(secrets_directory.join("db_password"), "hunter2\n").unwrap();
+    let mut application = application("");
+    application.container.environment = Some(vec![
+        "MODE=production".to_string(),
+        "DB_PASSWORD=secret:db_password".to_string(),
+    ]);
+    application.container.secrets = Some(vec!["tls_key".to_string()]);
+    generate_compose(
+        "./repositories/demo",
+        directory.path().join("compose_files").to_str().unwrap(),
+        secrets_directory.to_str().unwrap(),
+        "demo",
+        &application.container,
+        false,
+    )
+}
+
+#[test]
+fn secrets_are_not_written_to_the_compose_file() {
+    let directory = tempdir().unwrap();
+    let path = secret_compose_file(&directory);
+    let contents = read_to_string(&path).unwrap();
+    assert!(!contents.contains("hunter2"));
+    let compose: serde_yaml::Value = serde_yaml::from_str(&contents).unwrap();
+    assert_eq!(
+        compose["services"]["app"]["environment"],
+        serde_yaml::from_str::<serde_yaml::Value>("[MODE=production, DB_PASSWORD]").unwrap()
+    );
+    assert_eq!(compose["services"]["app"]["secrets"][0], "tls_key");
+    assert_eq!(compose["secrets"]["tls_key"]["file"], "../secrets/tls_key");
+    assert_eq!(
+        compose["x-secret-environment"]["DB_PASSWORD"],
+        "../secrets/db_password"
+    );
+    let mode = metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn secrets_are_passed_to_up() {
+    let directory = tempdir().unwrap();
+    let path = secret_compose_file(&directory);
+    let runner = Rc::new(RecordingRunner::new());
+    let runtime = Runtime::compose(runner.clone());
+    assert!(runtime.start(&path, "demo", false));
+    assert_eq!(
+        runner.invocations(),
+        vec![format!("docker compose -f {} -p demo up -d", path)]
+    );
+    assert_eq!(
+        runner.environment(),
+        vec![("DB_PASSWORD".to_string(), "hunter2".to_string())]
+    );
+}
+
+#[test]
+fn missing_secret_does_not_start() {
+    let directory = tempdir().unwrap();
+    let path = secret_compose_file(&directory);
+    remove_file(directory.path().join("secrets/db_password")).unwrap();
+    let runner = Rc::new(RecordingRunner::new());
+    let runtime = Runtime::compose(runner.clone());
+    assert!(!runtime.start(&path, "demo", false));
+    assert!(runner.invocations().is_empty());
+}
+
+/// a directory with an executable for every given binary
+fn bin_directory(binaries: &[&str]) -> TempDir {
+    let directory = tempdir().unwrap();
+    for binary in binaries {
+        let path = directory.path().join(binary);
+        write(&path, "#!/bin/sh\n").unwrap();
+        set_permissions(&path, Permissions::from_mode(0o755)).unwrap();
+    }
+    directory
+}
+
+#[test]
+fn detection_prefers_docker() {
+    let first = bin_directory(&["podman"]);
+    let second = bin_directory(&["docker"]);
+    let path = join_paths([first.path(), second.path()]).unwrap();
+    assert_eq!(detect_engine(&path), Some(Engine::Docker));
+}
+
+#[test]
+fn detection_finds_rootless_engines() {
+    let podman = bin_directory(&["podman-compose", "podman"]);
+    let path = join_paths([podman.path()]).unwrap();
+    assert_eq!(detect_engine(&path), Some(Engine::Podman));
+
+    let podman_compose = bin_directory(&["podman-compose"]);
+    let path = join_paths([podman_compose.path()]).unwrap();
+    assert_eq!(detect_engine(&path), Some(Engine::PodmanCompose));
+
+    let nerdctl = bin_directory(&["nerdctl"]);
+    let path = join_paths([nerdctl.path()]).unwrap();
+    assert_eq!(detect_engine(&path), Some(Engine::Nerdctl));
+}
+
+#[test]
+fn detection_ignores_files_that_are_not_executable() {
+    let directory = tempdir().unwrap();
+    write(directory.path().join("docker"), "").unwrap();
+    let path = join_paths([directory.path()]).unwrap();
+    assert_eq!(detect_engine(&path), None);
+}
+
+#[test]
+fn deployer_restarts_and_stops_the_application() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = Rc::new(RecordingRunner::new());
+    let mut deployer = Deployer::new("./repositories", "user", "password")
+        .runtime(Runtime::compose(runner.clone()));
+    deployer.compose_directory = directory
+        .path()
+        .join("compose_files")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let application = application("");
+    deployer.restart(&application).unwrap();
+    deployer.stop(&application).unwrap();
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            format!("docker compose -f {} -p demo down", path),
+            format!("docker compose -f {} -p demo up -d", path),
+            format!("docker compose -f {} -p demo down", path),
+        ]
+    );
+}
+
+#[test]
+fn deployer_reports_failed_stop() {
+    let directory = tempdir().unwrap();
+    compose_file(&directory, "demo");
+    let runner = Rc::new(RecordingRunner::new().fail_when(" down"));
+    let mut deployer =
+        Deployer::new("./repositories", "user", "password").runtime(Runtime::compose(runner));
+    deployer.compose_directory = directory
+        .path()
+        .join("compose_files")
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(
+        deployer.stop(&application("")),
+        Err(DeployError::Stop("demo".to_string()))
+    );
+}
+
+#[test]
+fn deployer_removes_the_application_and_its_files() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let repositories = directory.path().join("repositories");
+    create_dir_all(repositories.join("demo")).unwrap();
+    write(repositories.join("demo/Dockerfile"), "FROM scratch\n").unwrap();
+    let runner =
+        Rc::new(RecordingRunner::new().respond_when("image inspect", "sha256:demo 1024\n"));
+    let mut deployer = Deployer::new(repositories.to_str().unwrap(), "user", "password")
+        .runtime(Runtime::compose(runner.clone()));
+    deployer.compose_directory = directory
+        .path()
+        .join("compose_files")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let removal = Removal {
+        volumes: true,
+        images: true,
+        compose_file: true,
+        clone: true,
+        state: false,
+    };
+
+    let summary = deployer.remove(&application(""), removal).unwrap();
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            "docker image inspect --format {{.Id}} {{.Size}} demo".to_string(),
+            format!(
+                "docker compose -f {} -p demo down --volumes --rmi all",
+                path
+            ),
+        ]
+    );
+    assert_eq!(summary.reclaimed(), 1024 + 13 + 13);
+    assert!(!Path::new(&path).exists());
+    assert!(!repositories.join("demo").exists());
+}
+
+#[test]
+fn dry_run_removal_keeps_the_files() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = Rc::new(RecordingRunner::new());
+    let mut deployer = Deployer::new("./repositories", "user", "password")
+        .runtime(Runtime::compose(runner.clone()))
+        .dry_run(true);
+    deployer.compose_directory = directory
+        .path()
+        .join("compose_files")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let removal = Removal {
+        compose_file: true,
+        ..Removal::default()
+    };
+
+    let summary = deployer.remove(&application(""), removal).unwrap();
+    assert!(runner.invocations().is_empty());
+    assert_eq!(summary.removed, vec![(path.clone(), Some(13))]);
+    assert!(Path::new(&path).exists());
+}
+
+#[test]
+fn prune_keeps_the_images_of_the_latest_deployments() {
+    let directory = tempdir().unwrap();
+    let state_directory = directory.path().join("state");
+    create_dir_all(&state_directory).unwrap();
+    let deployment = |commit: &str, image: &str| {
+        format!(
+            "[[deployments]]\ntimestamp = \"2026-01-01T00:00:00Z\"\nbranch = \"origin/main\"\ncommit = \"{}\"\nimage = \"{}\"\n",
+            commit, image
+        )
+    };
+    write(
+        state_directory.join("demo.toml"),
+        [
+            deployment("a", "sha256:1"),
+            deployment("b", "sha256:2"),
+            deployment("c", "sha256:2"),
+            deployment("d", "sha256:3"),
+        ]
+        .concat(),
+    )
+    .unwrap();
+    let runner = Rc::new(
+        RecordingRunner::new()
+            .respond_when(
+                "inspect --format {{.Id}} {{.Size}} sha256:1",
+                "sha256:1 100\n",
+            )
+            .respond_when(
+                "inspect --format {{.Id}} {{.Size}} sha256:2",
+                "sha256:2 200\n",
+            ),
+    );
+    let deployer = Deployer::new("./repositories", "user", "password")
+        .runtime(Runtime::compose(runner.clone()))
+        .state_directory(state_directory.to_str().unwrap());
+
+    let summary = deployer.prune(&application("keep_images = 1"));
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            "docker image inspect --format {{.Id}} {{.Size}} sha256:2",
+            "docker image rm sha256:2",
+            "docker image inspect --format {{.Id}} {{.Size}} sha256:1",
+            "docker image rm sha256:1",
+        ]
+    );
+    assert_eq!(summary.reclaimed(), 300);
+    let state = load_state(state_directory.to_str().unwrap(), "demo");
+    let images: Vec<Option<&str>> = state
+        .deployments
+        .iter()
+        .map(|record| record.image.as_deref())
+        .collect();
+    assert_eq!(images, vec![None, None, None, Some("sha256:3")]);
+}
+
+#[test]
+fn sizes_are_parsed_in_powers_of_1024() {
+    assert_eq!(parse_size("0"), Ok(0));
+    assert_eq!(parse_size("512MB"), Ok(512 << 20));
+    assert_eq!(parse_size("1.5 GB"), Ok(3 << 29));
+    assert!(parse_size("10 parsecs").is_err());
+}
+
+#[test]
+fn blue_green_alternates_colors() {
+    let directory = tempdir().unwrap();
+    let compose_directory = directory.path().join("compose_files");
+    let compose_directory = compose_directory.to_str().unwrap();
+    let state_directory = directory.path().join("state");
+    let state_directory = state_directory.to_str().unwrap();
+    let application = blue_green(&directory, health_server(), "nginx -s reload");
+    let secrets_directory = directory.path().join("secrets").display().to_string();
+    let runner = Rc::new(RecordingRunner::new());
+    let runtime = Runtime::compose(runner.clone());
+
+    assert!(switch_color(
+        &runtime,
+        "./repositories",
+        compose_directory,
+        &secrets_directory,
+        state_directory,
+        &application,
+        false
+    ));
+    assert!(switch_color(
+        &runtime,
+        "./repositories",
+        compose_directory,
+        &secrets_directory,
+        state_directory,
+        &application,
+        false
+    ));
+    let blue = format!("{}/demo-blue.yaml", compose_directory);
+    let green = format!("{}/demo-green.yaml", compose_directory);
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            format!("docker compose -f {} -p demo-blue up -d", blue),
+            "nginx -s reload".to_string(),
+            format!("docker compose -f {} -p demo-green up -d", green),
+            "nginx -s reload".to_string(),
+            format!("docker compose -f {} -p demo-blue down", blue),
+        ]
+    );
+    let upstream = read_to_string(directory.path().join("upstream.conf")).unwrap();
+    assert!(upstream.contains("active color: green"));
+    assert_eq!(
+        load_state(state_directory, "demo")
+            .active_color
+            .map(|c| c.to_string()),
+        Some("green".to_string())
+    );
+}
+
+#[test]
+fn blue_green_keeps_the_active_color_when_the_proxy_reload_fails() {
+    let directory = tempdir().unwrap();
+    let compose_directory = directory.path().join("compose_files");
+    let compose_directory = compose_directory.to_str().unwrap();
+    let state_directory = directory.path().join("state");
+    let state_directory = state_directory.to_str().unwrap();
+    let application = blue_green(&directory, health_server(), "nginx -s reload");
+    let secrets_directory = directory.path().join("secrets").display().to_string();
+    let runner = Rc::new(RecordingRunner::new().fail_when("nginx"));
+    let runtime = Runtime::compose(runner.clone());
+    let upstream = directory.path().join("upstream.conf");
+    write(
+        &upstream,
+        "upstream demo {\n    server 127.0.0.1:9000;\n}\n",
+    )
+    .unwrap();
+
+    assert!(!switch_color(
+        &runtime,
+        "./repositories",
+        compose_directory,
+        &secrets_directory,
+        state_directory,
+        &application,
+        false
+    ));
+    let blue = format!("{}/demo-blue.yaml", compose_directory);
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            format!("docker compose -f {} -p demo-blue up -d", blue),
+            "nginx -s reload".to_string(),
+            format!("docker compose -f {} -p demo-blue down", blue),
+        ]
+    );
+    assert!(!Path::new(state_directory).join("demo.toml").exists());
+    // the proxy keeps pointing at the running deployment
+    assert_eq!(
+        read_to_string(&upstream).unwrap(),
+        "upstream demo {\n    server 127.0.0.1:9000;\n}\n"
+    );
+}
+
+/// the build context written to a generated compose file
+fn generated_context(build_directory: &str, compose_directory: &str) -> String {
+    let path = generate_compose(
+        build_directory,
+        compose_directory,
+        "./secrets",
+        "demo",
+        &application("").container,
+        false,
+    );
+    let compose: serde_yaml::Value = serde_yaml::from_str(&read_to_string(path).unwrap()).unwrap();
+    compose["services"]["app"]["build"]["context"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn build_context_is_relative_to_the_compose_file() {
+    let directory = tempdir().unwrap();
+    let root = directory.path().display();
+    assert_eq!(
+        generated_context(
+            &format!("{}/repos/demo", root),
+            &format!("{}/compose_files", root)
+        ),
+        "../repos/demo"
+    );
+    assert_eq!(
+        generated_context(
+            &format!("{}/srv/repos/demo", root),
+            &format!("{}/etc/autodeploy/compose_files", root)
+        ),
+        "../../../srv/repos/demo"
+    );
+    assert_eq!(
+        generated_context(
+            &format!("{}/compose_files/../repos/./demo", root),
+            &format!("{}/compose_files", root)
+        ),
+        "../repos/demo"
+    );
+}
+
+#[test]
+fn one_off_build_flags_are_passed_to_compose() {
+    let directory = tempdir().unwrap();
+    let path = compose_file(&directory, "demo");
+    let runner = RecordingRunner::new();
+    let flags = BuildFlags {
+        no_cache: true,
+        pull: true,
+    };
+    assert!(build_compose(&runner, Engine::Docker, &path, flags, false));
+    assert_eq!(
+        runner.invocations(),
+        vec![format!(
+            "docker compose -f {} build --no-cache --pull",
+            path
+        )]
+    );
+}
+
+#[test]
+fn build_options_are_written_to_the_build_section() {
+    let directory = tempdir().unwrap();
+    let compose_directory = directory.path().join("compose_files");
+    let secret = directory.path().join("secrets/npmrc");
+    let mut application = application("");
+    let build = &mut application.container.build;
+    build.target = Some("runtime".to_string());
+    build.platform = Some("linux/arm64".to_string());
+    build.no_cache = true;
+    build.cache_from = Some(vec![
+        "type=registry,ref=registry.example.com/demo:cache".to_string(),
+    ]);
+    build.cache_to = Some(vec!["type=inline".to_string()]);
+    build.secrets = Some(BTreeMap::from([
+        (
+            "npmrc".to_string(),
+            BuildSecret {
+                file: Some(secret.display().to_string()),
+                environment: None,
+            },
+        ),
+        (
+            "token".to_string(),
+            BuildSecret {
+                file: None,
+                environment: Some("NPM_TOKEN".to_string()),
+            },
+        ),
+    ]));
+    let path = generate_compose(
+        &format!("{}/repos/demo", directory.path().display()),
+        compose_directory.to_str().unwrap(),
+        "./secrets",
+        "demo",
+        &application.container,
+        false,
+    );
+    let compose: serde_yaml::Value = serde_yaml::from_str(&read_to_string(path).unwrap()).unwrap();
+    let build = &compose["services"]["app"]["build"];
+    assert_eq!(build["target"], "runtime");
+    assert_eq!(build["platforms"][0], "linux/arm64");
+    assert_eq!(build["no_cache"], true);
+    assert!(build["pull"].is_null());
+    assert_eq!(
+        build["cache_from"][0],
+        "type=registry,ref=registry.example.com/demo:cache"
+    );
+    assert_eq!(build["cache_to"][0], "type=inline");
+    assert_eq!(build["secrets"][0], "npmrc");
+    assert_eq!(build["secrets"][1], "token");
+    assert_eq!(compose["secrets"]["npmrc"]["file"], "../secrets/npmrc");
+    assert_eq!(compose["secrets"]["token"]["environment"], "NPM_TOKEN");
+}
+
+#[test]
+fn build_directory_includes_the_context_subdirectory() {
+    let mut application = application("");
+    assert_eq!(application.build_directory("/srv/repos"), "/srv/repos/demo");
+    application.context = Some("services/api/".to_string());
+    assert_eq!(
+        application.build_directory("/srv/repos"),
+        "/srv/repos/demo/services/api"
+    );
+    application.clone_directory = Some("platform".to_string());
+    assert_eq!(
+        application.build_directory("/srv/repos"),
+        "/srv/repos/platform/services/api"
+    );
+}
+
+#[test]
+fn relative_paths_between_directories() {
+    assert_eq!(
+        relative_path(Path::new("/srv/compose"), Path::new("/srv/repos/demo")),
+        Path::new("../repos/demo")
+    );
+    assert_eq!(
+        relative_path(Path::new("/srv"), Path::new("/srv/repos")),
+        Path::new("repos")
+    );
+    assert_eq!(
+        relative_path(Path::new("/srv/repos"), Path::new("/srv/repos")),
+        Path::new(".")
+    );
+}
+
+fn registry_deployer(directory: &TempDir, runner: Rc<RecordingRunner>) -> Deployer {
+    let path = |name: &str| directory.path().join(name).display().to_string();
+    Deployer::new(&path("repositories"), "user", "password")
+        .runtime(Runtime::compose(runner))
+        .compose_directory(&path("compose_files"))
+        .state_directory(&path("state"))
+}
+
+#[test]
+fn registry_deployments_record_the_image_apart_from_the_commit() {
+    let directory = tempdir().unwrap();
+    let application =
+        application("[registry]\nurl = \"registry.example.com\"\nrepository = \"team/demo\"\n");
+    let deployer = registry_deployer(&directory, Rc::new(RecordingRunner::new()));
+    let commit = "0123456789abcdef0123456789abcdef01234567";
+    deployer
+        .pipeline(&application)
+        .deploy_from_registry("feature-login")
+        .unwrap();
+    deployer
+        .pipeline(&application)
+        .deploy_from_registry(commit)
+        .unwrap();
+
+    let state_directory = directory.path().join("state");
+    let deployments = load_state(state_directory.to_str().unwrap(), "demo").deployments;
+    assert_eq!(deployments[0].branch, "feature-login");
+    assert_eq!(deployments[0].commit, "");
+    assert_eq!(
+        deployments[0].reference.as_deref(),
+        Some("registry.example.com/team/demo:feature-login")
+    );
+    assert_eq!(deployments[1].commit, commit);
+    assert_eq!(
+        deployments[1].reference.as_deref(),
+        Some(format!("registry.example.com/team/demo:{}", commit).as_str())
+    );
+}
+
+#[test]
+fn unsigned_registry_tags_are_refused() {
+    let directory = tempdir().unwrap();
+    let application = application(
+        "require_signed = true\n[registry]\nurl = \"registry.example.com\"\nrepository = \"team/demo\"\n",
+    );
+    let runner = Rc::new(RecordingRunner::new());
+    let deployer = registry_deployer(&directory, runner.clone());
+    assert!(matches!(
+        deployer.pipeline(&application).deploy_from_registry("main"),
+        Err(DeployError::Unverified(_))
+    ));
+    // the commit is not in the repository, its signature cannot be checked
+    assert!(matches!(
+        deployer
+            .pipeline(&application)
+            .deploy_from_registry("0123456789abcdef0123456789abcdef01234567"),
+        Err(DeployError::Unverified(_))
+    ));
+    assert!(runner.invocations().is_empty());
+}
+
+#[test]
+fn blue_green_restart_runs_the_image_deployed_from_the_registry() {
+    let directory = tempdir().unwrap();
+    let application = blue_green(&directory, health_server(), "nginx -s reload");
+    let state_directory = directory.path().join("state");
+    create_dir_all(&state_directory).unwrap();
+    write(
+        state_directory.join("demo.toml"),
+        "[[deployments]]\ntimestamp = \"2026-01-01T00:00:00Z\"\nbranch = \"main\"\ncommit = \"\"\nreference = \"registry.example.com/team/demo:main\"\n",
+    )
+    .unwrap();
+    let deployer = registry_deployer(&directory, Rc::new(RecordingRunner::new()));
+    deployer.restart(&application).unwrap();
+
+    let compose: serde_yaml::Value = serde_yaml::from_str(
+        &read_to_string(directory.path().join("compose_files/demo-blue.yaml")).unwrap(),
+    )
+    .unwrap();
+    let service = &compose["services"]["app"];
+    assert_eq!(service["image"], "registry.example.com/team/demo:main");
+    assert!(service.get("build").is_none());
+}
+
+#[test]
+fn removal_takes_both_colors_and_the_registry_tags() {
+    let directory = tempdir().unwrap();
+    let blue = compose_file(&directory, "demo-blue");
+    let green = compose_file(&directory, "demo-green");
+    let mut application =
+        application("[registry]\nurl = \"registry.example.com\"\nrepository = \"team/demo\"\n");
+    application.blue_green = blue_green(&directory, 0, "true").blue_green;
+    let state_directory = directory.path().join("state");
+    create_dir_all(&state_directory).unwrap();
+    write(
+        state_directory.join("demo.toml"),
+        "[[deployments]]\ntimestamp = \"2026-01-01T00:00:00Z\"\nbranch = \"origin/main\"\ncommit = \"0123456789abcdef0123456789abcdef01234567\"\n\
+         [[deployments]]\ntimestamp = \"2026-01-02T00:00:00Z\"\nbranch = \"v2\"\ncommit = \"\"\nreference = \"registry.example.com/team/demo:v2\"\n",
+    )
+    .unwrap();
+    let runner =
+        Rc::new(RecordingRunner::new().respond_when("image inspect", "sha256:demo 1024\n"));
+    let deployer = registry_deployer(&directory, runner.clone());
+    let removal = Removal {
+        images: true,
+        ..Removal::default()
+    };
+
+    deployer.remove(&application, removal).unwrap();
+    let inspect = "docker image inspect --format {{.Id}} {{.Size}}";
+    let tag = "registry.example.com/team/demo";
+    assert_eq!(
+        runner.invocations(),
+        vec![
+            format!("{} demo", inspect),
+            format!("docker compose -f {} -p demo-blue down --rmi all", blue),
+            format!("docker compose -f {} -p demo-green down --rmi all", green),
+            format!(
+                "{} {}:0123456789abcdef0123456789abcdef01234567",
+                inspect, tag
+            ),
+            format!(
+                "docker image rm {}:0123456789abcdef0123456789abcdef01234567",
+                tag
+            ),
+            format!("{} {}:main", inspect, tag),
+            format!("docker image rm {}:main", tag),
+            format!("{} {}:v2", inspect, tag),
+            format!("docker image rm {}:v2", tag),
+        ]
+    );
+}
+
+#[test]
+fn removal_deletes_the_state_and_the_proxy_config() {
+    let directory = tempdir().unwrap();
+    compose_file(&directory, "demo-blue");
+    let application = blue_green(&directory, 0, "true");
+    let proxy_config = directory.path().join("upstream.conf");
+    write(&proxy_config, "upstream demo {}\n").unwrap();
+    let state_path = directory.path().join("state/demo.toml");
+    create_dir_all(state_path.parent().unwrap()).unwrap();
+    write(&state_path, "").unwrap();
+    let deployer = registry_deployer(&directory, Rc::new(RecordingRunner::new()));
+    let removal = Removal {
+        state: true,
+        ..Removal::default()
+    };
+
+    let summary = deployer
+        .dry_run(true)
+        .remove(&application, removal)
+        .unwrap();
+    let listed: Vec<&str> = summary
+        .removed
+        .iter()
+        .map(|(item, _)| item.as_str())
+        .collect();
+    assert_eq!(
+        listed,
+        vec![state_path.to_str().unwrap(), proxy_config.to_str().unwrap()]
+    );
+    assert!(state_path.exists() && proxy_config.exists());
+
+    let deployer = registry_deployer(&directory, Rc::new(RecordingRunner::new()));
+    deployer.remove(&application, removal).unwrap();
+    assert!(!state_path.exists() && !proxy_config.exists());
+    // the compose file is only deleted on request
+    assert!(
+        directory
+            .path()
+            .join("compose_files/demo-blue.yaml")
+            .exists()
+    );
+}
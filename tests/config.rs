@@ -0,0 +1,317 @@
+use std::{fs::write, path::Path};
+
+use autodeploy::utils::config::{
+    global_configuration_candidates, load_global_configuration, load_project_configuration,
+    locate_global_configuration, resolve_path,
+};
+use tempfile::tempdir;
+
+#[test]
+fn explicit_configuration_is_used_as_is() {
+    let path = Path::new("/opt/deploy/global.toml");
+    assert_eq!(
+        locate_global_configuration(Some(path)),
+        Ok(path.to_path_buf())
+    );
+}
+
+#[test]
+fn lookup_starts_in_the_current_directory_and_ends_in_etc() {
+    let candidates = global_configuration_candidates();
+    assert_eq!(candidates.first().unwrap(), Path::new("global.toml"));
+    assert_eq!(
+        candidates.last().unwrap(),
+        Path::new("/etc/autodeploy/global.toml")
+    );
+}
+
+#[test]
+fn relative_paths_resolve_against_the_configuration_directory() {
+    let base = Path::new("/etc/autodeploy");
+    assert_eq!(
+        resolve_path(base, "./compose_files"),
+        "/etc/autodeploy/compose_files"
+    );
+    assert_eq!(resolve_path(base, "../repos"), "/etc/autodeploy/../repos");
+    assert_eq!(resolve_path(base, "/srv/repos"), "/srv/repos");
+    // a configuration in the current directory keeps its paths
+    assert_eq!(resolve_path(Path::new(""), "./repos"), "./repos");
+}
+
+#[test]
+fn global_configuration_paths_follow_the_file() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("global.toml");
+    write(
+        &path,
+        r#"
+banner = false
+configuration_file = "config.toml"
+state_directory = "/var/lib/autodeploy"
+"#,
+    )
+    .unwrap();
+
+    let config = load_global_configuration(&path).unwrap();
+    let base = directory.path().display();
+    assert_eq!(config.configuration_file, format!("{}/config.toml", base));
+    assert_eq!(config.compose_directory, format!("{}/compose_files", base));
+    assert_eq!(config.state_directory, "/var/lib/autodeploy");
+    assert_eq!(config.secrets_directory, format!("{}/secrets", base));
+}
+
+#[test]
+fn project_configuration_paths_follow_the_file() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("config.toml");
+    write(
+        &path,
+        r#"
+path = "./repos"
+
+[[application]]
+name = "demo"
+url = "https://example.com/demo.git"
+directory_name = "demo"
+allowed_signers = "allowed_signers"
+[application.container]
+name = "demo"
+image = "demo"
+restart_policy = "no"
+ports = []
+[application.container.build]
+dockerfile = "Dockerfile"
+[application.container.build.secrets.npmrc]
+file = "secrets/npmrc"
+[application.blue_green]
+container_port = 8080
+blue_port = 8081
+green_port = 8082
+proxy = "nginx"
+proxy_config = "/etc/nginx/conf.d/demo.upstream"
+"#,
+    )
+    .unwrap();
+
+    let config = load_project_configuration(path.to_str().unwrap()).unwrap();
+    let base = directory.path().display();
+    let application = &config.application[0];
+    assert_eq!(config.repository_path, format!("{}/repos", base));
+    assert_eq!(
+        application.allowed_signers,
+        Some(format!("{}/allowed_signers", base))
+    );
+    assert_eq!(application.gpg_keyring, None);
+    let secrets = application.container.build.secrets.as_ref().unwrap();
+    assert_eq!(
+        secrets["npmrc"].file,
+        Some(format!("{}/secrets/npmrc", base))
+    );
+    assert_eq!(
+        application.blue_green.as_ref().unwrap().proxy_config,
+        "/etc/nginx/conf.d/demo.upstream"
+    );
+}
+
+#[test]
+fn invalid_project_configuration_is_reported() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("config.toml");
+    write(&path, "path = 1").unwrap();
+    assert_eq!(
+        load_project_configuration(path.to_str().unwrap()).err(),
+        Some("Invalid Project Configuration Detected".to_string())
+    );
+}
+
+#[test]
+fn context_outside_the_repository_is_rejected() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("config.toml");
+    write(
+        &path,
+        r#"
+path = "./repos"
+
+[[application]]
+name = "demo"
+url = "https://example.com/demo.git"
+directory_name = "demo"
+context = "../other"
+[application.container]
+name = "demo"
+image = "demo"
+restart_policy = "no"
+ports = []
+[application.container.build]
+dockerfile = "Dockerfile"
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        load_project_configuration(path.to_str().unwrap()).err(),
+        Some(
+            "Invalid context `../other` of demo, it has to be a path inside the repository"
+                .to_string()
+        )
+    );
+}
+
+#[test]
+fn shared_clones_need_the_same_repository() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("config.toml");
+    let application = |name: &str, url: &str| {
+        format!(
+            r#"
+[[application]]
+name = "{0}"
+url = "{1}"
+directory_name = "{0}"
+clone_directory = "platform"
+[application.container]
+name = "{0}"
+image = "{0}"
+restart_policy = "no"
+ports = []
+[application.container.build]
+dockerfile = "Dockerfile"
+"#,
+            name, url
+        )
+    };
+    write(
+        &path,
+        format!(
+            "path = \"./repos\"\n{}{}",
+            application("api", "https://example.com/platform.git"),
+            application("web", "https://example.com/platform.git")
+        ),
+    )
+    .unwrap();
+    let config = load_project_configuration(path.to_str().unwrap()).unwrap();
+    assert_eq!(config.application[1].repository_directory(), "platform");
+
+    write(
+        &path,
+        format!(
+            "path = \"./repos\"\n{}{}",
+            application("api", "https://example.com/platform.git"),
+            application("web", "https://example.com/web.git")
+        ),
+    )
+    .unwrap();
+    assert_eq!(
+        load_project_configuration(path.to_str().unwrap()).err(),
+        Some("api and web share the clone `platform` but not the repository url".to_string())
+    );
+}
+
+#[test]
+fn secrets_outside_the_secrets_directory_are_rejected() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("config.toml");
+    write(
+        &path,
+        r#"
+path = "./repos"
+
+[[application]]
+name = "demo"
+url = "https://example.com/demo.git"
+directory_name = "demo"
+[application.container]
+name = "demo"
+image = "demo"
+restart_policy = "no"
+ports = []
+environment = ["MODE=production", "DB_PASSWORD=secret:../db_password"]
+[application.container.build]
+dockerfile = "Dockerfile"
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        load_project_configuration(path.to_str().unwrap()).err(),
+        Some(
+            "Invalid secret `../db_password` of demo, it has to be a file name in the secrets directory"
+                .to_string()
+        )
+    );
+}
+
+#[test]
+fn fetch_branches_have_to_form_refspecs() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("config.toml");
+    let load = |fetch_branches: &str| {
+        write(
+            &path,
+            format!(
+                r#"
+path = "./repos"
+
+[[application]]
+name = "demo"
+url = "https://example.com/demo.git"
+directory_name = "demo"
+fetch_branches = {}
+[application.container]
+name = "demo"
+image = "demo"
+restart_policy = "no"
+ports = []
+[application.container.build]
+dockerfile = "Dockerfile"
+"#,
+                fetch_branches
+            ),
+        )
+        .unwrap();
+        load_project_configuration(path.to_str().unwrap())
+    };
+    assert!(load(r#"["main", "release/*"]"#).is_ok());
+    assert_eq!(
+        load(r#"["main:refs/heads/other"]"#).err(),
+        Some(
+            "Invalid fetch branch `main:refs/heads/other` of demo, it has to be a branch name with at most one `*`"
+                .to_string()
+        )
+    );
+    assert!(load(r#"["main^"]"#).is_err());
+    assert!(load(r#"["*/*"]"#).is_err());
+}
+
+#[test]
+fn email_channels_need_a_sender_and_recipients() {
+    let directory = tempdir().unwrap();
+    let path = directory.path().join("global.toml");
+    let global = |channel: &str| {
+        write(
+            &path,
+            format!(
+                "banner = false\nconfiguration_file = \"config.toml\"\n[[notifications.channel]]\n{}",
+                channel
+            ),
+        )
+        .unwrap();
+        load_global_configuration(&path)
+    };
+
+    let config = global(
+        "kind = \"email\"\nurl = \"smtps://smtp.example.com\"\nfrom = \"deploy@example.com\"\nto = [\"ops@example.com\"]\nevents = [\"failed\", \"rolled_back\"]\n",
+    )
+    .unwrap();
+    assert_eq!(config.notifications.channels.len(), 1);
+    assert_eq!(
+        global(
+            "kind = \"email\"\nurl = \"smtps://smtp.example.com\"\nto = [\"ops@example.com\"]\n"
+        )
+        .err(),
+        Some("The email notification channel needs `from` and `to`".to_string())
+    );
+    assert_eq!(
+        global("kind = \"slack\"\nurl = \"\"\n").err(),
+        Some("The slack notification channel has no `url`".to_string())
+    );
+}
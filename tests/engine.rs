@@ -0,0 +1,593 @@
+use std::{
+    fs::{create_dir_all, write},
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixListener,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use autodeploy::{
+    objects::structs::{Build, Container},
+    utils::{
+        docker::{Runtime, generate_compose},
+        engine::{
+            BuildOptions, EngineClient, ImageInspect, api_remove, api_start, api_stop,
+            registry_auth,
+        },
+        registry::RegistryCredentials,
+        runner::RecordingRunner,
+    },
+};
+use serde_json::Value;
+use tempfile::{TempDir, tempdir};
+
+const RUNNING: &str = r#"{"Id":"abc","Name":"/demo","State":{"Status":"running","Running":true}}"#;
+const EXITED: &str =
+    r#"{"Id":"abc","Name":"/demo","State":{"Status":"exited","Running":false,"ExitCode":1}}"#;
+
+#[derive(Debug, Clone)]
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// a canned response, chunked responses are sent one piece per chunk
+struct Response {
+    status: u16,
+    pieces: Vec<Vec<u8>>,
+    chunked: bool,
+}
+
+fn json(status: u16, body: &str) -> Response {
+    Response {
+        status,
+        pieces: vec![body.as_bytes().to_vec()],
+        chunked: false,
+    }
+}
+
+fn empty(status: u16) -> Response {
+    Response {
+        status,
+        pieces: vec![],
+        chunked: false,
+    }
+}
+
+fn chunked(pieces: &[&[u8]]) -> Response {
+    Response {
+        status: 200,
+        pieces: pieces.iter().map(|piece| piece.to_vec()).collect(),
+        chunked: true,
+    }
+}
+
+/// a fake docker daemon on a unix socket in a temporary directory, recording
+/// every request and answering through `route`
+fn fake_engine(
+    route: impl Fn(&str, &str) -> Response + Send + 'static,
+) -> (TempDir, EngineClient, Arc<Mutex<Vec<Request>>>) {
+    let directory = tempdir().unwrap();
+    let socket = directory.path().join("docker.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let requests = Arc::new(Mutex::new(vec![]));
+    let recorded = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let path = parts.next().unwrap_or_default().to_string();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let response = route(&method, &path);
+            recorded
+                .lock()
+                .unwrap()
+                .push(Request { method, path, body });
+
+            let mut stream = reader.into_inner();
+            let mut output = format!("HTTP/1.1 {} Status\r\n", response.status).into_bytes();
+            if response.chunked {
+                output.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+                for piece in &response.pieces {
+                    output.extend_from_slice(format!("{:x}\r\n", piece.len()).as_bytes());
+                    output.extend_from_slice(piece);
+                    output.extend_from_slice(b"\r\n");
+                }
+                output.extend_from_slice(b"0\r\n\r\n");
+            } else {
+                let body = response.pieces.concat();
+                output.extend_from_slice(
+                    format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes(),
+                );
+                output.extend_from_slice(&body);
+            }
+            stream.write_all(&output).unwrap();
+        }
+    });
+    let client = EngineClient::new(&format!("unix://{}", socket.display()));
+    (directory, client, requests)
+}
+
+fn requests(recorded: &Arc<Mutex<Vec<Request>>>) -> Vec<String> {
+    recorded
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|request| format!("{} {}", request.method, request.path))
+        .collect()
+}
+
+/// a compose file generated the way a deployment does it
+fn compose_file(directory: &TempDir) -> String {
+    let compose_directory = directory.path().join("compose_files");
+    let container = Container {
+        name: "demo".to_string(),
+        image: "demo:latest".to_string(),
+        build: Build {
+            dockerfile: "Dockerfile".to_string(),
+            ..Build::default()
+        },
+        restart: "unless-stopped".to_string(),
+        ports: vec!["127.0.0.1:8081:8080".to_string(), "9090/udp".to_string()],
+        volumes: Some(vec![
+            "./data:/data:ro".to_string(),
+            "cache:/cache".to_string(),
+        ]),
+        environment: Some(vec!["MODE=production".to_string()]),
+        ..Container::default()
+    };
+    generate_compose(
+        "./repositories/demo",
+        compose_directory.to_str().unwrap(),
+        "./secrets",
+        "demo",
+        &container,
+        false,
+    )
+}
+
+#[test]
+fn build_streams_a_tar_context() {
+    let (directory, client, recorded) = fake_engine(|_, _| {
+        chunked(&[
+            b"{\"stream\":\"Step 1/1 : FROM scratch\\n\"}\r\n{\"str",
+            b"eam\":\"Successfully built\\n\"}\r\n",
+        ])
+    });
+    let context = directory.path().join("context");
+    create_dir_all(context.join("src")).unwrap();
+    write(context.join("Dockerfile"), "FROM scratch\n").unwrap();
+    write(context.join("src/main.rs"), "fn main() {}\n").unwrap();
+
+    let options = BuildOptions {
+        dockerfile: "Dockerfile".to_string(),
+        build_args: vec!["VERSION=1.0".to_string()],
+        target: Some("runtime".to_string()),
+        no_cache: true,
+        ..BuildOptions::default()
+    };
+    client.build(&context, "demo:latest", &options).unwrap();
+
+    let request = recorded.lock().unwrap()[0].clone();
+    assert_eq!(request.method, "POST");
+    assert_eq!(
+        request.path,
+        "/v1.41/build?t=demo%3Alatest&dockerfile=Dockerfile&buildargs=%7B%22VERSION%22%3A%221.0%22%7D&rm=1&target=runtime&nocache=1"
+    );
+    let mut archive = tar::Archive::new(request.body.as_slice());
+    let entries: Vec<String> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path().unwrap().display().to_string();
+            path.trim_start_matches("./").to_string()
+        })
+        .collect();
+    assert!(entries.contains(&"Dockerfile".to_string()));
+    assert!(entries.contains(&"src/main.rs".to_string()));
+}
+
+#[test]
+fn build_context_leaves_out_ignored_files() {
+    let (directory, client, recorded) =
+        fake_engine(|_, _| chunked(&[b"{\"stream\":\"done\\n\"}\r\n"]));
+    let context = directory.path().join("context");
+    for path in [".git/objects", "src/fixtures", "node_modules/left-pad"] {
+        create_dir_all(context.join(path)).unwrap();
+    }
+    for path in [
+        "Dockerfile",
+        ".env",
+        ".git/HEAD",
+        "src/main.rs",
+        "src/debug.log",
+        "src/fixtures/keep.log",
+        "node_modules/left-pad/index.js",
+    ] {
+        write(context.join(path), "").unwrap();
+    }
+    write(
+        context.join(".dockerignore"),
+        "# local files\nDockerfile\n.env\nnode_modules/\n**/*.log\n!src/fixtures/keep.log\n",
+    )
+    .unwrap();
+
+    let options = BuildOptions {
+        dockerfile: "Dockerfile".to_string(),
+        ..BuildOptions::default()
+    };
+    client.build(&context, "demo:latest", &options).unwrap();
+
+    let request = recorded.lock().unwrap()[0].clone();
+    let mut archive = tar::Archive::new(request.body.as_slice());
+    let mut files: Vec<String> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.header().entry_type().is_file())
+        .map(|entry| entry.path().unwrap().display().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            ".dockerignore",
+            "Dockerfile",
+            "src/fixtures/keep.log",
+            "src/main.rs"
+        ]
+    );
+}
+
+#[test]
+fn build_reports_the_build_error() {
+    let (directory, client, _) = fake_engine(|_, _| {
+        chunked(&[
+            b"{\"stream\":\"Step 1/2 : RUN false\\n\"}\r\n",
+            b"{\"errorDetail\":{\"message\":\"exit code 1\"},\"error\":\"exit code 1\"}\r\n",
+        ])
+    });
+    let context = directory.path().join("context");
+    create_dir_all(&context).unwrap();
+    let options = BuildOptions {
+        dockerfile: "Dockerfile".to_string(),
+        ..BuildOptions::default()
+    };
+    let result = client.build(&context, "demo", &options);
+    assert_eq!(result, Err("exit code 1".to_string()));
+}
+
+#[test]
+fn start_recreates_and_checks_the_container() {
+    let (directory, client, recorded) = fake_engine(|method, path| match (method, path) {
+        ("DELETE", _) => empty(404),
+        ("POST", "/v1.41/containers/create?name=demo") => json(201, "{\"Id\":\"abc\"}"),
+        ("POST", _) => empty(204),
+        ("GET", _) => json(200, RUNNING),
+        _ => empty(500),
+    });
+    let compose_path = compose_file(&directory);
+
+    assert!(api_start(&client, &compose_path, "demo", false));
+    assert_eq!(
+        requests(&recorded),
+        vec![
+            "DELETE /v1.41/containers/demo?force=true",
+            "POST /v1.41/containers/create?name=demo",
+            "POST /v1.41/containers/demo/start",
+            "GET /v1.41/containers/demo/json",
+        ]
+    );
+    let config: Value = serde_json::from_slice(&recorded.lock().unwrap()[1].body).unwrap();
+    assert_eq!(config["Image"], "demo:latest");
+    assert_eq!(config["Env"][0], "MODE=production");
+    assert_eq!(config["Labels"]["com.docker.compose.project"], "demo");
+    assert_eq!(
+        config["HostConfig"]["PortBindings"]["8080/tcp"][0]["HostPort"],
+        "8081"
+    );
+    assert_eq!(
+        config["HostConfig"]["PortBindings"]["8080/tcp"][0]["HostIp"],
+        "127.0.0.1"
+    );
+    assert!(config["ExposedPorts"]["9090/udp"].is_object());
+    assert!(config["HostConfig"]["PortBindings"]["9090/udp"].is_null());
+    assert_eq!(
+        config["HostConfig"]["RestartPolicy"]["Name"],
+        "unless-stopped"
+    );
+    let binds = config["HostConfig"]["Binds"].as_array().unwrap();
+    assert!(binds[0].as_str().unwrap().starts_with('/'));
+    assert!(binds[0].as_str().unwrap().ends_with("/data:/data:ro"));
+    assert_eq!(binds[1], "demo_cache:/cache");
+}
+
+#[test]
+fn start_passes_the_secrets_to_the_container() {
+    let (directory, client, recorded) = fake_engine(|method, path| match (method, path) {
+        ("DELETE", _) => empty(404),
+        ("POST", "/v1.41/containers/create?name=demo") => json(201, "{\"Id\":\"abc\"}"),
+        ("POST", _) => empty(204),
+        ("GET", _) => json(200, RUNNING),
+        _ => empty(500),
+    });
+    let secrets_directory = directory.path().join("secrets");
+    create_dir_all(&secrets_directory).unwrap();
+    write(secrets_directory.join("db_password"), "hunter2\n").unwrap();
+    write(secrets_directory.join("tls_key"), "key").unwrap();
+    let container = Container {
+        name: "demo".to_string(),
+        image: "demo:latest".to_string(),
+        restart: "no".to_string(),
+        environment: Some(vec![
+            "MODE=production".to_string(),
+            "DB_PASSWORD=secret:db_password".to_string(),
+        ]),
+        secrets: Some(vec!["tls_key".to_string()]),
+        ..Container::default()
+    };
+    let compose_path = generate_compose(
+        "./repositories/demo",
+        directory.path().join("compose_files").to_str().unwrap(),
+        secrets_directory.to_str().unwrap(),
+        "demo",
+        &container,
+        false,
+    );
+
+    assert!(api_start(&client, &compose_path, "demo", false));
+    let config: Value = serde_json::from_slice(&recorded.lock().unwrap()[1].body).unwrap();
+    assert_eq!(config["Env"][0], "MODE=production");
+    assert_eq!(config["Env"][1], "DB_PASSWORD=hunter2");
+    assert_eq!(
+        config["HostConfig"]["Binds"][0],
+        format!(
+            "{}:/run/secrets/tls_key:ro",
+            directory
+                .path()
+                .join("compose_files/../secrets/tls_key")
+                .display()
+        )
+    );
+}
+
+#[test]
+fn start_fails_when_the_container_exits() {
+    let (directory, client, recorded) = fake_engine(|method, path| match (method, path) {
+        ("DELETE", _) => empty(204),
+        ("POST", "/v1.41/containers/create?name=demo") => json(201, "{\"Id\":\"abc\"}"),
+        ("POST", _) => empty(204),
+        ("GET", "/v1.41/containers/demo/json") => json(200, EXITED),
+        ("GET", _) => Response {
+            status: 200,
+            pieces: vec![[&[2, 0, 0, 0, 0, 0, 0, 6][..], b"panic\n"].concat()],
+            chunked: false,
+        },
+        _ => empty(500),
+    });
+    let compose_path = compose_file(&directory);
+
+    assert!(!api_start(&client, &compose_path, "demo", false));
+    assert_eq!(
+        requests(&recorded).last().unwrap(),
+        "GET /v1.41/containers/demo/logs?stdout=1&stderr=1&tail=50"
+    );
+}
+
+#[test]
+fn start_reports_the_engine_error() {
+    let (directory, client, recorded) = fake_engine(|method, _| match method {
+        "DELETE" => empty(204),
+        _ => json(404, "{\"message\":\"No such image: demo:latest\"}"),
+    });
+    let compose_path = compose_file(&directory);
+
+    assert!(!api_start(&client, &compose_path, "demo", false));
+    assert_eq!(requests(&recorded).len(), 2);
+}
+
+#[test]
+fn stop_stops_and_removes_the_container() {
+    let (directory, client, recorded) = fake_engine(|_, _| empty(204));
+    let compose_path = compose_file(&directory);
+
+    assert!(api_stop(&client, &compose_path, "demo", false));
+    assert_eq!(
+        requests(&recorded),
+        vec![
+            "POST /v1.41/containers/demo/stop",
+            "DELETE /v1.41/containers/demo?force=true",
+        ]
+    );
+}
+
+#[test]
+fn remove_deletes_named_volumes_and_images() {
+    let (directory, client, recorded) = fake_engine(|method, path| match (method, path) {
+        ("GET", _) => json(200, r#"{"Id":"sha256:abc","Size":2048}"#),
+        ("DELETE", path) if path.starts_with("/v1.41/images") => json(200, "[]"),
+        _ => empty(204),
+    });
+    let compose_path = compose_file(&directory);
+
+    assert_eq!(
+        client.inspect_image("demo:latest"),
+        Ok(Some(ImageInspect {
+            id: "sha256:abc".to_string(),
+            size: 2048
+        }))
+    );
+    assert!(api_remove(
+        &client,
+        &compose_path,
+        "demo",
+        true,
+        true,
+        false
+    ));
+    assert_eq!(
+        requests(&recorded),
+        vec![
+            "GET /v1.41/images/demo%3Alatest/json",
+            "POST /v1.41/containers/demo/stop",
+            "DELETE /v1.41/containers/demo?force=true",
+            "DELETE /v1.41/volumes/demo_cache",
+            "DELETE /v1.41/images/demo%3Alatest?force=true",
+        ]
+    );
+}
+
+#[test]
+fn build_cache_is_pruned_down_to_the_limit() {
+    let (_directory, client, recorded) =
+        fake_engine(|_, _| json(200, r#"{"CachesDeleted":["a","b"],"SpaceReclaimed":4096}"#));
+    assert_eq!(client.prune_build_cache(1 << 30), Ok(4096));
+    assert_eq!(
+        requests(&recorded),
+        vec!["POST /v1.41/build/prune?keep-storage=1073741824"]
+    );
+}
+
+#[test]
+fn images_are_tagged_pushed_and_pulled() {
+    let (_directory, client, recorded) = fake_engine(|_, path| {
+        if path.contains("/tag?") {
+            empty(201)
+        } else {
+            chunked(&[b"{\"status\":\"Pushing\",\"id\":\"abc\"}\r\n"])
+        }
+    });
+    let runtime = Runtime::api(Rc::new(RecordingRunner::new()), client);
+    let credentials = RegistryCredentials {
+        username: "deploy".to_string(),
+        password: "secret".to_string(),
+    };
+    assert!(runtime.push_image(
+        "demo:latest",
+        "registry.example.com/team/demo",
+        &["abc123".to_string(), "main".to_string()],
+        Some(&credentials),
+        false
+    ));
+    assert!(runtime.pull_image("registry.example.com/team/demo", "abc123", None, false));
+    assert_eq!(
+        requests(&recorded),
+        vec![
+            "POST /v1.41/images/demo%3Alatest/tag?repo=registry.example.com%2Fteam%2Fdemo&tag=abc123",
+            "POST /v1.41/images/registry.example.com%2Fteam%2Fdemo/push?tag=abc123",
+            "POST /v1.41/images/demo%3Alatest/tag?repo=registry.example.com%2Fteam%2Fdemo&tag=main",
+            "POST /v1.41/images/registry.example.com%2Fteam%2Fdemo/push?tag=main",
+            "POST /v1.41/images/create?fromImage=registry.example.com%2Fteam%2Fdemo&tag=abc123",
+        ]
+    );
+}
+
+#[test]
+fn push_reports_the_registry_error() {
+    let (_directory, client, _) = fake_engine(|_, path| {
+        if path.contains("/tag?") {
+            empty(201)
+        } else {
+            chunked(&[b"{\"error\":\"denied: requested access to the resource is denied\"}\r\n"])
+        }
+    });
+    assert!(client.tag_image("demo:latest", "team/demo", "main").is_ok());
+    assert!(
+        client
+            .push_image("team/demo", "main", &registry_auth("team", None))
+            .unwrap_err()
+            .contains("denied")
+    );
+}
+
+#[test]
+fn registry_auth_carries_the_credentials() {
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE};
+
+    let credentials = RegistryCredentials {
+        username: "deploy".to_string(),
+        password: "secret".to_string(),
+    };
+    let decode = |auth: String| -> Value {
+        serde_json::from_slice(&URL_SAFE.decode(auth).unwrap()).unwrap()
+    };
+    let auth = decode(registry_auth("registry.example.com", Some(&credentials)));
+    assert_eq!(auth["username"], "deploy");
+    assert_eq!(auth["password"], "secret");
+    assert_eq!(auth["serveraddress"], "registry.example.com");
+    assert_eq!(
+        decode(registry_auth("registry.example.com", None)),
+        Value::Object(Default::default())
+    );
+}
+
+#[test]
+fn inspect_of_a_missing_container_is_none() {
+    let (_directory, client, _) =
+        fake_engine(|_, _| json(404, "{\"message\":\"No such container: demo\"}"));
+    assert_eq!(client.inspect_container("demo"), Ok(None));
+}
+
+#[test]
+fn logs_are_demultiplexed() {
+    let (_directory, client, _) = fake_engine(|_, _| Response {
+        status: 200,
+        pieces: vec![
+            [
+                &[1, 0, 0, 0, 0, 0, 0, 6][..],
+                b"ready\n",
+                &[2, 0, 0, 0, 0, 0, 0, 8][..],
+                b"warning\n",
+            ]
+            .concat(),
+        ],
+        chunked: false,
+    });
+    assert_eq!(
+        client.container_logs("demo", 10),
+        Ok("ready\nwarning\n".to_string())
+    );
+}
+
+#[test]
+fn runtime_restart_goes_through_the_engine() {
+    let (directory, client, recorded) = fake_engine(|method, path| match (method, path) {
+        ("POST", "/v1.41/containers/create?name=demo") => json(201, "{\"Id\":\"abc\"}"),
+        ("GET", _) => json(200, RUNNING),
+        _ => empty(204),
+    });
+    let compose_path = compose_file(&directory);
+    let runner = Rc::new(RecordingRunner::new());
+    let runtime = Runtime::api(runner.clone(), client);
+
+    assert!(runtime.restart(&compose_path, "demo", false));
+    assert_eq!(
+        requests(&recorded),
+        vec![
+            "POST /v1.41/containers/demo/stop",
+            "DELETE /v1.41/containers/demo?force=true",
+            "DELETE /v1.41/containers/demo?force=true",
+            "POST /v1.41/containers/create?name=demo",
+            "POST /v1.41/containers/demo/start",
+            "GET /v1.41/containers/demo/json",
+        ]
+    );
+    assert!(runner.invocations().is_empty());
+}
@@ -0,0 +1,631 @@
+use std::{
+    fs::{create_dir_all, read_to_string, write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process::{Child, Command, Stdio},
+    rc::Rc,
+    thread::sleep,
+    time::Duration,
+};
+
+use autodeploy::{
+    objects::structs::{Application, DirtyPolicy},
+    pipeline::{DeployError, Deployer, Step},
+    utils::{
+        docker::Runtime,
+        git::{
+            branch_changelog, deployable_branches, ensure_commit, is_deployable_branch,
+            path_changed, working_tree_changes,
+        },
+        runner::RecordingRunner,
+    },
+};
+use chrono::Utc;
+use git2::{Repository, Signature, Time};
+use tempfile::{TempDir, tempdir};
+
+/// an application deployed from the repository at `url`
+fn application(url: &str, extra: &str) -> Application {
+    toml::from_str(&format!(
+        r#"
+name = "demo"
+url = "{}"
+directory_name = "demo"
+{}
+[container]
+name = "demo"
+image = "demo"
+restart_policy = "no"
+ports = []
+[container.build]
+dockerfile = "Dockerfile"
+"#,
+        url, extra
+    ))
+    .unwrap()
+}
+
+/// a repository with `main` checked out
+fn init(path: &Path) -> Repository {
+    let repo = Repository::init(path).unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+    repo
+}
+
+/// commit the files on top of HEAD and return the commit id
+fn commit(repo: &Repository, files: &[(&str, &str)]) -> String {
+    commit_at(repo, "change", files, Utc::now().timestamp())
+}
+
+/// commit the files with the message at `time`, in seconds since the epoch
+fn commit_at(repo: &Repository, message: &str, files: &[(&str, &str)], time: i64) -> String {
+    let root = repo.workdir().unwrap();
+    let mut index = repo.index().unwrap();
+    for (path, contents) in files {
+        let file = root.join(path);
+        create_dir_all(file.parent().unwrap()).unwrap();
+        write(&file, contents).unwrap();
+        index.add_path(Path::new(path)).unwrap();
+    }
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature =
+        Signature::new("autodeploy", "autodeploy@example.com", &Time::new(time, 0)).unwrap();
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parent.iter().collect::<Vec<_>>(),
+    )
+    .unwrap()
+    .to_string()
+}
+
+/// the upstream repository the application is cloned from
+fn upstream(directory: &TempDir) -> (Repository, String) {
+    let path = directory.path().join("upstream");
+    (init(&path), path.to_str().unwrap().to_string())
+}
+
+/// the clone of the application below the repository root
+fn clone_path(directory: &TempDir) -> String {
+    directory
+        .path()
+        .join("repositories/demo")
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+fn head(repo: &Repository) -> String {
+    repo.head()
+        .unwrap()
+        .peel_to_commit()
+        .unwrap()
+        .id()
+        .to_string()
+}
+
+/// a deployer below the directory that never prompts nor runs docker
+fn deployer(directory: &TempDir) -> Deployer {
+    let path = |name: &str| directory.path().join(name).to_str().unwrap().to_string();
+    Deployer::new(&path("repositories"), "", "")
+        .compose_directory(&path("compose_files"))
+        .state_directory(&path("state"))
+        .non_interactive(true)
+        .runtime(Runtime::compose(Rc::new(RecordingRunner::new())))
+}
+
+/// fetch and check out the branch like a new run of autodeploy, cloning the
+/// repository the first time
+fn checkout(
+    directory: &TempDir,
+    application: &Application,
+    branch: &str,
+    dirty_policy: Option<DirtyPolicy>,
+) -> Result<(), DeployError> {
+    let deployer = deployer(directory);
+    let mut pipeline = deployer.pipeline(application).dirty_policy(dirty_policy);
+    pipeline.fetch()?;
+    pipeline.checkout(branch)
+}
+
+#[test]
+fn changes_are_detected_below_the_path_only() {
+    let directory = tempdir().unwrap();
+    let repo = Repository::init(directory.path()).unwrap();
+    let path = directory.path().to_str().unwrap();
+    let first = commit(
+        &repo,
+        &[
+            ("services/api/main.rs", "api"),
+            ("services/web/index.html", "web"),
+        ],
+    );
+    let second = commit(&repo, &[("services/web/index.html", "web v2")]);
+
+    assert!(!path_changed(path, &first, &second, Some("services/api")));
+    assert!(!path_changed(path, &first, &second, Some("services/api/")));
+    assert!(path_changed(path, &first, &second, Some("services/web")));
+    assert!(path_changed(path, &first, &second, None));
+    assert!(!path_changed(path, &second, &second, None));
+}
+
+#[test]
+fn unknown_commits_count_as_changed() {
+    let directory = tempdir().unwrap();
+    let repo = Repository::init(directory.path()).unwrap();
+    let path = directory.path().to_str().unwrap();
+    let head = commit(&repo, &[("README.md", "demo")]);
+    let missing = "0123456789012345678901234567890123456789";
+    assert!(path_changed(path, missing, &head, Some("services/api")));
+}
+
+#[test]
+fn steps_before_the_fetch_are_refused() {
+    let directory = tempdir().unwrap();
+    let root = directory.path().join("repositories");
+    let deployer = Deployer::new(root.to_str().unwrap(), "", "")
+        .state_directory(directory.path().join("state").to_str().unwrap());
+    let application = application("https://example.com/demo.git", "");
+    let mut pipeline = deployer.pipeline(&application);
+    assert_eq!(
+        pipeline.resolve_branch("main"),
+        Err(DeployError::MissingStep(Step::Fetch))
+    );
+    assert_eq!(
+        pipeline.checkout("main"),
+        Err(DeployError::MissingStep(Step::Fetch))
+    );
+}
+
+#[test]
+fn diverged_branch_is_reset_to_the_remote_tip() {
+    let directory = tempdir().unwrap();
+    let (upstream, url) = upstream(&directory);
+    commit(&upstream, &[("app.txt", "v1")]);
+    let application = application(&url, r#"divergence_policy = "reset_to_remote""#);
+    checkout(&directory, &application, "main", None).unwrap();
+    let clone = Repository::open(clone_path(&directory)).unwrap();
+    commit(&clone, &[("app.txt", "local")]);
+    let remote_tip = commit(&upstream, &[("app.txt", "v2")]);
+
+    checkout(&directory, &application, "main", None).unwrap();
+    assert_eq!(head(&clone), remote_tip);
+    assert_eq!(clone.head().unwrap().name(), Some("refs/heads/origin/main"));
+    let contents = read_to_string(Path::new(&clone_path(&directory)).join("app.txt"));
+    assert_eq!(contents.unwrap(), "v2");
+}
+
+#[test]
+fn diverged_branch_is_kept_when_aborting() {
+    let directory = tempdir().unwrap();
+    let (upstream, url) = upstream(&directory);
+    commit(&upstream, &[("app.txt", "v1")]);
+    let application = application(&url, r#"divergence_policy = "abort""#);
+    checkout(&directory, &application, "main", None).unwrap();
+    let clone = Repository::open(clone_path(&directory)).unwrap();
+    let local = commit(&clone, &[("app.txt", "local")]);
+    commit(&upstream, &[("app.txt", "v2")]);
+
+    assert_eq!(
+        checkout(&directory, &application, "main", None),
+        Err(DeployError::Diverged)
+    );
+    assert_eq!(head(&clone), local);
+    let contents = read_to_string(Path::new(&clone_path(&directory)).join("app.txt"));
+    assert_eq!(contents.unwrap(), "local");
+}
+
+#[test]
+fn branch_behind_the_remote_is_fast_forwarded() {
+    let directory = tempdir().unwrap();
+    let (upstream, url) = upstream(&directory);
+    commit(&upstream, &[("app.txt", "v1")]);
+    // the prompt policy cannot be answered here, it must not be needed
+    let application = application(&url, "");
+    checkout(&directory, &application, "main", None).unwrap();
+    let remote_tip = commit(&upstream, &[("app.txt", "v2")]);
+
+    checkout(&directory, &application, "main", None).unwrap();
+    let clone = Repository::open(clone_path(&directory)).unwrap();
+    assert_eq!(head(&clone), remote_tip);
+}
+
+/// a clone at `v1` with a modified, an untracked and an ignored file while the
+/// remote moved on to `v2`
+fn dirty_clone(directory: &TempDir) -> Application {
+    let (upstream, url) = upstream(directory);
+    commit(&upstream, &[("app.txt", "v1"), (".gitignore", ".env\n")]);
+    let application = application(&url, "");
+    checkout(directory, &application, "main", None).unwrap();
+    let clone = Path::new(&clone_path(directory)).to_path_buf();
+    write(clone.join("app.txt"), "edited").unwrap();
+    write(clone.join("notes.txt"), "notes").unwrap();
+    write(clone.join(".env"), "SECRET=1").unwrap();
+    commit(&upstream, &[("app.txt", "v2")]);
+    application
+}
+
+#[test]
+fn local_changes_are_listed_with_untracked_files() {
+    let directory = tempdir().unwrap();
+    dirty_clone(&directory);
+    assert_eq!(
+        working_tree_changes(&clone_path(&directory)).unwrap(),
+        vec!["M app.txt", "?? notes.txt"]
+    );
+}
+
+#[test]
+fn local_changes_are_kept_without_a_policy() {
+    let directory = tempdir().unwrap();
+    let application = dirty_clone(&directory);
+    assert_eq!(
+        checkout(&directory, &application, "main", None),
+        Err(DeployError::LocalChanges)
+    );
+    let clone = Path::new(&clone_path(&directory)).to_path_buf();
+    assert_eq!(read_to_string(clone.join("app.txt")).unwrap(), "edited");
+    assert!(clone.join("notes.txt").exists());
+}
+
+#[test]
+fn local_changes_are_stashed_with_untracked_files() {
+    let directory = tempdir().unwrap();
+    let application = dirty_clone(&directory);
+    checkout(&directory, &application, "main", Some(DirtyPolicy::Stash)).unwrap();
+
+    let clone = Path::new(&clone_path(&directory)).to_path_buf();
+    assert_eq!(read_to_string(clone.join("app.txt")).unwrap(), "v2");
+    assert!(!clone.join("notes.txt").exists());
+    assert_eq!(read_to_string(clone.join(".env")).unwrap(), "SECRET=1");
+    let repo = Repository::open(&clone).unwrap();
+    let stash = repo
+        .revparse_single("refs/stash")
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    let modified = stash
+        .tree()
+        .unwrap()
+        .get_path(Path::new("app.txt"))
+        .unwrap();
+    let blob = repo.find_blob(modified.id()).unwrap();
+    assert_eq!(blob.content(), b"edited");
+    // untracked files are kept in the third parent of the stash commit
+    let untracked = stash.parent(2).unwrap().tree().unwrap();
+    assert!(untracked.get_path(Path::new("notes.txt")).is_ok());
+}
+
+#[test]
+fn local_changes_are_discarded_with_untracked_files() {
+    let directory = tempdir().unwrap();
+    let application = dirty_clone(&directory);
+    checkout(&directory, &application, "main", Some(DirtyPolicy::Discard)).unwrap();
+
+    let clone = Path::new(&clone_path(&directory)).to_path_buf();
+    assert_eq!(read_to_string(clone.join("app.txt")).unwrap(), "v2");
+    assert!(!clone.join("notes.txt").exists());
+    // ignored files are no local changes
+    assert_eq!(read_to_string(clone.join(".env")).unwrap(), "SECRET=1");
+    let repo = Repository::open(&clone).unwrap();
+    assert!(repo.revparse_single("refs/stash").is_err());
+}
+
+/// `git daemon` serving the repositories below a directory, libgit2 cannot
+/// fetch shallow from a local path
+struct GitDaemon {
+    child: Child,
+    port: u16,
+}
+
+impl GitDaemon {
+    fn serve(directory: &Path) -> GitDaemon {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        // run `git-daemon` itself, `git daemon` would leave it behind when killed
+        let exec_path = Command::new("git").arg("--exec-path").output().unwrap();
+        let exec_path = String::from_utf8(exec_path.stdout).unwrap();
+        let child = Command::new(Path::new(exec_path.trim()).join("git-daemon"))
+            .args(["--export-all", "--reuseaddr", "--listen=127.0.0.1"])
+            .arg(format!("--port={}", port))
+            .arg(format!("--base-path={}", directory.display()))
+            .arg(directory)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                break;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        GitDaemon { child, port }
+    }
+
+    fn url(&self, repository: &str) -> String {
+        format!("git://127.0.0.1:{}/{}", self.port, repository)
+    }
+}
+
+impl Drop for GitDaemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn shallow_clone_is_deepened_for_a_missing_commit() {
+    let directory = tempdir().unwrap();
+    let (upstream, _) = upstream(&directory);
+    let first = commit(&upstream, &[("app.txt", "v1")]);
+    commit(&upstream, &[("app.txt", "v2")]);
+    commit(&upstream, &[("app.txt", "v3")]);
+    let daemon = GitDaemon::serve(directory.path());
+    let application = application(&daemon.url("upstream"), "clone_depth = 1");
+    deployer(&directory).pipeline(&application).fetch().unwrap();
+    let path = clone_path(&directory);
+    let clone = Repository::open(&path).unwrap();
+    assert!(clone.is_shallow());
+    assert!(clone.find_commit(first.parse().unwrap()).is_err());
+
+    assert!(ensure_commit(
+        "",
+        "",
+        &application,
+        &path,
+        &first,
+        false,
+        true
+    ));
+    let clone = Repository::open(&path).unwrap();
+    assert!(!clone.is_shallow());
+    assert!(clone.find_commit(first.parse().unwrap()).is_ok());
+}
+
+#[test]
+fn fetch_branches_clone_only_the_configured_branches() {
+    let directory = tempdir().unwrap();
+    let (upstream, url) = upstream(&directory);
+    let main = commit(&upstream, &[("app.txt", "v1")]);
+    let tip = upstream.find_commit(main.parse().unwrap()).unwrap();
+    upstream.branch("release-1", &tip, false).unwrap();
+    upstream.branch("feature-x", &tip, false).unwrap();
+    let application = application(&url, r#"fetch_branches = ["main", "release-*"]"#);
+    deployer(&directory).pipeline(&application).fetch().unwrap();
+
+    let clone = Repository::open(clone_path(&directory)).unwrap();
+    let remote = clone.find_remote("origin").unwrap();
+    let refspecs: Vec<String> = remote
+        .fetch_refspecs()
+        .unwrap()
+        .iter()
+        .flatten()
+        .map(str::to_string)
+        .collect();
+    assert_eq!(
+        refspecs,
+        vec![
+            "refs/heads/main:refs/remotes/origin/main",
+            "refs/heads/release-*:refs/remotes/origin/release-*"
+        ]
+    );
+    assert!(clone.find_reference("refs/remotes/origin/main").is_ok());
+    assert!(
+        clone
+            .find_reference("refs/remotes/origin/release-1")
+            .is_ok()
+    );
+    assert!(
+        clone
+            .find_reference("refs/remotes/origin/feature-x")
+            .is_err()
+    );
+    // nothing is checked out until a branch is deployed
+    assert!(clone.head().is_err());
+}
+
+#[test]
+fn allowlist_alone_limits_the_branches() {
+    let application = application("", r#"branch_allowlist = ["main", "release-*"]"#);
+    assert!(is_deployable_branch(&application, "origin/main"));
+    assert!(is_deployable_branch(&application, "origin/release-1.2"));
+    assert!(!is_deployable_branch(&application, "origin/feature-x"));
+}
+
+#[test]
+fn denylist_alone_excludes_the_branches() {
+    let application = application("", r#"branch_denylist = ["wip-*"]"#);
+    assert!(is_deployable_branch(&application, "origin/main"));
+    assert!(!is_deployable_branch(&application, "origin/wip-login"));
+}
+
+#[test]
+fn denylist_overrides_the_allowlist() {
+    let application = application(
+        "",
+        r#"
+branch_allowlist = ["feature-*"]
+branch_denylist = ["feature-x"]
+"#,
+    );
+    assert!(is_deployable_branch(&application, "origin/feature-y"));
+    assert!(!is_deployable_branch(&application, "origin/feature-x"));
+}
+
+#[test]
+fn patterns_match_without_the_remote_name() {
+    let application = application(
+        "",
+        r#"
+branch_allowlist = ["feature-*", "team/*"]
+branch_denylist = ["feature-x"]
+"#,
+    );
+    assert!(is_deployable_branch(&application, "fork/feature-y"));
+    assert!(!is_deployable_branch(&application, "fork/feature-x"));
+    // only the remote is stripped, branches may contain slashes themselves
+    assert!(is_deployable_branch(&application, "origin/team/login"));
+    assert!(!is_deployable_branch(&application, "team/login"));
+}
+
+#[test]
+fn branches_are_offered_most_recently_updated_first() {
+    let directory = tempdir().unwrap();
+    let (upstream, url) = upstream(&directory);
+    let main = commit_at(&upstream, "first", &[("app.txt", "v1")], 1_000);
+    let main = upstream.find_commit(main.parse().unwrap()).unwrap();
+    for (branch, time) in [("release-1", 2_000), ("feature-x", 3_000), ("wip-y", 4_000)] {
+        upstream.branch(branch, &main, false).unwrap();
+        upstream
+            .set_head(&format!("refs/heads/{}", branch))
+            .unwrap();
+        commit_at(&upstream, branch, &[("app.txt", branch)], time);
+    }
+    upstream.set_head("refs/heads/main").unwrap();
+    let application = application(&url, r#"branch_denylist = ["wip-*"]"#);
+    deployer(&directory).pipeline(&application).fetch().unwrap();
+
+    let branches = deployable_branches(&clone_path(&directory), &application).unwrap();
+    let offered: Vec<(&str, &str)> = branches
+        .iter()
+        .map(|branch| (branch.name.as_str(), branch.subject.as_str()))
+        .collect();
+    // origin/HEAD and the denied branch are left out
+    assert_eq!(
+        offered,
+        vec![
+            ("origin/feature-x", "feature-x"),
+            ("origin/release-1", "release-1"),
+            ("origin/main", "first"),
+        ]
+    );
+}
+
+#[test]
+fn moved_repository_updates_the_remote_of_the_clone() {
+    let directory = tempdir().unwrap();
+    let (upstream, url) = upstream(&directory);
+    commit(&upstream, &[("app.txt", "v1")]);
+    checkout(&directory, &application(&url, ""), "main", None).unwrap();
+
+    // the repository moves to a new host and gets new commits there
+    let moved_path = directory.path().join("moved");
+    let moved = Repository::clone(&url, &moved_path).unwrap();
+    let tip = commit(&moved, &[("app.txt", "v2")]);
+    let moved_url = moved_path.to_str().unwrap();
+    checkout(&directory, &application(moved_url, ""), "main", None).unwrap();
+
+    let clone = Repository::open(clone_path(&directory)).unwrap();
+    assert_eq!(clone.find_remote("origin").unwrap().url(), Some(moved_url));
+    assert_eq!(head(&clone), tip);
+}
+
+#[test]
+fn fork_branches_keep_the_remote_name() {
+    let directory = tempdir().unwrap();
+    let (upstream, url) = upstream(&directory);
+    commit(&upstream, &[("app.txt", "v1")]);
+    let fork_path = directory.path().join("fork");
+    let fork = Repository::clone(&url, &fork_path).unwrap();
+    let main = fork.head().unwrap().peel_to_commit().unwrap();
+    fork.branch("feature-x", &main, false).unwrap();
+    fork.set_head("refs/heads/feature-x").unwrap();
+    let feature = commit(&fork, &[("app.txt", "feature")]);
+    let application = application(
+        &url,
+        &format!(
+            r#"forks = [{{ name = "fork", url = "{}" }}]"#,
+            fork_path.display()
+        ),
+    );
+
+    let deployer = deployer(&directory);
+    let mut pipeline = deployer.pipeline(&application);
+    pipeline.fetch().unwrap();
+    assert_eq!(pipeline.resolve_branch("main").unwrap(), "origin/main");
+    assert_eq!(
+        pipeline.resolve_branch("fork/feature-x").unwrap(),
+        "fork/feature-x"
+    );
+    assert!(matches!(
+        pipeline.resolve_branch("feature-x"),
+        Err(DeployError::UnknownBranch(_))
+    ));
+    pipeline.checkout("fork/feature-x").unwrap();
+    assert_eq!(
+        head(&Repository::open(clone_path(&directory)).unwrap()),
+        feature
+    );
+}
+
+#[test]
+fn changelog_lists_the_commits_since_the_deployed_one() {
+    let directory = tempdir().unwrap();
+    let (upstream, url) = upstream(&directory);
+    let deployed = commit_at(&upstream, "first", &[("app.txt", "v1")], 1_000);
+    let second = commit_at(&upstream, "second", &[("app.txt", "v2")], 2_000);
+    let third = commit_at(&upstream, "third", &[("config.txt", "v3")], 3_000);
+    deployer(&directory)
+        .pipeline(&application(&url, ""))
+        .fetch()
+        .unwrap();
+
+    let changelog =
+        branch_changelog(&clone_path(&directory), Some(&deployed), "origin/main").unwrap();
+    assert_eq!(changelog.target, third);
+    let entries: Vec<(&str, &str)> = changelog
+        .entries
+        .iter()
+        .map(|entry| (entry.commit.as_str(), entry.subject.as_str()))
+        .collect();
+    assert_eq!(
+        entries,
+        vec![(third.as_str(), "third"), (second.as_str(), "second")]
+    );
+    assert_eq!(changelog.entries[0].author, "autodeploy");
+    let stats = changelog.stats.unwrap();
+    assert!(stats.contains("app.txt") && stats.contains("config.txt"));
+    assert!(stats.contains("2 files changed"));
+}
+
+#[test]
+fn nested_submodules_are_checked_out() {
+    let directory = tempdir().unwrap();
+    let inner = init(&directory.path().join("inner"));
+    commit(&inner, &[("file", "inner")]);
+    // middle has inner as submodule, the outer repository has middle
+    let with_submodule = |name: &str, submodule: &str| {
+        let repo = init(&directory.path().join(name));
+        let url = format!("file://{}", directory.path().join(submodule).display());
+        let mut added = repo.submodule(&url, Path::new(submodule), true).unwrap();
+        added.clone(None).unwrap();
+        added.add_finalize().unwrap();
+        commit(&repo, &[]);
+    };
+    with_submodule("middle", "inner");
+    with_submodule("outer", "middle");
+    let url = directory.path().join("outer");
+
+    checkout(
+        &directory,
+        &application(url.to_str().unwrap(), ""),
+        "main",
+        None,
+    )
+    .unwrap();
+    let clone = clone_path(&directory);
+    assert_eq!(
+        read_to_string(Path::new(&clone).join("middle/inner/file")).unwrap(),
+        "inner"
+    );
+}
@@ -0,0 +1,156 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    rc::Rc,
+    thread,
+    time::Duration,
+};
+
+use autodeploy::{
+    objects::structs::{Channel, ChannelKind, NotificationEvent, Notifications, Operator},
+    utils::{
+        notify::{Notification, Notifier, payload},
+        runner::{RecordingRunner, SystemRunner},
+    },
+};
+use serde_json::Value;
+
+fn notification(event: NotificationEvent) -> Notification {
+    Notification {
+        event,
+        application: "demo".to_string(),
+        branch: Some("main".to_string()),
+        commit: Some("0123456789abcdef0123456789abcdef01234567".to_string()),
+        operator: Operator {
+            user: "deploy".to_string(),
+            sudo_user: Some("alice".to_string()),
+            hostname: "web-1".to_string(),
+        },
+        duration: Duration::from_secs(90),
+        error: None,
+        log: None,
+    }
+}
+
+fn channel(kind: ChannelKind, url: &str) -> Channel {
+    Channel {
+        kind,
+        url: url.to_string(),
+        ..Channel::default()
+    }
+}
+
+/// answers a single HTTP request and hands over its request line and body
+fn http_sink() -> (String, thread::JoinHandle<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hooks/deploy", listener.local_addr().unwrap());
+    let sink = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+            .unwrap();
+        (
+            request_line.trim().to_string(),
+            String::from_utf8(body).unwrap(),
+        )
+    });
+    (url, sink)
+}
+
+#[test]
+fn templates_are_rendered() {
+    let mut notification = notification(NotificationEvent::Failed);
+    notification.error = Some("Starting demo failed".to_string());
+    assert_eq!(
+        notification
+            .render("{application} {branch} {short_commit} by {operator} on {hostname}: {error}"),
+        "demo main 0123456789ab by deploy (sudo by alice) on web-1: Starting demo failed"
+    );
+    notification.branch = None;
+    notification.commit = None;
+    assert_eq!(notification.render("{branch} {short_commit}"), "- -");
+}
+
+#[test]
+fn payloads_match_the_channel_kind() {
+    let mut notification = notification(NotificationEvent::Failed);
+    notification.log = Some("$ docker compose up\nno space left on device".to_string());
+
+    let slack = payload(ChannelKind::Slack, "failed", &notification);
+    assert_eq!(
+        slack["text"],
+        "failed\n```\n$ docker compose up\nno space left on device\n```"
+    );
+    let teams = payload(ChannelKind::Teams, "failed", &notification);
+    assert_eq!(teams["@type"], "MessageCard");
+    assert_eq!(teams["themeColor"], "D40E0D");
+    let webhook = payload(ChannelKind::Webhook, "failed", &notification);
+    assert_eq!(webhook["event"], "failed");
+    assert_eq!(webhook["operator"]["sudo_user"], "alice");
+    assert_eq!(webhook["duration_ms"], 90000);
+
+    notification.log = Some("x".repeat(5000));
+    let discord = payload(ChannelKind::Discord, "failed", &notification);
+    assert_eq!(discord["content"].as_str().unwrap().chars().count(), 2000);
+}
+
+#[test]
+fn slack_message_is_posted_to_the_webhook() {
+    let (url, sink) = http_sink();
+    let notifications = Notifications {
+        channels: vec![channel(ChannelKind::Slack, &url)],
+        ..Notifications::default()
+    };
+    Notifier::new(notifications, Rc::new(SystemRunner))
+        .send(&notification(NotificationEvent::Succeeded), false);
+
+    let (request_line, body) = sink.join().unwrap();
+    assert_eq!(request_line, "POST /hooks/deploy HTTP/1.1");
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        body["text"],
+        "demo main (0123456789ab) was deployed by deploy (sudo by alice) in 2 minutes"
+    );
+}
+
+#[test]
+fn channels_only_get_their_events() {
+    let runner = Rc::new(RecordingRunner::new().fail_when("curl"));
+    let mut failures = channel(ChannelKind::Discord, "https://example.com/failures");
+    failures.events = Some(vec![NotificationEvent::Failed]);
+    let notifications = Notifications {
+        channels: vec![
+            channel(ChannelKind::Webhook, "https://example.com/all"),
+            failures,
+        ],
+        ..Notifications::default()
+    };
+    let notifier = Notifier::new(notifications, runner.clone());
+
+    // a failing channel is reported and the others are still notified
+    notifier.send(&notification(NotificationEvent::Failed), false);
+    assert_eq!(runner.invocations().len(), 2);
+    notifier.send(&notification(NotificationEvent::Started), false);
+    assert_eq!(runner.invocations().len(), 3);
+    notifier.send(&notification(NotificationEvent::Started), true);
+    assert_eq!(runner.invocations().len(), 3);
+}
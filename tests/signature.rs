@@ -0,0 +1,326 @@
+use std::{
+    fs::{read_to_string, write},
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    rc::Rc,
+};
+
+use autodeploy::{
+    objects::structs::Application,
+    pipeline::Deployer,
+    utils::{
+        docker::Runtime, runner::RecordingRunner, signature::verify_revision, state::load_state,
+    },
+};
+use git2::{ObjectType, Oid, Repository, Signature, Time};
+use tempfile::{TempDir, tempdir};
+
+/// an application deployed from the repository at `url`
+fn application(url: &str, extra: &str) -> Application {
+    toml::from_str(&format!(
+        r#"
+name = "demo"
+url = "{}"
+directory_name = "demo"
+require_signed = true
+{}
+[container]
+name = "demo"
+image = "demo"
+restart_policy = "no"
+ports = []
+[container.build]
+dockerfile = "Dockerfile"
+"#,
+        url, extra
+    ))
+    .unwrap()
+}
+
+/// run the command with `input` on stdin and return its stdout
+fn run(command: &mut Command, input: &[u8]) -> Vec<u8> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?} failed", command);
+    output.stdout
+}
+
+/// a repository with `main` checked out holding a Dockerfile
+fn repository(directory: &TempDir) -> Repository {
+    let repo = Repository::init(directory.path().join("upstream")).unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+    write(repo.workdir().unwrap().join("Dockerfile"), "FROM scratch\n").unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new("Dockerfile")).unwrap();
+    index.write().unwrap();
+    repo
+}
+
+fn url(repo: &Repository) -> &str {
+    repo.workdir().unwrap().to_str().unwrap()
+}
+
+fn signature() -> Signature<'static> {
+    Signature::new("autodeploy", "autodeploy@example.com", &Time::new(1_000, 0)).unwrap()
+}
+
+/// signs the commit buffer, returning the armored signature
+type Sign<'s> = &'s dyn Fn(&[u8]) -> String;
+
+/// commit the index to main, signed by `sign` unless it is `None`
+fn commit(repo: &Repository, sign: Option<Sign>) -> String {
+    let tree = repo
+        .find_tree(repo.index().unwrap().write_tree().unwrap())
+        .unwrap();
+    let buffer = repo
+        .commit_create_buffer(&signature(), &signature(), "release", &tree, &[])
+        .unwrap();
+    let content = buffer.as_str().unwrap();
+    let oid = match sign {
+        Some(sign) => repo.commit_signed(content, &sign(&buffer), None).unwrap(),
+        None => repo
+            .odb()
+            .unwrap()
+            .write(ObjectType::Commit, &buffer)
+            .unwrap(),
+    };
+    repo.reference("refs/heads/main", oid, true, "commit")
+        .unwrap();
+    oid.to_string()
+}
+
+/// an ed25519 key pair, returns the private key and the allowed signers line
+fn ssh_key(directory: &TempDir, name: &str) -> (PathBuf, String) {
+    let key = directory.path().join(name);
+    run(
+        Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", name, "-f"])
+            .arg(&key),
+        &[],
+    );
+    let public_key = read_to_string(key.with_extension("pub")).unwrap();
+    let line = format!(
+        "autodeploy@example.com namespaces=\"git\" {}",
+        public_key.trim()
+    );
+    (key, line)
+}
+
+fn ssh_sign(key: &Path, data: &[u8]) -> String {
+    let signature = run(
+        Command::new("ssh-keygen")
+            .args(["-Y", "sign", "-n", "git", "-f"])
+            .arg(key),
+        data,
+    );
+    String::from_utf8(signature).unwrap()
+}
+
+/// the allowed signers file of the application
+fn allowed_signers(directory: &TempDir, lines: &[&str]) -> String {
+    let path = directory.path().join("allowed_signers");
+    write(&path, format!("{}\n", lines.join("\n"))).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// a throwaway GPG home with a signing key, the agent is stopped on drop
+struct Gpg {
+    home: TempDir,
+}
+
+impl Gpg {
+    fn new() -> Gpg {
+        let gpg = Gpg {
+            home: tempdir().unwrap(),
+        };
+        run(
+            gpg.command().args([
+                "--passphrase",
+                "",
+                "--quick-gen-key",
+                "autodeploy <autodeploy@example.com>",
+                "ed25519",
+                "sign",
+                "never",
+            ]),
+            &[],
+        );
+        gpg
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new("gpg");
+        command.arg("--homedir").arg(self.home.path()).args([
+            "--batch",
+            "--pinentry-mode",
+            "loopback",
+        ]);
+        command
+    }
+
+    fn sign(&self, data: &[u8]) -> String {
+        String::from_utf8(run(self.command().args(["--armor", "--detach-sign"]), data)).unwrap()
+    }
+
+    /// export the public key into a keyring file
+    fn export(&self, path: &Path) {
+        write(path, run(self.command().arg("--export"), &[])).unwrap();
+    }
+}
+
+impl Drop for Gpg {
+    fn drop(&mut self) {
+        let _ = Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(self.home.path())
+            .args(["--kill", "gpg-agent"])
+            .status();
+    }
+}
+
+#[test]
+fn unsigned_commits_are_refused() {
+    let directory = tempdir().unwrap();
+    let repo = repository(&directory);
+    let commit = commit(&repo, None);
+    let (_, line) = ssh_key(&directory, "key");
+    let signers = allowed_signers(&directory, &[&line]);
+    let application = application(url(&repo), &format!("allowed_signers = \"{}\"", signers));
+
+    let verification = verify_revision(url(&repo), &commit, &application);
+    assert!(!verification.verified);
+    assert_eq!(
+        verification.message,
+        format!("commit {} is not signed", commit)
+    );
+}
+
+#[test]
+fn ssh_signatures_need_an_allowed_signer() {
+    let directory = tempdir().unwrap();
+    let repo = repository(&directory);
+    let (key, line) = ssh_key(&directory, "key");
+    let (_, other_line) = ssh_key(&directory, "other");
+    let commit = commit(&repo, Some(&|data| ssh_sign(&key, data)));
+
+    let signers = allowed_signers(&directory, &[&line]);
+    let allowed = application(url(&repo), &format!("allowed_signers = \"{}\"", signers));
+    let verification = verify_revision(url(&repo), &commit, &allowed);
+    assert!(verification.verified, "{}", verification.message);
+    assert_eq!(verification.format.as_deref(), Some("ssh"));
+    assert_eq!(
+        verification.signer.as_deref(),
+        Some("autodeploy@example.com")
+    );
+
+    let unconfigured = application(url(&repo), "");
+    assert!(!verify_revision(url(&repo), &commit, &unconfigured).verified);
+
+    let signers = allowed_signers(&directory, &[&other_line]);
+    let other = application(url(&repo), &format!("allowed_signers = \"{}\"", signers));
+    let verification = verify_revision(url(&repo), &commit, &other);
+    assert!(!verification.verified);
+    assert!(verification.message.contains("not in the allowed signers"));
+}
+
+#[test]
+fn gpg_signatures_need_the_key_in_the_keyring() {
+    let directory = tempdir().unwrap();
+    let repo = repository(&directory);
+    let gpg = Gpg::new();
+    let commit = commit(&repo, Some(&|data| gpg.sign(data)));
+
+    let empty = directory.path().join("empty.gpg");
+    write(&empty, "").unwrap();
+    let unknown = application(
+        url(&repo),
+        &format!("gpg_keyring = \"{}\"", empty.display()),
+    );
+    assert!(!verify_revision(url(&repo), &commit, &unknown).verified);
+
+    let keyring = directory.path().join("keyring.gpg");
+    gpg.export(&keyring);
+    let known = application(
+        url(&repo),
+        &format!("gpg_keyring = \"{}\"", keyring.display()),
+    );
+    let verification = verify_revision(url(&repo), &commit, &known);
+    assert!(verification.verified, "{}", verification.message);
+    assert_eq!(verification.format.as_deref(), Some("gpg"));
+    assert_eq!(
+        verification.signer.as_deref(),
+        Some("autodeploy <autodeploy@example.com>")
+    );
+}
+
+#[test]
+fn signed_tags_vouch_for_unsigned_commits() {
+    let directory = tempdir().unwrap();
+    let repo = repository(&directory);
+    let (key, line) = ssh_key(&directory, "key");
+    let commit = commit(&repo, None);
+    let tag = format!(
+        "object {}\ntype commit\ntag v1.0\ntagger autodeploy <autodeploy@example.com> 1000 +0000\n\nrelease\n",
+        commit
+    );
+    let signed_tag = format!("{}{}", tag, ssh_sign(&key, tag.as_bytes()));
+    let tag_id = repo
+        .odb()
+        .unwrap()
+        .write(ObjectType::Tag, signed_tag.as_bytes())
+        .unwrap();
+    repo.reference("refs/tags/v1.0", tag_id, false, "tag")
+        .unwrap();
+
+    let signers = allowed_signers(&directory, &[&line]);
+    let application = application(url(&repo), &format!("allowed_signers = \"{}\"", signers));
+    let verification = verify_revision(url(&repo), &commit, &application);
+    assert!(verification.verified, "{}", verification.message);
+    assert_eq!(verification.object, "tag v1.0");
+}
+
+#[test]
+fn signature_is_recorded_with_the_deployment() {
+    let directory = tempdir().unwrap();
+    let repo = repository(&directory);
+    let (key, line) = ssh_key(&directory, "key");
+    let commit = commit(&repo, Some(&|data| ssh_sign(&key, data)));
+    let signers = allowed_signers(&directory, &[&line]);
+    let application = application(url(&repo), &format!("allowed_signers = \"{}\"", signers));
+
+    let path = |name: &str| directory.path().join(name).to_str().unwrap().to_string();
+    let deployer = Deployer::new(&path("repositories"), "", "")
+        .compose_directory(&path("compose_files"))
+        .state_directory(&path("state"))
+        .non_interactive(true)
+        .runtime(Runtime::compose(Rc::new(RecordingRunner::new())));
+    deployer.pipeline(&application).run("main").unwrap();
+
+    let state = load_state(&path("state"), &application.slug);
+    let deployment = state.deployments.last().unwrap();
+    assert_eq!(deployment.commit, commit);
+    let signature = deployment.signature.as_ref().unwrap();
+    assert!(signature.verified);
+    assert_eq!(signature.object, format!("commit {}", commit));
+    assert_eq!(signature.signer.as_deref(), Some("autodeploy@example.com"));
+}
+
+#[test]
+fn missing_commits_are_refused() {
+    let directory = tempdir().unwrap();
+    let repo = repository(&directory);
+    commit(&repo, None);
+    let missing = Oid::hash_object(ObjectType::Blob, b"missing")
+        .unwrap()
+        .to_string();
+    let verification = verify_revision(url(&repo), &missing, &application(url(&repo), ""));
+    assert!(!verification.verified);
+    assert!(verification.message.ends_with("is not in the repository"));
+}